@@ -1,6 +1,6 @@
 // Standalone probe to reproduce/diagnose feed-fetch failures (e.g. the 415 from
-// nakedbiblepodcast.com) locally, using the IDENTICAL reqwest client config as
-// the production poller in src/main.rs::check_feed_is_updated.
+// nakedbiblepodcast.com) locally, using the IDENTICAL reqwest client and
+// redirect-following path as the production poller (aggrivator::fetch).
 //
 // Why an example binary: it links the same reqwest (rustls-tls + gzip) build, so
 // the TLS fingerprint, header set, redirect handling and gzip behavior match prod
@@ -10,7 +10,7 @@
 // Usage:
 //   cargo run --example probe -- <url>
 //
-//   # Toggle an Accept header to A/B test the suspected fix:
+//   # Override the Accept header to A/B test a suspected fix:
 //   ACCEPT='application/rss+xml, application/xml;q=0.9, */*;q=0.8' \
 //     cargo run --example probe -- https://nakedbiblepodcast.com/feed/podcast/
 //
@@ -19,14 +19,16 @@
 //   IF_MODIFIED_SINCE='Wed, 01 Jan 2025 00:00:00 GMT' cargo run --example probe -- <url>
 //
 // Env vars (all optional):
-//   ACCEPT             value for the Accept header (unset => no Accept header, matching prod today)
+//   ACCEPT             override the Accept header (unset => same as prod)
 //   IF_NONE_MATCH      value for If-None-Match (conditional request)
 //   IF_MODIFIED_SINCE  value for If-Modified-Since (conditional request)
 //   UA                 override User-Agent (default = same as prod)
 
 use std::env;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use reqwest::{header, redirect};
+use std::time::Duration;
+use reqwest::header;
+use aggrivator::fetch::{build_client, send_following_redirects};
+use aggrivator::timing::RequestPhases;
 use aggrivator::signing::WebBotAuthSigner;
 
 const DEFAULT_USERAGENT: &str = concat!("Aggrivator (PodcastIndex.org)/v", env!("CARGO_PKG_VERSION"));
@@ -43,15 +45,13 @@ async fn main() {
 
     let user_agent = env::var("UA").unwrap_or_else(|_| DEFAULT_USERAGENT.to_string());
 
-    // Build the request headers exactly like prod does, plus the optional toggles.
+    // Per-request headers, exactly like prod does, plus the optional toggles. The
+    // User-Agent and Accept defaults live on the shared client.
     let mut headers = header::HeaderMap::new();
-    headers.insert("User-Agent", header::HeaderValue::from_str(&user_agent).unwrap());
 
     if let Ok(accept) = env::var("ACCEPT") {
         headers.insert("Accept", header::HeaderValue::from_str(&accept).unwrap());
         println!("  + Accept: {}", accept);
-    } else {
-        println!("  (no Accept header -- matches current production behavior)");
     }
     if let Ok(inm) = env::var("IF_NONE_MATCH") {
         headers.insert("If-None-Match", header::HeaderValue::from_str(&inm).unwrap());
@@ -62,46 +62,37 @@ async fn main() {
         println!("  + If-Modified-Since: {}", ims);
     }
 
-    // Same custom redirect policy shape as prod: log each hop, cap at 10.
-    let custom = redirect::Policy::custom(move |attempt| {
-        let status_code = attempt.status().as_u16();
-        println!("  -> redirect [{}] to {}", status_code, attempt.url());
-        if attempt.previous().len() > 9 {
-            return attempt.error("Error - Too many redirects");
-        }
-        attempt.follow()
-    });
-
-    // IDENTICAL client builder to src/main.rs::check_feed_is_updated.
-    let client = reqwest::Client::builder()
-        .use_rustls_tls()
-        .connect_timeout(Duration::from_secs(20))
-        .timeout(Duration::from_secs(30))
-        .pool_idle_timeout(Duration::from_secs(20))
-        .default_headers(headers)
-        .gzip(true)
-        .redirect(custom)
-        .build()
-        .unwrap();
+    // IDENTICAL client to the production poller.
+    let client = build_client(&user_agent).unwrap();
 
     println!("\nUser-Agent: {}", user_agent);
     println!("GET {}\n", url);
 
-    let mut req = client.get(&url);
+    let mut signer = None;
     if let Ok(key_path) = env::var("SIGN_KEY") {
         let agent = env::var("AGGRIVATOR_SIGNATURE_AGENT")
             .unwrap_or_else(|_| "https://podcastindex.org".to_string());
-        let signer = WebBotAuthSigner::from_pem_file(&key_path, agent, 300)
+        let loaded = WebBotAuthSigner::from_pem_file(&key_path, agent, 300)
             .expect("load SIGN_KEY");
-        println!("  + Web Bot Auth signing (keyid={})", signer.keyid());
-        if let Ok(parsed) = reqwest::Url::parse(&url) {
-            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
-            for (name, value) in signer.sign(&parsed, now) {
-                req = req.header(name, value);
-            }
-        }
+        println!("  + Web Bot Auth signing (keyid={})", loaded.keyid());
+        signer = Some(loaded);
+    }
+
+    // Same redirect handling as prod: each hop is followed (and signed) by hand, capped at 10.
+    let mut hops = Vec::new();
+    let response = send_following_redirects(
+        &client,
+        &url,
+        &headers,
+        signer.as_ref(),
+        Duration::from_secs(30),
+        &mut hops,
+        &mut RequestPhases::default(),
+    ).await;
+    for hop in &hops {
+        println!("  -> redirect [{}] to {}", hop.status, hop.location);
     }
-    match req.send().await {
+    match response {
         Ok(res) => {
             let status = res.status();
             let final_url = res.url().to_string();
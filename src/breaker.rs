@@ -0,0 +1,239 @@
+//! Per-host circuit breaker. After a run of failures from one host, its
+//! remaining feeds are deferred for a cool-down instead of each waiting out
+//! its own timeouts; then a single probe request decides whether to resume.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// When to stop sending requests to a failing host, and for how long.
+#[derive(Debug, Clone)]
+pub struct BreakerConfig {
+    /// Consecutive failures from a host that open its circuit. 0 disables
+    /// the breaker.
+    pub failure_threshold: u32,
+    /// How long an open circuit defers requests before a probe is let through.
+    pub cool_down: Duration,
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            cool_down: Duration::from_secs(300),
+        }
+    }
+}
+
+/// What to do with a request to a host.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Admission {
+    /// The host is healthy; go ahead.
+    Allowed,
+    /// The cool-down is over; this request decides whether the circuit closes.
+    Probe,
+    /// The circuit is open (or a probe is already out); don't send it.
+    Deferred,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum HostState {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    HalfOpen,
+}
+
+/// Whether a result status (real or pseudo) says the host itself is in
+/// trouble: rate limiting, server errors, and failures to connect or get a
+/// response. Problems with one feed's url or body don't count.
+pub fn is_host_failure(status: u16) -> bool {
+    matches!(status, 429 | 500..=599 | 666 | 670..=676)
+}
+
+/// Circuit state for every host seen in a run.
+pub struct CircuitBreaker {
+    config: BreakerConfig,
+    hosts: Mutex<HashMap<String, HostState>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: BreakerConfig) -> Self {
+        Self {
+            config,
+            hosts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Decide whether a request to `host` may go out now. A `Probe` must be
+    /// followed by a `record` or a `release` for the same host, or the circuit
+    /// stays half-open; `probe` wraps it in a guard that does one or the other.
+    pub fn admit(&self, host: &str) -> Admission {
+        if self.config.failure_threshold == 0 {
+            return Admission::Allowed;
+        }
+        let mut hosts = self.hosts.lock().unwrap();
+        let state = hosts.entry(host.to_string()).or_insert(HostState::Closed { failures: 0 });
+        match *state {
+            HostState::Closed { .. } => Admission::Allowed,
+            HostState::Open { until } if Instant::now() >= until => {
+                *state = HostState::HalfOpen;
+                Admission::Probe
+            }
+            HostState::Open { .. } | HostState::HalfOpen => Admission::Deferred,
+        }
+    }
+
+    /// Record how a request to `host` went. Returns true if this opened the
+    /// circuit.
+    pub fn record(&self, host: &str, failed: bool) -> bool {
+        if self.config.failure_threshold == 0 {
+            return false;
+        }
+        let mut hosts = self.hosts.lock().unwrap();
+        let state = hosts.entry(host.to_string()).or_insert(HostState::Closed { failures: 0 });
+        let open = HostState::Open {
+            until: Instant::now() + self.config.cool_down,
+        };
+        match (*state, failed) {
+            (_, false) => {
+                *state = HostState::Closed { failures: 0 };
+                false
+            }
+            (HostState::Closed { failures }, true) if failures + 1 >= self.config.failure_threshold => {
+                *state = open;
+                true
+            }
+            (HostState::Closed { failures }, true) => {
+                *state = HostState::Closed { failures: failures + 1 };
+                false
+            }
+            (HostState::HalfOpen, true) => {
+                *state = open;
+                true
+            }
+            //A request admitted before the circuit opened; it changes nothing
+            (HostState::Open { .. }, true) => false,
+        }
+    }
+
+    /// Give back a probe that was never sent. The circuit goes back to open
+    /// with its cool-down already over, so the next request probes instead.
+    pub fn release(&self, host: &str) {
+        let mut hosts = self.hosts.lock().unwrap();
+        if let Some(state @ HostState::HalfOpen) = hosts.get_mut(host) {
+            *state = HostState::Open { until: Instant::now() };
+        }
+    }
+
+    /// Hold the probe `admit` just let through for `host`.
+    pub fn probe(&self, host: &str) -> ProbeGuard<'_> {
+        ProbeGuard {
+            breaker: self,
+            host: host.to_string(),
+            recorded: false,
+        }
+    }
+}
+
+/// A probe request on its way out. Recording how it went closes or reopens
+/// the circuit; dropping it unrecorded releases it, so a probe that is never
+/// sent can't leave its host half-open for good.
+pub struct ProbeGuard<'a> {
+    breaker: &'a CircuitBreaker,
+    host: String,
+    recorded: bool,
+}
+
+impl ProbeGuard<'_> {
+    /// Record how the probe went. Returns true if this opened the circuit again.
+    pub fn record(mut self, failed: bool) -> bool {
+        self.recorded = true;
+        self.breaker.record(&self.host, failed)
+    }
+}
+
+impl Drop for ProbeGuard<'_> {
+    fn drop(&mut self) {
+        if !self.recorded {
+            self.breaker.release(&self.host);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker(failure_threshold: u32, cool_down_ms: u64) -> CircuitBreaker {
+        CircuitBreaker::new(BreakerConfig {
+            failure_threshold,
+            cool_down: Duration::from_millis(cool_down_ms),
+        })
+    }
+
+    #[test]
+    fn trips_after_consecutive_failures_only() {
+        let breaker = breaker(3, 60_000);
+        assert!(!breaker.record("a.example", true));
+        assert!(!breaker.record("a.example", true));
+        assert!(!breaker.record("a.example", false));
+        assert!(!breaker.record("a.example", true));
+        assert!(!breaker.record("a.example", true));
+        assert_eq!(breaker.admit("a.example"), Admission::Allowed);
+        assert!(breaker.record("a.example", true));
+        assert_eq!(breaker.admit("a.example"), Admission::Deferred);
+        assert_eq!(breaker.admit("b.example"), Admission::Allowed);
+    }
+
+    #[test]
+    fn half_opens_with_a_single_probe() {
+        let breaker = breaker(1, 20);
+        assert!(breaker.record("a.example", true));
+        assert_eq!(breaker.admit("a.example"), Admission::Deferred);
+
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(breaker.admit("a.example"), Admission::Probe);
+        assert_eq!(breaker.admit("a.example"), Admission::Deferred);
+
+        //A failed probe opens it again, a good one closes it
+        assert!(breaker.record("a.example", true));
+        assert_eq!(breaker.admit("a.example"), Admission::Deferred);
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(breaker.admit("a.example"), Admission::Probe);
+        breaker.record("a.example", false);
+        assert_eq!(breaker.admit("a.example"), Admission::Allowed);
+    }
+
+    #[test]
+    fn unsent_probes_are_released() {
+        let breaker = breaker(1, 20);
+        assert!(breaker.record("a.example", true));
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(breaker.admit("a.example"), Admission::Probe);
+        drop(breaker.probe("a.example"));
+
+        //The next request gets to probe, and its result counts
+        assert_eq!(breaker.admit("a.example"), Admission::Probe);
+        assert!(!breaker.probe("a.example").record(false));
+        assert_eq!(breaker.admit("a.example"), Admission::Allowed);
+    }
+
+    #[test]
+    fn threshold_zero_disables() {
+        let breaker = breaker(0, 60_000);
+        for _ in 0..10 {
+            assert!(!breaker.record("a.example", true));
+        }
+        assert_eq!(breaker.admit("a.example"), Admission::Allowed);
+    }
+
+    #[test]
+    fn classifies_host_failures() {
+        assert!(is_host_failure(503));
+        assert!(is_host_failure(429));
+        assert!(is_host_failure(673));
+        assert!(!is_host_failure(404));
+        assert!(!is_host_failure(668));
+        assert!(!is_host_failure(677));
+    }
+}
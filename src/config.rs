@@ -0,0 +1,785 @@
+//! Runtime configuration. Built-in defaults, then an optional TOML file, then
+//! `AGGRIVATOR_*` environment variables, each overriding the one before. Every
+//! setting can be given either way; the env names are listed in `apply_env`.
+
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use tracing_subscriber::EnvFilter;
+
+use crate::breaker::BreakerConfig;
+use crate::feedfile::NamingPolicy;
+use crate::hosts::PolitenessConfig;
+use crate::queue::{DeadPolicy, QueueOrder, QueueSelection};
+use crate::retry::RetryPolicy;
+use crate::schedule::ScheduleConfig;
+use crate::sink::{JsonDirSink, SinkKind};
+
+/// Read from the working directory when no config file is named.
+pub const DEFAULT_CONFIG_FILE: &str = "aggrivator.toml";
+
+const DAY_SECS: u64 = 86400;
+const HOUR_SECS: u64 = 3600;
+
+/// Why a configuration couldn't be loaded.
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Env(String, String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "can't read config file [{}]: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "invalid config file [{}]: {}", path.display(), e),
+            ConfigError::Env(name, e) => write!(f, "invalid {}: {}", name, e),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Everything the poller can be configured with.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The sqlite feed queue.
+    pub queue_db: PathBuf,
+    /// Where feed files are written, with the `files` sink.
+    pub feeds_dir: PathBuf,
+    /// Where permanent redirect stubs are written.
+    pub redirects_dir: PathBuf,
+    /// Feed checks in flight at once.
+    pub concurrency: usize,
+    pub connect_timeout_secs: u64,
+    /// Limit on each request, from sending it to the end of the body.
+    pub request_timeout_secs: u64,
+    /// Bodies bigger than this are recorded as 668 and not stored.
+    pub max_body_length: usize,
+    /// `full` for feed files with the `X-Aggrivator-*` lines, or `legacy` for
+    /// just the four line header, for parsers that expect the body on line five.
+    pub header_format: String,
+    /// `replace`, `supersede` or `queue`.
+    pub file_policy: NamingPolicy,
+    /// Sync each feed file and its directory before moving it into place.
+    pub fsync: bool,
+    /// Write check results back to the queue.
+    pub write_back: bool,
+    pub write_back_batch: usize,
+    /// How often `status.go` is re-read during a run.
+    pub status_poll_secs: u64,
+    /// How often a daemon looks for due feeds.
+    pub daemon_interval_secs: u64,
+    /// Where to write each run's summary as JSON, if anywhere.
+    pub summary_file: Option<PathBuf>,
+    /// Serve Prometheus metrics at `http://<this>/metrics`, e.g. `0.0.0.0:9898`.
+    pub metrics_addr: Option<String>,
+    pub output: OutputSection,
+    pub signing: SigningSection,
+    pub politeness: PolitenessSection,
+    pub retry: RetrySection,
+    pub breaker: BreakerSection,
+    pub schedule: ScheduleSection,
+    pub queue: QueueSection,
+    pub log: LogSection,
+    pub otlp: OtlpSection,
+    /// The file this was loaded from, if any.
+    #[serde(skip)]
+    pub source: Option<PathBuf>,
+}
+
+/// Where check results go.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutputSection {
+    /// `files` (feed files in `feeds_dir` and `redirects_dir`), `jsonl`
+    /// (stdout), `sqlite` or `json-dir`.
+    pub sink: String,
+    /// The `sqlite` sink's database. It may be the queue db.
+    pub sqlite_db: PathBuf,
+    /// The `json-dir` sink's directory.
+    pub json_dir: PathBuf,
+}
+
+/// Web Bot Auth request signing. Off unless `key` is set.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SigningSection {
+    /// PKCS#8 PEM Ed25519 private key.
+    pub key: Option<PathBuf>,
+    /// The `Signature-Agent` url.
+    pub agent: String,
+    pub ttl_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PolitenessSection {
+    pub per_host_limit: usize,
+    /// 0 for no limit.
+    pub per_ip_limit: usize,
+    pub host_delay_ms: u64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetrySection {
+    pub attempts: u32,
+    pub base_ms: u64,
+    pub max_delay_secs: u64,
+    pub budget_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BreakerSection {
+    /// 0 turns the breaker off.
+    pub threshold: u32,
+    pub cool_down_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScheduleSection {
+    pub enabled: bool,
+    pub min_minutes: u64,
+    pub max_days: u64,
+    pub initial_hours: u64,
+    pub max_cache_hours: u64,
+}
+
+/// Queue selection. The optional settings default to whatever `mode` implies.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueueSection {
+    /// `all` to poll every feed by id like the original poller, or `due` for
+    /// only the feeds that are due (see `QueueSelection::due`).
+    pub mode: String,
+    /// `include`, `skip` or `sample`.
+    pub dead: Option<String>,
+    pub dead_sample_percent: u8,
+    pub dead_recheck_days: u64,
+    /// `id` or `priority`.
+    pub order: Option<String>,
+    pub only_due: Option<bool>,
+    pub frequency_unit_secs: u64,
+    pub active_days: u64,
+    pub stale_days: u64,
+    pub stale_interval_hours: Option<u64>,
+    pub dormant_interval_hours: Option<u64>,
+    /// 0 for no limit.
+    pub limit: usize,
+}
+
+/// Logging, to stderr.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogSection {
+    /// A level (`info`, `debug`...) or a full filter like `info,aggrivator=debug`.
+    pub level: String,
+    /// `text`, or `json` for one JSON object per line.
+    pub format: String,
+}
+
+/// OpenTelemetry trace export. Off unless `endpoint` is set.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OtlpSection {
+    /// The collector's OTLP/HTTP base url, e.g. `http://localhost:4318`.
+    pub endpoint: Option<String>,
+    /// `http/protobuf` or `http/json`.
+    pub protocol: String,
+    /// The share of feed checks traced, from 0 to 1.
+    pub sample_ratio: f64,
+    pub service_name: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            queue_db: PathBuf::from("feed_poller_queue.db"),
+            feeds_dir: PathBuf::from("feeds"),
+            redirects_dir: PathBuf::from("redirects"),
+            concurrency: 100,
+            connect_timeout_secs: 20,
+            request_timeout_secs: 30,
+            max_body_length: 73400320,
+            header_format: "full".to_string(),
+            file_policy: NamingPolicy::Replace,
+            fsync: false,
+            write_back: false,
+            write_back_batch: 500,
+            status_poll_secs: 10,
+            daemon_interval_secs: 60,
+            summary_file: None,
+            metrics_addr: None,
+            output: OutputSection::default(),
+            signing: SigningSection::default(),
+            politeness: PolitenessSection::default(),
+            retry: RetrySection::default(),
+            breaker: BreakerSection::default(),
+            schedule: ScheduleSection::default(),
+            queue: QueueSection::default(),
+            log: LogSection::default(),
+            otlp: OtlpSection::default(),
+            source: None,
+        }
+    }
+}
+
+impl Default for OutputSection {
+    fn default() -> Self {
+        Self {
+            sink: "files".to_string(),
+            sqlite_db: PathBuf::from("results.db"),
+            json_dir: PathBuf::from("results"),
+        }
+    }
+}
+
+impl Default for SigningSection {
+    fn default() -> Self {
+        Self {
+            key: None,
+            agent: "https://podcastindex.org".to_string(),
+            ttl_secs: 300,
+        }
+    }
+}
+
+impl Default for PolitenessSection {
+    fn default() -> Self {
+        let defaults = PolitenessConfig::default();
+        Self {
+            per_host_limit: defaults.per_host_limit,
+            per_ip_limit: defaults.per_ip_limit.unwrap_or(0),
+            host_delay_ms: defaults.min_host_delay.as_millis() as u64,
+        }
+    }
+}
+
+impl Default for RetrySection {
+    fn default() -> Self {
+        let defaults = RetryPolicy::default();
+        Self {
+            attempts: defaults.max_attempts,
+            base_ms: defaults.base_delay.as_millis() as u64,
+            max_delay_secs: defaults.max_delay.as_secs(),
+            budget_secs: defaults.max_total.as_secs(),
+        }
+    }
+}
+
+impl Default for BreakerSection {
+    fn default() -> Self {
+        let defaults = BreakerConfig::default();
+        Self {
+            threshold: defaults.failure_threshold,
+            cool_down_secs: defaults.cool_down.as_secs(),
+        }
+    }
+}
+
+impl Default for ScheduleSection {
+    fn default() -> Self {
+        let defaults = ScheduleConfig::default();
+        Self {
+            enabled: true,
+            min_minutes: defaults.min_interval_secs / 60,
+            max_days: defaults.max_interval_secs / DAY_SECS,
+            initial_hours: defaults.initial_interval_secs / HOUR_SECS,
+            max_cache_hours: defaults.max_cache_hint_secs / HOUR_SECS,
+        }
+    }
+}
+
+impl Default for QueueSection {
+    fn default() -> Self {
+        let defaults = QueueSelection::due();
+        let (dead_sample_percent, dead_recheck_days) = match defaults.dead {
+            DeadPolicy::Sample { percent, recheck_secs } => (percent, recheck_secs / DAY_SECS),
+            _ => (5, 7),
+        };
+        Self {
+            mode: "all".to_string(),
+            dead: None,
+            dead_sample_percent,
+            dead_recheck_days,
+            order: None,
+            only_due: None,
+            frequency_unit_secs: defaults.update_frequency_unit_secs,
+            active_days: defaults.active_days,
+            stale_days: defaults.stale_days,
+            stale_interval_hours: None,
+            dormant_interval_hours: None,
+            limit: 0,
+        }
+    }
+}
+
+impl Default for LogSection {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+            format: "text".to_string(),
+        }
+    }
+}
+
+impl Default for OtlpSection {
+    fn default() -> Self {
+        Self {
+            endpoint: None,
+            protocol: "http/protobuf".to_string(),
+            sample_ratio: 1.0,
+            service_name: "aggrivator".to_string(),
+        }
+    }
+}
+
+//The on/off env vars keep the meanings they had before there was a config file, so existing
+//deployments don't change behaviour. None of them can be invalid.
+
+/// `AGGRIVATOR_WRITE_BACK`: on for anything but empty or `0`.
+fn write_back_flag(value: &str) -> Result<bool, String> {
+    Ok(!value.is_empty() && value != "0")
+}
+
+/// `AGGRIVATOR_SCHEDULE`: on unless it's `off`.
+fn schedule_flag(value: &str) -> Result<bool, String> {
+    Ok(value != "off")
+}
+
+/// `AGGRIVATOR_FSYNC`: on only for `true`.
+fn fsync_flag(value: &str) -> Result<bool, String> {
+    Ok(value.parse::<bool>().unwrap_or(false))
+}
+
+/// `AGGRIVATOR_QUEUE_ONLY_DUE`: a number, 0 for off. Anything else leaves it to the queue mode.
+fn only_due_flag(value: &str) -> Result<Option<bool>, String> {
+    Ok(value.parse::<u8>().ok().map(|only_due| only_due != 0))
+}
+
+/// Collects env overrides, remembering the first bad value.
+struct EnvReader<F> {
+    lookup: F,
+    error: Option<ConfigError>,
+}
+
+impl<F: Fn(&str) -> Option<String>> EnvReader<F> {
+    fn set<T: FromStr>(&mut self, name: &str, target: &mut T)
+    where
+        T::Err: fmt::Display,
+    {
+        self.set_with(name, target, |value| value.trim().parse::<T>().map_err(|e| e.to_string()));
+    }
+
+    fn set_some<T: FromStr>(&mut self, name: &str, target: &mut Option<T>)
+    where
+        T::Err: fmt::Display,
+    {
+        self.set_with(name, target, |value| value.trim().parse::<T>().map(Some).map_err(|e| e.to_string()));
+    }
+
+    fn set_with<T>(&mut self, name: &str, target: &mut T, parse: impl Fn(&str) -> Result<T, String>) {
+        let Some(value) = (self.lookup)(name) else { return };
+        match parse(&value) {
+            Ok(parsed) => *target = parsed,
+            Err(e) => {
+                self.error.get_or_insert(ConfigError::Env(name.to_string(), e));
+            }
+        }
+    }
+}
+
+impl Config {
+    /// Load the configuration: defaults, then `path` (or `aggrivator.toml` if
+    /// it exists), then the environment as seen through `lookup`.
+    pub fn load(path: Option<&Path>, lookup: impl Fn(&str) -> Option<String>) -> Result<Self, ConfigError> {
+        let path = match path {
+            Some(path) => Some(path.to_path_buf()),
+            None => Some(PathBuf::from(DEFAULT_CONFIG_FILE)).filter(|path| path.exists()),
+        };
+        let mut config = match &path {
+            Some(path) => {
+                let text = std::fs::read_to_string(path).map_err(|e| ConfigError::Read(path.clone(), e))?;
+                Self::from_toml(&text).map_err(|e| ConfigError::Parse(path.clone(), e))?
+            }
+            None => Self::default(),
+        };
+        config.source = path;
+        config.apply_env(lookup)?;
+        Ok(config)
+    }
+
+    pub fn from_toml(text: &str) -> Result<Self, toml::de::Error> {
+        toml::from_str(text)
+    }
+
+    /// The configuration as a TOML document.
+    pub fn to_toml(&self) -> String {
+        toml::to_string(self).unwrap_or_default()
+    }
+
+    /// Override settings from `AGGRIVATOR_*` variables.
+    pub fn apply_env(&mut self, lookup: impl Fn(&str) -> Option<String>) -> Result<(), ConfigError> {
+        let mut env = EnvReader { lookup, error: None };
+        env.set("AGGRIVATOR_QUEUE_DB", &mut self.queue_db);
+        env.set("AGGRIVATOR_FEEDS_DIR", &mut self.feeds_dir);
+        env.set("AGGRIVATOR_REDIRECTS_DIR", &mut self.redirects_dir);
+        env.set("AGGRIVATOR_CONCURRENCY", &mut self.concurrency);
+        env.set("AGGRIVATOR_CONNECT_TIMEOUT_SECS", &mut self.connect_timeout_secs);
+        env.set("AGGRIVATOR_REQUEST_TIMEOUT_SECS", &mut self.request_timeout_secs);
+        env.set("AGGRIVATOR_MAX_BODY_LENGTH", &mut self.max_body_length);
+        env.set("AGGRIVATOR_HEADER_FORMAT", &mut self.header_format);
+        env.set("AGGRIVATOR_FILE_POLICY", &mut self.file_policy);
+        env.set_with("AGGRIVATOR_FSYNC", &mut self.fsync, fsync_flag);
+        env.set_with("AGGRIVATOR_WRITE_BACK", &mut self.write_back, write_back_flag);
+        env.set("AGGRIVATOR_WRITE_BACK_BATCH", &mut self.write_back_batch);
+        env.set("AGGRIVATOR_STATUS_POLL_SECS", &mut self.status_poll_secs);
+        env.set("AGGRIVATOR_DAEMON_INTERVAL_SECS", &mut self.daemon_interval_secs);
+        env.set_with("AGGRIVATOR_SUMMARY_FILE", &mut self.summary_file, |value| {
+            Ok(Some(PathBuf::from(value)).filter(|path| !path.as_os_str().is_empty()))
+        });
+        env.set_with("AGGRIVATOR_METRICS_ADDR", &mut self.metrics_addr, |value| {
+            Ok(Some(value.trim().to_string()).filter(|addr| !addr.is_empty()))
+        });
+
+        env.set("AGGRIVATOR_OUTPUT_SINK", &mut self.output.sink);
+        env.set("AGGRIVATOR_OUTPUT_SQLITE_DB", &mut self.output.sqlite_db);
+        env.set("AGGRIVATOR_OUTPUT_JSON_DIR", &mut self.output.json_dir);
+
+        env.set_with("AGGRIVATOR_SIGNING_KEY", &mut self.signing.key, |value| {
+            Ok(Some(PathBuf::from(value)).filter(|path| !path.as_os_str().is_empty()))
+        });
+        env.set("AGGRIVATOR_SIGNATURE_AGENT", &mut self.signing.agent);
+        env.set("AGGRIVATOR_SIGNATURE_TTL", &mut self.signing.ttl_secs);
+
+        env.set("AGGRIVATOR_PER_HOST_LIMIT", &mut self.politeness.per_host_limit);
+        env.set("AGGRIVATOR_PER_IP_LIMIT", &mut self.politeness.per_ip_limit);
+        env.set("AGGRIVATOR_HOST_DELAY_MS", &mut self.politeness.host_delay_ms);
+
+        env.set("AGGRIVATOR_RETRY_ATTEMPTS", &mut self.retry.attempts);
+        env.set("AGGRIVATOR_RETRY_BASE_MS", &mut self.retry.base_ms);
+        env.set("AGGRIVATOR_RETRY_MAX_DELAY_SECS", &mut self.retry.max_delay_secs);
+        env.set("AGGRIVATOR_RETRY_BUDGET_SECS", &mut self.retry.budget_secs);
+
+        env.set("AGGRIVATOR_BREAKER_THRESHOLD", &mut self.breaker.threshold);
+        env.set("AGGRIVATOR_BREAKER_COOL_DOWN_SECS", &mut self.breaker.cool_down_secs);
+
+        env.set_with("AGGRIVATOR_SCHEDULE", &mut self.schedule.enabled, schedule_flag);
+        env.set("AGGRIVATOR_SCHEDULE_MIN_MINUTES", &mut self.schedule.min_minutes);
+        env.set("AGGRIVATOR_SCHEDULE_MAX_DAYS", &mut self.schedule.max_days);
+        env.set("AGGRIVATOR_SCHEDULE_INITIAL_HOURS", &mut self.schedule.initial_hours);
+        env.set("AGGRIVATOR_SCHEDULE_MAX_CACHE_HOURS", &mut self.schedule.max_cache_hours);
+
+        env.set("AGGRIVATOR_QUEUE_MODE", &mut self.queue.mode);
+        env.set_some("AGGRIVATOR_QUEUE_DEAD", &mut self.queue.dead);
+        env.set("AGGRIVATOR_QUEUE_DEAD_SAMPLE_PERCENT", &mut self.queue.dead_sample_percent);
+        env.set("AGGRIVATOR_QUEUE_DEAD_RECHECK_DAYS", &mut self.queue.dead_recheck_days);
+        env.set_some("AGGRIVATOR_QUEUE_ORDER", &mut self.queue.order);
+        env.set_with("AGGRIVATOR_QUEUE_ONLY_DUE", &mut self.queue.only_due, only_due_flag);
+        env.set("AGGRIVATOR_QUEUE_FREQUENCY_UNIT_SECS", &mut self.queue.frequency_unit_secs);
+        env.set("AGGRIVATOR_QUEUE_ACTIVE_DAYS", &mut self.queue.active_days);
+        env.set("AGGRIVATOR_QUEUE_STALE_DAYS", &mut self.queue.stale_days);
+        env.set_some("AGGRIVATOR_QUEUE_STALE_INTERVAL_HOURS", &mut self.queue.stale_interval_hours);
+        env.set_some("AGGRIVATOR_QUEUE_DORMANT_INTERVAL_HOURS", &mut self.queue.dormant_interval_hours);
+        env.set("AGGRIVATOR_QUEUE_LIMIT", &mut self.queue.limit);
+
+        env.set("AGGRIVATOR_LOG_LEVEL", &mut self.log.level);
+        env.set("AGGRIVATOR_LOG_FORMAT", &mut self.log.format);
+
+        env.set_with("AGGRIVATOR_OTLP_ENDPOINT", &mut self.otlp.endpoint, |value| {
+            Ok(Some(value.trim().to_string()).filter(|endpoint| !endpoint.is_empty()))
+        });
+        env.set("AGGRIVATOR_OTLP_PROTOCOL", &mut self.otlp.protocol);
+        env.set("AGGRIVATOR_OTLP_SAMPLE_RATIO", &mut self.otlp.sample_ratio);
+        env.set("AGGRIVATOR_OTLP_SERVICE_NAME", &mut self.otlp.service_name);
+
+        match env.error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Everything wrong with the settings, as one message each. Doesn't touch
+    /// the filesystem.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let mut check = |ok: bool, problem: &str| {
+            if !ok {
+                problems.push(problem.to_string());
+            }
+        };
+        check(self.concurrency > 0, "concurrency must be at least 1");
+        check(self.connect_timeout_secs > 0, "connect_timeout_secs must be at least 1");
+        check(self.request_timeout_secs > 0, "request_timeout_secs must be at least 1");
+        check(self.max_body_length > 0, "max_body_length must be at least 1");
+        check(matches!(self.header_format.as_str(), "full" | "legacy"), "header_format must be full or legacy");
+        check(self.feeds_dir != self.redirects_dir, "feeds_dir and redirects_dir must be different");
+        check(
+            self.output.sink.parse::<SinkKind>().is_ok(),
+            "output.sink must be files, jsonl, sqlite or json-dir",
+        );
+        check(self.write_back_batch > 0, "write_back_batch must be at least 1");
+        check(self.status_poll_secs > 0, "status_poll_secs must be at least 1");
+        check(self.daemon_interval_secs > 0, "daemon_interval_secs must be at least 1");
+        check(
+            self.metrics_addr.as_deref().is_none_or(|addr| addr.parse::<SocketAddr>().is_ok()),
+            "metrics_addr must be an address and port, like 0.0.0.0:9898",
+        );
+        check(self.signing.agent.starts_with("https://"), "signing.agent must be an https:// url");
+        check(self.politeness.per_host_limit > 0, "politeness.per_host_limit must be at least 1");
+        check(self.retry.attempts > 0, "retry.attempts must be at least 1");
+        check(self.schedule.min_minutes > 0, "schedule.min_minutes must be at least 1");
+        check(
+            self.schedule.min_minutes <= self.schedule.max_days * 24 * 60,
+            "schedule.min_minutes must not be longer than schedule.max_days",
+        );
+        check(matches!(self.queue.mode.as_str(), "due" | "all"), "queue.mode must be due or all");
+        check(
+            matches!(self.queue.dead.as_deref(), None | Some("include" | "skip" | "sample")),
+            "queue.dead must be include, skip or sample",
+        );
+        check(self.queue.dead_sample_percent <= 100, "queue.dead_sample_percent must be at most 100");
+        check(
+            matches!(self.queue.order.as_deref(), None | Some("id" | "priority")),
+            "queue.order must be id or priority",
+        );
+        check(EnvFilter::try_new(&self.log.level).is_ok(), "log.level must be a level or a tracing filter");
+        check(matches!(self.log.format.as_str(), "text" | "json"), "log.format must be text or json");
+        check(
+            self.otlp.endpoint.as_deref().is_none_or(|endpoint| {
+                Url::parse(endpoint).is_ok_and(|url| matches!(url.scheme(), "http" | "https"))
+            }),
+            "otlp.endpoint must be an http:// or https:// url",
+        );
+        check(
+            matches!(self.otlp.protocol.as_str(), "http/protobuf" | "http/json"),
+            "otlp.protocol must be http/protobuf or http/json",
+        );
+        check((0.0..=1.0).contains(&self.otlp.sample_ratio), "otlp.sample_ratio must be between 0 and 1");
+        problems
+    }
+
+    pub fn legacy_header(&self) -> bool {
+        self.header_format == "legacy"
+    }
+
+    pub fn naming_policy(&self) -> NamingPolicy {
+        self.file_policy
+    }
+
+    pub fn sink_kind(&self) -> SinkKind {
+        self.output.sink.parse().unwrap_or(SinkKind::Files)
+    }
+
+    /// The directories the configured sink writes to.
+    pub fn output_dirs(&self) -> Vec<PathBuf> {
+        match self.sink_kind() {
+            SinkKind::Files => vec![self.feeds_dir.clone(), self.redirects_dir.clone()],
+            SinkKind::JsonDir => vec![self.output.json_dir.clone(), JsonDirSink::redirects_dir_in(&self.output.json_dir)],
+            SinkKind::JsonLines | SinkKind::Sqlite => Vec::new(),
+        }
+    }
+
+    pub fn connect_timeout(&self) -> Duration {
+        Duration::from_secs(self.connect_timeout_secs)
+    }
+
+    pub fn request_timeout(&self) -> Duration {
+        Duration::from_secs(self.request_timeout_secs)
+    }
+
+    /// Where to serve metrics. `None` if unset, or if it isn't an address
+    /// (which `problems` reports).
+    pub fn metrics_addr(&self) -> Option<SocketAddr> {
+        self.metrics_addr.as_deref()?.parse().ok()
+    }
+
+    pub fn politeness(&self) -> PolitenessConfig {
+        PolitenessConfig {
+            per_host_limit: self.politeness.per_host_limit,
+            per_ip_limit: Some(self.politeness.per_ip_limit).filter(|limit| *limit > 0),
+            min_host_delay: Duration::from_millis(self.politeness.host_delay_ms),
+        }
+    }
+
+    pub fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: self.retry.attempts.max(1),
+            base_delay: Duration::from_millis(self.retry.base_ms),
+            max_delay: Duration::from_secs(self.retry.max_delay_secs),
+            max_total: Duration::from_secs(self.retry.budget_secs),
+        }
+    }
+
+    pub fn breaker_config(&self) -> BreakerConfig {
+        BreakerConfig {
+            failure_threshold: self.breaker.threshold,
+            cool_down: Duration::from_secs(self.breaker.cool_down_secs),
+        }
+    }
+
+    /// `None` when adaptive scheduling is off.
+    pub fn schedule_config(&self) -> Option<ScheduleConfig> {
+        if !self.schedule.enabled {
+            return None;
+        }
+        Some(ScheduleConfig {
+            min_interval_secs: self.schedule.min_minutes * 60,
+            max_interval_secs: self.schedule.max_days * DAY_SECS,
+            initial_interval_secs: self.schedule.initial_hours * HOUR_SECS,
+            max_cache_hint_secs: self.schedule.max_cache_hours * HOUR_SECS,
+        })
+    }
+
+    pub fn queue_selection(&self) -> QueueSelection {
+        let queue = &self.queue;
+        let mut selection = match queue.mode.as_str() {
+            "due" => QueueSelection::due(),
+            _ => QueueSelection::everything(),
+        };
+        selection.dead = match queue.dead.as_deref() {
+            Some("include") => DeadPolicy::Include,
+            Some("skip") => DeadPolicy::Skip,
+            Some("sample") => DeadPolicy::Sample { percent: 0, recheck_secs: 0 },
+            _ => selection.dead,
+        };
+        if let DeadPolicy::Sample { .. } = selection.dead {
+            selection.dead = DeadPolicy::Sample {
+                percent: queue.dead_sample_percent.min(100),
+                recheck_secs: queue.dead_recheck_days * DAY_SECS,
+            };
+        }
+        selection.order = match queue.order.as_deref() {
+            Some("id") => QueueOrder::Id,
+            Some("priority") => QueueOrder::Priority,
+            _ => selection.order,
+        };
+        selection.only_due = queue.only_due.unwrap_or(selection.only_due);
+        selection.update_frequency_unit_secs = queue.frequency_unit_secs;
+        selection.active_days = queue.active_days;
+        selection.stale_days = queue.stale_days;
+        if let Some(hours) = queue.stale_interval_hours {
+            selection.stale_interval_secs = hours * HOUR_SECS;
+        }
+        if let Some(hours) = queue.dormant_interval_hours {
+            selection.dormant_interval_secs = hours * HOUR_SECS;
+        }
+        selection.limit = Some(queue.limit).filter(|limit| *limit > 0);
+        selection.adaptive &= self.schedule.enabled;
+        selection
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        move |name| vars.get(name).cloned()
+    }
+
+    #[test]
+    fn defaults_match_the_library_defaults() {
+        let config = Config::default();
+        assert!(config.problems().is_empty());
+        assert_eq!(config.retry_policy().max_attempts, RetryPolicy::default().max_attempts);
+        assert_eq!(config.politeness().per_ip_limit, None);
+        assert_eq!(config.breaker_config().cool_down, BreakerConfig::default().cool_down);
+        assert_eq!(config.schedule_config().unwrap().initial_interval_secs, ScheduleConfig::default().initial_interval_secs);
+        let selection = config.queue_selection();
+        let defaults = QueueSelection::default();
+        assert_eq!((selection.dead, selection.order, selection.only_due), (defaults.dead, defaults.order, defaults.only_due));
+        assert_eq!(config.sink_kind(), SinkKind::Files);
+        //The encoding and the rest of the extra lines are written unless asked not to be
+        assert!(!config.legacy_header());
+        assert_eq!(config.output_dirs(), vec![PathBuf::from("feeds"), PathBuf::from("redirects")]);
+    }
+
+    #[test]
+    fn env_overrides_the_file() {
+        let mut config = Config::from_toml(
+            "concurrency = 10\nfeeds_dir = \"/data/feeds\"\n[retry]\nattempts = 5\n[queue]\nmode = \"all\"\n",
+        )
+        .unwrap();
+        config
+            .apply_env(env(&[("AGGRIVATOR_RETRY_ATTEMPTS", "2"), ("AGGRIVATOR_SCHEDULE", "off"), ("AGGRIVATOR_WRITE_BACK", "1")]))
+            .unwrap();
+        assert_eq!(config.concurrency, 10);
+        assert_eq!(config.feeds_dir, PathBuf::from("/data/feeds"));
+        assert_eq!(config.retry.attempts, 2);
+        assert!(config.schedule_config().is_none());
+        assert!(config.write_back);
+
+        //`all` keeps its own defaults unless they're set explicitly
+        let selection = config.queue_selection();
+        assert_eq!((selection.dead, selection.order, selection.only_due), (DeadPolicy::Include, QueueOrder::Id, false));
+        config.apply_env(env(&[("AGGRIVATOR_QUEUE_DEAD", "skip")])).unwrap();
+        assert_eq!(config.queue_selection().dead, DeadPolicy::Skip);
+
+        //`due` turns the rest of the rules on
+        config.apply_env(env(&[("AGGRIVATOR_QUEUE_MODE", "due")])).unwrap();
+        let selection = config.queue_selection();
+        assert_eq!((selection.dead, selection.order, selection.only_due), (DeadPolicy::Skip, QueueOrder::Priority, true));
+    }
+
+    #[test]
+    fn on_off_env_vars_keep_their_old_meanings() {
+        let flags = |vars: &[(&str, &str)]| {
+            let mut config = Config::default();
+            config.apply_env(env(vars)).unwrap();
+            (config.write_back, config.schedule.enabled, config.fsync, config.queue.only_due)
+        };
+        assert_eq!(flags(&[]), (false, true, false, None));
+        assert_eq!(
+            flags(&[
+                ("AGGRIVATOR_WRITE_BACK", "yes"),
+                ("AGGRIVATOR_SCHEDULE", ""),
+                ("AGGRIVATOR_FSYNC", "true"),
+                ("AGGRIVATOR_QUEUE_ONLY_DUE", "2"),
+            ]),
+            (true, true, true, Some(true))
+        );
+        assert_eq!(
+            flags(&[
+                ("AGGRIVATOR_WRITE_BACK", "0"),
+                ("AGGRIVATOR_SCHEDULE", "off"),
+                ("AGGRIVATOR_FSYNC", "1"),
+                ("AGGRIVATOR_QUEUE_ONLY_DUE", "0"),
+            ]),
+            (false, false, false, Some(false))
+        );
+        assert_eq!(flags(&[("AGGRIVATOR_WRITE_BACK", "false"), ("AGGRIVATOR_QUEUE_ONLY_DUE", "no")]), (true, true, false, None));
+    }
+
+    #[test]
+    fn rejects_bad_values() {
+        assert!(Config::from_toml("concurency = 10").is_err());
+        let mut config = Config::default();
+        let e = config.apply_env(env(&[("AGGRIVATOR_CONCURRENCY", "lots")])).unwrap_err();
+        assert!(e.to_string().starts_with("invalid AGGRIVATOR_CONCURRENCY"));
+        assert!(Config::from_toml("file_policy = \"newest\"").is_err());
+        let e = config.apply_env(env(&[("AGGRIVATOR_FILE_POLICY", "newest")])).unwrap_err();
+        assert!(e.to_string().starts_with("invalid AGGRIVATOR_FILE_POLICY"));
+
+        config.retry.attempts = 0;
+        config.queue.dead = Some("bury".to_string());
+        config.log.level = "verbose=very=much".to_string();
+        config.log.format = "xml".to_string();
+        config.otlp.endpoint = Some("localhost:4318".to_string());
+        config.otlp.sample_ratio = 2.0;
+        config.output.sink = "kafka".to_string();
+        assert_eq!(config.problems().len(), 7);
+    }
+}
@@ -0,0 +1,258 @@
+//! The daemon's loop, and what stop and reload signals do to it. The polling
+//! itself is behind `Daemon`, so the loop can be driven without a queue.
+
+use std::error::Error;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use tokio::sync::Notify;
+use tokio::time::Instant;
+use tracing::{error, info};
+
+/// Raised by SIGTERM/SIGINT and SIGHUP. `wake` cuts short a daemon's wait
+/// between passes, `stopped` every retry wait.
+#[derive(Default)]
+pub struct SignalState {
+    shutdown: AtomicBool,
+    reload: AtomicBool,
+    wake: Notify,
+    stopped: Notify,
+}
+
+impl SignalState {
+    /// A stop signal: halt the run so no new feeds are started. Returns true
+    /// for the second one, when the process should exit without waiting.
+    pub fn stop(&self, halted: &AtomicBool) -> bool {
+        if self.shutdown.swap(true, Ordering::SeqCst) {
+            return true;
+        }
+        halted.store(true, Ordering::SeqCst);
+        self.wake.notify_one();
+        self.stopped.notify_waiters();
+        false
+    }
+
+    /// A reload signal: have the daemon load its settings again before the
+    /// next pass, which starts straight away.
+    pub fn hang_up(&self) {
+        self.reload.store(true, Ordering::SeqCst);
+        self.wake.notify_one();
+    }
+
+    pub fn stopping(&self) -> bool {
+        self.shutdown.load(Ordering::SeqCst)
+    }
+
+    /// Clear a `status.go` halt left by the last pass, before starting the
+    /// next one. A stop signal that came in between passes stays raised.
+    pub fn resume(&self, halted: &AtomicBool) {
+        halted.store(self.stopping(), Ordering::SeqCst);
+    }
+
+    /// Why a run stopped early, if it did.
+    pub fn halt_reason(&self, halted: &AtomicBool) -> Option<&'static str> {
+        match (self.stopping(), halted.load(Ordering::SeqCst)) {
+            (true, _) => Some("a stop signal"),
+            (false, true) => Some("status.go"),
+            (false, false) => None,
+        }
+    }
+
+    /// Wait for a stop signal, or return straight away if there has been one.
+    pub async fn stopped(&self) {
+        let notified = self.stopped.notified();
+        tokio::pin!(notified);
+        //Registered before the check, so a stop in between isn't missed
+        notified.as_mut().enable();
+        if !self.stopping() {
+            notified.await;
+        }
+    }
+
+    /// Wait until a signal wants attention.
+    pub async fn woken(&self) {
+        self.wake.notified().await
+    }
+}
+
+/// What the daemon loop drives.
+pub trait Daemon {
+    /// Load the settings again after a SIGHUP. On an error the old ones stay.
+    fn reload(&mut self) -> Result<(), Box<dyn Error>>;
+
+    /// Time from the start of one pass to the start of the next.
+    fn interval(&self) -> Duration;
+
+    /// Check whatever is due.
+    fn pass(&mut self) -> impl Future<Output = ()>;
+}
+
+/// Run passes until a stop signal, reloading first whenever a SIGHUP came in
+/// since the last one.
+pub async fn run(daemon: &mut impl Daemon, signals: &SignalState) {
+    while !signals.stopping() {
+        let pass_started = Instant::now();
+
+        if signals.reload.swap(false, Ordering::SeqCst) {
+            info!("Reloading settings.");
+            if let Err(e) = daemon.reload() {
+                error!(error = %e, "Error reloading settings, keeping the old ones");
+            }
+        }
+
+        daemon.pass().await;
+
+        //Wait out the rest of the interval, unless a signal wants attention sooner
+        if let Some(rest) = daemon.interval().checked_sub(pass_started.elapsed()) {
+            tokio::select! {
+                _ = tokio::time::sleep(rest) => {}
+                _ = signals.woken() => {}
+            }
+        }
+    }
+    info!("Daemon stopped.");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //Counts passes and reloads, and raises signals from inside a pass
+    struct Script<'a> {
+        signals: &'a SignalState,
+        halted: AtomicBool,
+        interval: Duration,
+        passes: u32,
+        reloads: u32,
+        fail_reload: bool,
+        on_pass: fn(u32, &SignalState, &AtomicBool),
+    }
+
+    impl<'a> Script<'a> {
+        fn new(signals: &'a SignalState, on_pass: fn(u32, &SignalState, &AtomicBool)) -> Self {
+            Self {
+                signals,
+                halted: AtomicBool::new(false),
+                interval: Duration::from_secs(3600),
+                passes: 0,
+                reloads: 0,
+                fail_reload: false,
+                on_pass,
+            }
+        }
+    }
+
+    impl Daemon for Script<'_> {
+        fn reload(&mut self) -> Result<(), Box<dyn Error>> {
+            self.reloads += 1;
+            match self.fail_reload {
+                true => Err("bad config".into()),
+                false => {
+                    self.interval = Duration::from_millis(1);
+                    Ok(())
+                }
+            }
+        }
+
+        fn interval(&self) -> Duration {
+            self.interval
+        }
+
+        async fn pass(&mut self) {
+            self.signals.resume(&self.halted);
+            self.passes += 1;
+            (self.on_pass)(self.passes, self.signals, &self.halted);
+        }
+    }
+
+    async fn run_briefly(script: &mut Script<'_>) {
+        let signals = script.signals;
+        tokio::time::timeout(Duration::from_secs(5), run(script, signals)).await.expect("daemon didn't stop");
+    }
+
+    #[test]
+    fn second_stop_exits() {
+        let signals = SignalState::default();
+        let halted = AtomicBool::new(false);
+        assert_eq!(signals.halt_reason(&halted), None);
+
+        assert!(!signals.stop(&halted));
+        assert!(halted.load(Ordering::SeqCst));
+        assert_eq!(signals.halt_reason(&halted), Some("a stop signal"));
+        assert!(signals.stop(&halted));
+    }
+
+    #[test]
+    fn resuming_keeps_a_stop_but_clears_status_go() {
+        let signals = SignalState::default();
+        let halted = AtomicBool::new(true);
+        assert_eq!(signals.halt_reason(&halted), Some("status.go"));
+        signals.resume(&halted);
+        assert!(!halted.load(Ordering::SeqCst));
+
+        signals.stop(&halted);
+        signals.resume(&halted);
+        assert!(halted.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn stop_wakes_every_waiter() {
+        let signals = std::sync::Arc::new(SignalState::default());
+        let waiters: Vec<_> = (0..3)
+            .map(|_| {
+                let signals = signals.clone();
+                tokio::spawn(async move { signals.stopped().await })
+            })
+            .collect();
+        tokio::task::yield_now().await;
+        signals.stop(&AtomicBool::new(false));
+        for waiter in waiters {
+            tokio::time::timeout(Duration::from_secs(5), waiter).await.unwrap().unwrap();
+        }
+        //And anyone who starts waiting later doesn't wait at all
+        tokio::time::timeout(Duration::from_secs(5), signals.stopped()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn stop_during_a_pass_ends_the_loop_without_waiting() {
+        let signals = SignalState::default();
+        let mut script = Script::new(&signals, |_, signals, halted| {
+            signals.stop(halted);
+        });
+        run_briefly(&mut script).await;
+        assert_eq!(script.passes, 1);
+        assert!(script.halted.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn hang_up_reloads_before_the_next_pass() {
+        let signals = SignalState::default();
+        let mut script = Script::new(&signals, |pass, signals, halted| match pass {
+            1 => signals.hang_up(),
+            3 => {
+                signals.stop(halted);
+            }
+            _ => {}
+        });
+        run_briefly(&mut script).await;
+        //The hang up cut the hour long wait short, and the reload shortened the interval
+        assert_eq!((script.passes, script.reloads), (3, 1));
+    }
+
+    #[tokio::test]
+    async fn failed_reload_keeps_going() {
+        let signals = SignalState::default();
+        signals.hang_up();
+        let mut script = Script::new(&signals, |pass, signals, halted| {
+            if pass == 2 {
+                signals.stop(halted);
+            } else {
+                signals.hang_up();
+            }
+        });
+        script.fail_reload = true;
+        run_briefly(&mut script).await;
+        assert_eq!((script.passes, script.reloads), (2, 2));
+    }
+}
@@ -0,0 +1,177 @@
+//! Working out which character encoding a feed body is in, so it can be
+//! transcoded to UTF-8 without mangling ISO-8859-1 or Windows-1252 feeds.
+
+use encoding_rs::{Encoding, UTF_8};
+
+/// How many leading bytes of a body are enough to find an XML declaration.
+pub const SNIFF_LENGTH: usize = 1024;
+
+/// Where a detected encoding came from, strongest first.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EncodingSource {
+    /// A byte order mark at the start of the body.
+    Bom,
+    /// The `charset` parameter of the `Content-Type` header.
+    Header,
+    /// The `encoding` pseudo-attribute of the `<?xml ...?>` declaration.
+    XmlDeclaration,
+    /// Nothing said otherwise, so UTF-8.
+    Default,
+}
+
+impl EncodingSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EncodingSource::Bom => "bom",
+            EncodingSource::Header => "header",
+            EncodingSource::XmlDeclaration => "xml",
+            EncodingSource::Default => "default",
+        }
+    }
+}
+
+/// The encoding a body will be decoded with, and why.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DetectedEncoding {
+    pub encoding: &'static Encoding,
+    pub source: EncodingSource,
+}
+
+/// The `charset` parameter of a `Content-Type` header value, if any.
+pub fn charset_from_content_type(content_type: &str) -> Option<&str> {
+    content_type.split(';').skip(1).find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        if name.trim().eq_ignore_ascii_case("charset") {
+            Some(value.trim().trim_matches('"'))
+        } else {
+            None
+        }
+    })
+}
+
+/// The value of the `encoding` pseudo-attribute in a leading XML declaration.
+pub fn xml_declared_encoding(prefix: &[u8]) -> Option<&str> {
+    let prefix = prefix.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(prefix);
+    if !prefix.starts_with(b"<?xml") {
+        return None;
+    }
+    let end = find(prefix, b"?>")?;
+    let declaration = std::str::from_utf8(&prefix[..end]).ok()?;
+    let at = declaration.find("encoding")?;
+    let rest = declaration[at + "encoding".len()..].trim_start();
+    let rest = rest.strip_prefix('=')?.trim_start();
+    let quote = rest.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+    let value = &rest[1..];
+    value.find(quote).map(|close| &value[..close])
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+/// Pick the encoding for a body from its `Content-Type` header and its first
+/// bytes. A BOM wins, then the header charset, then the XML declaration.
+/// A declaration naming UTF-16 is ignored: we could only read it because the
+/// bytes are ASCII-compatible, so it can't be right.
+pub fn detect(content_type: Option<&str>, prefix: &[u8]) -> DetectedEncoding {
+    if let Some((encoding, _)) = Encoding::for_bom(prefix) {
+        return DetectedEncoding {
+            encoding,
+            source: EncodingSource::Bom,
+        };
+    }
+    if let Some(encoding) = content_type
+        .and_then(charset_from_content_type)
+        .and_then(|charset| Encoding::for_label(charset.as_bytes()))
+    {
+        return DetectedEncoding {
+            encoding,
+            source: EncodingSource::Header,
+        };
+    }
+    if let Some(encoding) = xml_declared_encoding(prefix)
+        .and_then(|label| Encoding::for_label(label.as_bytes()))
+        .filter(|encoding| encoding.is_ascii_compatible())
+    {
+        return DetectedEncoding {
+            encoding,
+            source: EncodingSource::XmlDeclaration,
+        };
+    }
+    DetectedEncoding {
+        encoding: UTF_8,
+        source: EncodingSource::Default,
+    }
+}
+
+/// Once a body has been transcoded to UTF-8, its XML declaration must say so
+/// or a parser honoring it would decode the text a second time. Returns the
+/// text with the declared encoding replaced, or `None` if nothing changed.
+pub fn relabel_xml_declaration(text: &str) -> Option<String> {
+    let declared = xml_declared_encoding(text.as_bytes())?;
+    if declared.eq_ignore_ascii_case("utf-8") {
+        return None;
+    }
+    let offset = text.strip_prefix('\u{feff}').map_or(0, |_| '\u{feff}'.len_utf8());
+    let attribute = offset + text[offset..].find("encoding")?;
+    let start = attribute + text[attribute..].find(declared)?;
+    Some(format!("{}UTF-8{}", &text[..start], &text[start + declared.len()..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use encoding_rs::{UTF_16LE, WINDOWS_1252};
+
+    #[test]
+    fn charset_parameter_is_case_and_quote_insensitive() {
+        assert_eq!(charset_from_content_type("text/xml; Charset=\"ISO-8859-1\""), Some("ISO-8859-1"));
+        assert_eq!(charset_from_content_type("application/rss+xml"), None);
+    }
+
+    #[test]
+    fn reads_the_declared_encoding() {
+        assert_eq!(
+            xml_declared_encoding(b"<?xml version=\"1.0\" encoding='windows-1252'?><rss/>"),
+            Some("windows-1252")
+        );
+        assert_eq!(xml_declared_encoding(b"<?xml version=\"1.0\"?><rss/>"), None);
+        assert_eq!(xml_declared_encoding(b"<rss/>"), None);
+    }
+
+    #[test]
+    fn precedence_is_bom_then_header_then_declaration() {
+        let declared = b"<?xml version=\"1.0\" encoding=\"ISO-8859-1\"?><rss/>";
+        assert_eq!(
+            detect(None, declared),
+            DetectedEncoding { encoding: WINDOWS_1252, source: EncodingSource::XmlDeclaration }
+        );
+        assert_eq!(
+            detect(Some("text/xml; charset=utf-8"), declared),
+            DetectedEncoding { encoding: UTF_8, source: EncodingSource::Header }
+        );
+        assert_eq!(
+            detect(Some("text/xml; charset=iso-8859-1"), b"\xFF\xFE<\x00"),
+            DetectedEncoding { encoding: UTF_16LE, source: EncodingSource::Bom }
+        );
+        assert_eq!(
+            detect(Some("text/xml"), b"<rss/>"),
+            DetectedEncoding { encoding: UTF_8, source: EncodingSource::Default }
+        );
+    }
+
+    #[test]
+    fn utf16_declaration_in_ascii_bytes_is_ignored() {
+        assert_eq!(detect(None, b"<?xml version=\"1.0\" encoding=\"UTF-16\"?>").source, EncodingSource::Default);
+    }
+
+    #[test]
+    fn relabels_transcoded_declaration() {
+        assert_eq!(
+            relabel_xml_declaration("<?xml version=\"1.0\" encoding=\"ISO-8859-1\"?><rss>café</rss>").as_deref(),
+            Some("<?xml version=\"1.0\" encoding=\"UTF-8\"?><rss>café</rss>")
+        );
+        assert_eq!(relabel_xml_declaration("<?xml version=\"1.0\" encoding=\"utf-8\"?><rss/>"), None);
+        assert_eq!(relabel_xml_declaration("<rss/>"), None);
+    }
+}
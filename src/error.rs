@@ -0,0 +1,337 @@
+//! Why a feed fetch failed. Every failure maps to a distinct 6xx pseudo-status
+//! (used in the feed file name) and a machine-readable reason, so the parser
+//! can tell a dead domain from a flaky server.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// Generic transport failure, when nothing more specific is known.
+pub const ERRORCODE_GENERAL_CONNECTION_FAILURE: u16 = 666;
+/// Generic failure while downloading a response body.
+pub const ERRORCODE_GENERAL_DOWNLOAD_FAILURE: u16 = 667;
+/// The response body was larger than we are willing to store.
+pub const ERRORCODE_GENERAL_FILE_SIZE_EXCEEDED: u16 = 668;
+/// Not fetched: the host's circuit breaker is open.
+pub const ERRORCODE_HOST_DEFERRED: u16 = 669;
+pub const ERRORCODE_DNS_NXDOMAIN: u16 = 670;
+pub const ERRORCODE_DNS_SERVFAIL: u16 = 671;
+pub const ERRORCODE_CONNECT_REFUSED: u16 = 672;
+pub const ERRORCODE_CONNECT_TIMEOUT: u16 = 673;
+pub const ERRORCODE_TLS_FAILURE: u16 = 674;
+pub const ERRORCODE_READ_TIMEOUT: u16 = 675;
+pub const ERRORCODE_CONNECTION_RESET: u16 = 676;
+pub const ERRORCODE_TOO_MANY_REDIRECTS: u16 = 677;
+pub const ERRORCODE_BODY_DECODE_FAILURE: u16 = 678;
+pub const ERRORCODE_INVALID_URL: u16 = 679;
+/// Fetched, but the result couldn't be written out here.
+pub const ERRORCODE_LOCAL_WRITE_FAILURE: u16 = 680;
+
+/// A failed feed fetch. The `String`s carry the underlying error message.
+#[derive(Debug)]
+pub enum FetchError {
+    /// The hostname does not exist.
+    DnsNxDomain(String),
+    /// The name servers failed or timed out; the name may well exist.
+    DnsServFail(String),
+    ConnectRefused(String),
+    ConnectTimeout(String),
+    /// TLS handshake or certificate verification failed.
+    Tls(String),
+    /// Connected, but the response (headers or body) didn't arrive in time.
+    ReadTimeout(String),
+    /// The server closed or reset the connection mid-exchange.
+    ConnectionReset(String),
+    TooManyRedirects(usize),
+    /// The body could not be decoded (e.g. corrupt gzip).
+    BodyDecode(String),
+    /// The body was larger than the limit; holds the bytes seen.
+    SizeExceeded(u64),
+    InvalidUrl(String),
+    /// Not attempted because the host has been failing; holds the host.
+    HostDeferred(String),
+    /// Some other failure before a response arrived.
+    Connection(String),
+    /// Some other failure while reading the body.
+    Download(String),
+    /// The response was fine, but writing it out locally failed.
+    LocalWrite(String),
+}
+
+impl FetchError {
+    /// The pseudo-status code recorded for this failure.
+    pub fn status_code(&self) -> u16 {
+        match self {
+            FetchError::DnsNxDomain(_) => ERRORCODE_DNS_NXDOMAIN,
+            FetchError::DnsServFail(_) => ERRORCODE_DNS_SERVFAIL,
+            FetchError::ConnectRefused(_) => ERRORCODE_CONNECT_REFUSED,
+            FetchError::ConnectTimeout(_) => ERRORCODE_CONNECT_TIMEOUT,
+            FetchError::Tls(_) => ERRORCODE_TLS_FAILURE,
+            FetchError::ReadTimeout(_) => ERRORCODE_READ_TIMEOUT,
+            FetchError::ConnectionReset(_) => ERRORCODE_CONNECTION_RESET,
+            FetchError::TooManyRedirects(_) => ERRORCODE_TOO_MANY_REDIRECTS,
+            FetchError::BodyDecode(_) => ERRORCODE_BODY_DECODE_FAILURE,
+            FetchError::SizeExceeded(_) => ERRORCODE_GENERAL_FILE_SIZE_EXCEEDED,
+            FetchError::InvalidUrl(_) => ERRORCODE_INVALID_URL,
+            FetchError::HostDeferred(_) => ERRORCODE_HOST_DEFERRED,
+            FetchError::Connection(_) => ERRORCODE_GENERAL_CONNECTION_FAILURE,
+            FetchError::Download(_) => ERRORCODE_GENERAL_DOWNLOAD_FAILURE,
+            FetchError::LocalWrite(_) => ERRORCODE_LOCAL_WRITE_FAILURE,
+        }
+    }
+
+    /// A short, stable, machine-readable name for this kind of failure.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            FetchError::DnsNxDomain(_) => "dns_nxdomain",
+            FetchError::DnsServFail(_) => "dns_servfail",
+            FetchError::ConnectRefused(_) => "connect_refused",
+            FetchError::ConnectTimeout(_) => "connect_timeout",
+            FetchError::Tls(_) => "tls_error",
+            FetchError::ReadTimeout(_) => "read_timeout",
+            FetchError::ConnectionReset(_) => "connection_reset",
+            FetchError::TooManyRedirects(_) => "too_many_redirects",
+            FetchError::BodyDecode(_) => "body_decode",
+            FetchError::SizeExceeded(_) => "size_exceeded",
+            FetchError::InvalidUrl(_) => "invalid_url",
+            FetchError::HostDeferred(_) => "host_deferred",
+            FetchError::Connection(_) => "connection_failure",
+            FetchError::Download(_) => "download_failure",
+            FetchError::LocalWrite(_) => "local_write",
+        }
+    }
+
+    /// Whether the failure is likely to go away if we try again shortly.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            FetchError::ConnectTimeout(_) | FetchError::ReadTimeout(_) | FetchError::ConnectionReset(_)
+        )
+    }
+
+    /// What actually went wrong, as a single line of human-readable text.
+    pub fn detail(&self) -> String {
+        match self {
+            FetchError::TooManyRedirects(hops) => format!("gave up after {} redirects", hops),
+            FetchError::SizeExceeded(bytes) => format!("body exceeded the limit at {} bytes", bytes),
+            FetchError::HostDeferred(host) => format!("circuit open for {}, not attempted", host),
+            FetchError::DnsNxDomain(message)
+            | FetchError::DnsServFail(message)
+            | FetchError::ConnectRefused(message)
+            | FetchError::ConnectTimeout(message)
+            | FetchError::Tls(message)
+            | FetchError::ReadTimeout(message)
+            | FetchError::ConnectionReset(message)
+            | FetchError::BodyDecode(message)
+            | FetchError::InvalidUrl(message)
+            | FetchError::Connection(message)
+            | FetchError::Download(message)
+            | FetchError::LocalWrite(message) => message.replace(['\r', '\n'], " "),
+        }
+    }
+
+    /// Classify a reqwest error. `in_body` says whether the response headers
+    /// had already arrived, which decides the generic fallback.
+    pub fn from_reqwest(err: &reqwest::Error, in_body: bool) -> FetchError {
+        let message = error_chain_message(err);
+
+        if err.is_decode() {
+            return FetchError::BodyDecode(message);
+        }
+
+        let mut dns = false;
+        let mut source: Option<&(dyn Error + 'static)> = Some(err);
+        while let Some(current) = source {
+            let text = current.to_string();
+            if text.starts_with("dns error") {
+                dns = true;
+            }
+            if let Some(io_err) = current.downcast_ref::<io::Error>() {
+                if wraps_rustls(io_err) {
+                    return FetchError::Tls(message);
+                }
+                if dns {
+                    return classify_dns(&io_err.to_string(), message);
+                }
+                match io_err.kind() {
+                    io::ErrorKind::ConnectionRefused => return FetchError::ConnectRefused(message),
+                    io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::UnexpectedEof => return FetchError::ConnectionReset(message),
+                    _ => {}
+                }
+            }
+            if let Some(hyper_err) = current.downcast_ref::<hyper::Error>() {
+                if hyper_err.is_incomplete_message() {
+                    return FetchError::ConnectionReset(message);
+                }
+            }
+            source = current.source();
+        }
+
+        if dns {
+            return FetchError::DnsServFail(message);
+        }
+        if err.is_timeout() {
+            return match err.is_connect() {
+                true => FetchError::ConnectTimeout(message),
+                false => FetchError::ReadTimeout(message),
+            };
+        }
+        match in_body || err.is_body() {
+            true => FetchError::Download(message),
+            false => FetchError::Connection(message),
+        }
+    }
+}
+
+/// Whether `err` is a TLS failure. The connector wraps rustls's io::Error in
+/// another one, and an io::Error doesn't give what it wraps as its `source()`.
+fn wraps_rustls(err: &io::Error) -> bool {
+    match err.get_ref() {
+        Some(inner) if inner.is::<rustls::Error>() => true,
+        Some(inner) => inner.downcast_ref::<io::Error>().is_some_and(wraps_rustls),
+        None => false,
+    }
+}
+
+/// Tell a name that doesn't exist from a resolver that couldn't answer, from
+/// the getaddrinfo error text (EAI_NONAME / EAI_NODATA vs EAI_AGAIN / EAI_FAIL).
+fn classify_dns(resolver_message: &str, message: String) -> FetchError {
+    let lower = resolver_message.to_lowercase();
+    let not_found = [
+        "name or service not known",
+        "nodename nor servname provided",
+        "no address associated with hostname",
+        "no such host",
+        "host not found",
+    ];
+    match not_found.iter().any(|needle| lower.contains(needle)) {
+        true => FetchError::DnsNxDomain(message),
+        false => FetchError::DnsServFail(message),
+    }
+}
+
+/// The messages of an error and all its sources, on one line.
+fn error_chain_message(err: &(dyn Error + 'static)) -> String {
+    let mut parts: Vec<String> = Vec::new();
+    let mut source: Option<&(dyn Error + 'static)> = Some(err);
+    while let Some(current) = source {
+        let text = current.to_string();
+        if !parts.iter().any(|part| part.contains(&text)) {
+            parts.push(text);
+        }
+        source = current.source();
+    }
+    parts.join(": ")
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.reason(), self.detail())
+    }
+}
+
+impl Error for FetchError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    async fn fetch_error(url: &str, timeout: Duration) -> FetchError {
+        let client = reqwest::Client::builder().timeout(timeout).build().unwrap();
+        let err = client.get(url).send().await.unwrap_err();
+        FetchError::from_reqwest(&err, false)
+    }
+
+    #[test]
+    fn every_kind_has_its_own_code() {
+        let errors = [
+            FetchError::DnsNxDomain(String::new()),
+            FetchError::DnsServFail(String::new()),
+            FetchError::ConnectRefused(String::new()),
+            FetchError::ConnectTimeout(String::new()),
+            FetchError::Tls(String::new()),
+            FetchError::ReadTimeout(String::new()),
+            FetchError::ConnectionReset(String::new()),
+            FetchError::TooManyRedirects(10),
+            FetchError::BodyDecode(String::new()),
+            FetchError::SizeExceeded(0),
+            FetchError::InvalidUrl(String::new()),
+            FetchError::HostDeferred(String::new()),
+            FetchError::Connection(String::new()),
+            FetchError::Download(String::new()),
+            FetchError::LocalWrite(String::new()),
+        ];
+        let mut codes: Vec<u16> = errors.iter().map(|e| e.status_code()).collect();
+        codes.sort_unstable();
+        codes.dedup();
+        assert_eq!(codes.len(), errors.len());
+    }
+
+    #[test]
+    fn dns_failures_split_on_resolver_message() {
+        let nx = classify_dns("failed to lookup address information: Name or service not known", String::new());
+        assert_eq!(nx.reason(), "dns_nxdomain");
+        let servfail = classify_dns("failed to lookup address information: Temporary failure in name resolution", String::new());
+        assert_eq!(servfail.reason(), "dns_servfail");
+    }
+
+    #[tokio::test]
+    async fn refused_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let err = fetch_error(&format!("http://{}/feed", addr), Duration::from_secs(5)).await;
+        assert_eq!(err.status_code(), ERRORCODE_CONNECT_REFUSED);
+    }
+
+    #[tokio::test]
+    async fn failed_handshake_is_a_tls_error() {
+        use tokio::io::AsyncWriteExt;
+
+        //Plain http where the handshake should be
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut sock, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = sock.read(&mut buf).await;
+            let _ = sock.write_all(b"HTTP/1.1 400 Bad Request\r\ncontent-length: 0\r\n\r\n").await;
+        });
+        let err = fetch_error(&format!("https://{}/feed", addr), Duration::from_secs(5)).await;
+        assert_eq!(err.status_code(), ERRORCODE_TLS_FAILURE);
+    }
+
+    #[tokio::test]
+    async fn silent_server_is_a_read_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut sock, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = sock.read(&mut buf).await;
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        });
+        let err = fetch_error(&format!("http://{}/feed", addr), Duration::from_millis(200)).await;
+        assert_eq!(err.reason(), "read_timeout");
+    }
+
+    #[tokio::test]
+    async fn dropped_connection_is_a_reset() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut sock, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = sock.read(&mut buf).await;
+            drop(sock);
+        });
+        let err = fetch_error(&format!("http://{}/feed", addr), Duration::from_secs(5)).await;
+        assert_eq!(err.reason(), "connection_reset");
+    }
+}
@@ -0,0 +1,452 @@
+//! Writing feed files so the parser never sees a partial one: everything goes
+//! to a hidden temp file in the same directory, which is renamed into place
+//! only once it is complete. Also decides what the files are called, and what
+//! happens to older files for the same feed that the parser hasn't taken yet.
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// Suffix of in-progress files. They also start with a `.`, so nothing that
+/// looks for `*.txt` will pick them up.
+pub const TEMP_SUFFIX: &str = ".tmp";
+
+static TEMP_SEQUENCE: AtomicUsize = AtomicUsize::new(0);
+
+/// A hidden temp file name in `dir` for what will become `dir/name`. Two
+/// checks of the same feed can be in flight at once, so they are unique per
+/// process.
+fn temp_path(dir: &Path, name: &str) -> PathBuf {
+    let sequence = TEMP_SEQUENCE.fetch_add(1, Ordering::SeqCst);
+    dir.join(format!(".{}.{}.{}{}", name, std::process::id(), sequence, TEMP_SUFFIX))
+}
+
+/// A temp file in `dir` for holding something too big to keep in memory. It
+/// is unlinked as soon as it's made, so it can't be left behind.
+pub fn unlinked_temp_file(dir: &Path) -> io::Result<File> {
+    let path = temp_path(dir, "spool");
+    let file = OpenOptions::new().read(true).write(true).create_new(true).open(&path)?;
+    fs::remove_file(&path)?;
+    Ok(file)
+}
+
+/// A feed file being written. Nothing is visible under the final name until
+/// `commit`; dropping it uncommitted removes the temp file.
+pub struct PendingFeedFile {
+    file: Option<BufWriter<File>>,
+    temp_path: PathBuf,
+    final_path: PathBuf,
+    fsync: bool,
+}
+
+impl PendingFeedFile {
+    /// Start writing what will become `dir/name`. With `fsync` set, `commit`
+    /// syncs the file and the directory so the result survives a crash.
+    pub fn create(dir: &Path, name: &str, fsync: bool) -> io::Result<Self> {
+        let temp_path = temp_path(dir, name);
+        let file = File::create(&temp_path)?;
+        Ok(Self {
+            file: Some(BufWriter::new(file)),
+            temp_path,
+            final_path: dir.join(name),
+            fsync,
+        })
+    }
+
+    /// The name the file will have once committed.
+    pub fn final_path(&self) -> &Path {
+        &self.final_path
+    }
+
+    /// Flush everything written so far and move the file into place,
+    /// replacing any existing file of the same name.
+    pub fn commit(mut self) -> io::Result<PathBuf> {
+        let mut writer = self.file.take().expect("file is only taken on commit");
+        writer.flush()?;
+        let file = writer.into_inner().map_err(|e| e.into_error())?;
+        if self.fsync {
+            file.sync_all()?;
+        }
+        drop(file);
+
+        fs::rename(&self.temp_path, &self.final_path)?;
+        if self.fsync {
+            if let Some(dir) = self.final_path.parent() {
+                File::open(dir)?.sync_all()?;
+            }
+        }
+        Ok(self.final_path.clone())
+    }
+}
+
+impl Write for PendingFeedFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.as_mut().expect("file is only taken on commit").write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.as_mut().expect("file is only taken on commit").flush()
+    }
+}
+
+impl Drop for PendingFeedFile {
+    fn drop(&mut self) {
+        if self.file.take().is_some() {
+            let _ = fs::remove_file(&self.temp_path);
+        }
+    }
+}
+
+/// What to do when a feed already has a result on disk that the parser hasn't
+/// consumed yet.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NamingPolicy {
+    /// Plain `{id}_{status}.txt` names. A newer result with the same status
+    /// overwrites the older one; results with other statuses are left alone.
+    Replace,
+    /// Unique names. A newer result removes every older file for the feed in
+    /// the same directory, so the parser only ever sees the latest.
+    Supersede,
+    /// Unique names, and older files are kept so the parser can work through
+    /// them in order.
+    Queue,
+}
+
+impl FromStr for NamingPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "replace" => Ok(NamingPolicy::Replace),
+            "supersede" => Ok(NamingPolicy::Supersede),
+            "queue" => Ok(NamingPolicy::Queue),
+            other => Err(format!("unknown file policy: {}", other)),
+        }
+    }
+}
+
+/// The feed id a feed file name starts with, if it is one of ours.
+pub fn feed_id_of(file_name: &str) -> Option<u64> {
+    feed_id_with_extension(file_name, "txt")
+}
+
+fn feed_id_with_extension(file_name: &str, extension: &str) -> Option<u64> {
+    let stem = file_name.strip_suffix(extension)?.strip_suffix('.')?;
+    if stem.starts_with('.') {
+        return None;
+    }
+    stem.split('_').next()?.parse().ok()
+}
+
+/// The status a feed file name records, whatever the naming scheme.
+fn status_of(path: &Path) -> Option<u16> {
+    let stem = path.file_stem()?.to_str()?;
+    stem.split('_').nth(1)?.parse().ok()
+}
+
+/// Whether a result with `status` carries a feed body. Only these can
+/// supersede a body the parser hasn't taken yet.
+fn carries_body(status: u16) -> bool {
+    matches!(status, 200 | 203 | 214)
+}
+
+/// Names feed files for a run and applies the `NamingPolicy` as they are
+/// committed. Unique names are `{id}_{status}_{run}_{sequence}.txt`, where
+/// `run` is the run's start time, so they sort in the order they were written.
+pub struct FeedFileNamer {
+    policy: NamingPolicy,
+    run_started: u64,
+    extension: &'static str,
+    sequence: AtomicUsize,
+    existing: Mutex<HashMap<(PathBuf, u64), Vec<PathBuf>>>,
+}
+
+impl FeedFileNamer {
+    pub fn new(policy: NamingPolicy, run_started: u64) -> Self {
+        Self {
+            policy,
+            run_started,
+            extension: "txt",
+            sequence: AtomicUsize::new(0),
+            existing: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Name files `.{extension}` instead of `.txt`.
+    pub fn with_extension(mut self, extension: &'static str) -> Self {
+        self.extension = extension;
+        self
+    }
+
+    pub fn policy(&self) -> NamingPolicy {
+        self.policy
+    }
+
+    /// Note the feed files already in `dir`, so a superseding result can find
+    /// the ones left by earlier runs. Only needed for `Supersede`. Returns how
+    /// many were found.
+    pub fn index_dir(&self, dir: &Path) -> io::Result<usize> {
+        let mut found = 0;
+        let mut existing = self.existing.lock().unwrap();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if let Some(feed_id) = feed_id_with_extension(&entry.file_name().to_string_lossy(), self.extension) {
+                existing.entry((dir.to_path_buf(), feed_id)).or_default().push(entry.path());
+                found += 1;
+            }
+        }
+        Ok(found)
+    }
+
+    /// The name the next result for `feed_id` with `status` should get in `dir`.
+    pub fn file_name(&self, dir: &Path, feed_id: u64, status: u16) -> String {
+        if self.policy == NamingPolicy::Replace {
+            return self.name(feed_id, status, 0);
+        }
+        loop {
+            let name = self.name(feed_id, status, self.sequence.fetch_add(1, Ordering::SeqCst));
+            //Another process with the same start second could have used it
+            if !dir.join(&name).exists() {
+                return name;
+            }
+        }
+    }
+
+    /// The name `file_name` would give a result now, without using it up. For
+    /// reporting where a result would go: the next one written takes it.
+    pub fn preview_name(&self, feed_id: u64, status: u16) -> String {
+        self.name(feed_id, status, self.sequence.load(Ordering::SeqCst))
+    }
+
+    fn name(&self, feed_id: u64, status: u16, sequence: usize) -> String {
+        match self.policy {
+            NamingPolicy::Replace => format!("{}_{}.{}", feed_id, status, self.extension),
+            _ => format!("{}_{}_{}_{:06}.{}", feed_id, status, self.run_started, sequence, self.extension),
+        }
+    }
+
+    /// Start writing the next result for `feed_id` in `dir`.
+    pub fn create(&self, dir: &Path, feed_id: u64, status: u16, fsync: bool) -> io::Result<PendingFeedFile> {
+        PendingFeedFile::create(dir, &self.file_name(dir, feed_id, status), fsync)
+    }
+
+    /// Move a finished result for `feed_id` into place, then (when
+    /// superseding) remove the older files for that feed in the same directory.
+    /// A result without a body (a 304, an error) leaves older bodies alone, so
+    /// the parser still gets the last feed that was fetched.
+    pub fn commit(&self, feed_id: u64, pending: PendingFeedFile) -> io::Result<PathBuf> {
+        let path = pending.commit()?;
+        if self.policy != NamingPolicy::Supersede {
+            return Ok(path);
+        }
+
+        let dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        let has_body = status_of(&path).is_some_and(carries_body);
+        let older = {
+            let mut existing = self.existing.lock().unwrap();
+            let files = existing.entry((dir, feed_id)).or_default();
+            let (kept, older) = std::mem::take(files)
+                .into_iter()
+                .filter(|old| *old != path)
+                .partition(|old| !has_body && status_of(old).is_some_and(carries_body));
+            *files = kept;
+            files.push(path.clone());
+            older
+        };
+        for old in older.iter() {
+            match fs::remove_file(old) {
+                //The parser got to it first
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                other => other?,
+            }
+        }
+        Ok(path)
+    }
+}
+
+/// Temp files older than this are assumed to be left over from a run that
+/// died, even if their process id is still in use.
+pub const STALE_TEMP_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// The process id in a temp file name.
+fn temp_file_pid(name: &str) -> Option<u32> {
+    let mut parts = name.strip_suffix(TEMP_SUFFIX)?.rsplitn(3, '.');
+    let _sequence = parts.next()?;
+    parts.next()?.parse().ok()
+}
+
+/// Remove temp files left behind in `dir` by a run that died mid-write: ones
+/// with this process's id (so left by an earlier process that had it), or
+/// untouched for longer than `stale_after`. Temp files another poller is still
+/// writing are left alone. Returns how many were removed.
+pub fn clean_temp_files(dir: &Path, stale_after: Duration) -> io::Result<usize> {
+    let mut removed = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if !name.starts_with('.') || !name.ends_with(TEMP_SUFFIX) || !entry.file_type()?.is_file() {
+            continue;
+        }
+        let ours = temp_file_pid(&name) == Some(std::process::id());
+        let stale = entry.metadata()?.modified()?.elapsed().is_ok_and(|age| age > stale_after);
+        if ours || stale {
+            match fs::remove_file(entry.path()) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                other => other?,
+            }
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(namer: &FeedFileNamer, dir: &Path, feed_id: u64, status: u16, body: &str) -> PathBuf {
+        let mut pending = namer.create(dir, feed_id, status, false).unwrap();
+        pending.write_all(body.as_bytes()).unwrap();
+        namer.commit(feed_id, pending).unwrap()
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("aggrivator-feedfile-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn file_names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn invisible_until_committed() {
+        let dir = temp_dir("commit");
+        let mut pending = PendingFeedFile::create(&dir, "1_200.txt", true).unwrap();
+        pending.write_all(b"0\n[[NO_ETAG]]\n").unwrap();
+        assert!(!dir.join("1_200.txt").exists());
+
+        pending.write_all(b"<rss/>").unwrap();
+        let path = pending.commit().unwrap();
+        assert_eq!(path, dir.join("1_200.txt"));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "0\n[[NO_ETAG]]\n<rss/>");
+        assert_eq!(file_names(&dir), vec!["1_200.txt"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn dropping_uncommitted_leaves_nothing() {
+        let dir = temp_dir("drop");
+        fs::write(dir.join("1_200.txt"), "previous").unwrap();
+        let mut pending = PendingFeedFile::create(&dir, "1_200.txt", false).unwrap();
+        pending.write_all(b"partial").unwrap();
+        drop(pending);
+
+        assert_eq!(file_names(&dir), vec!["1_200.txt"]);
+        assert_eq!(fs::read_to_string(dir.join("1_200.txt")).unwrap(), "previous");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn cleans_only_stale_temp_files() {
+        let dir = temp_dir("clean");
+        let ours = format!(".2_200.txt.{}.0.tmp", std::process::id());
+        fs::write(dir.join("1_200.txt"), "").unwrap();
+        fs::write(dir.join(&ours), "").unwrap();
+        fs::write(dir.join(".3_200.txt.1.0.tmp"), "").unwrap();
+        fs::write(dir.join(".4_200.txt.1.0.tmp"), "").unwrap();
+        fs::write(dir.join("notes.tmp"), "").unwrap();
+        let old = std::time::SystemTime::now() - Duration::from_secs(7200);
+        File::options().write(true).open(dir.join(".4_200.txt.1.0.tmp")).unwrap().set_modified(old).unwrap();
+
+        assert_eq!(clean_temp_files(&dir, STALE_TEMP_AGE).unwrap(), 2);
+        assert_eq!(file_names(&dir), vec![".3_200.txt.1.0.tmp", "1_200.txt", "notes.tmp"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reads_feed_id_from_any_naming_scheme() {
+        assert_eq!(feed_id_of("123_200.txt"), Some(123));
+        assert_eq!(feed_id_of("123_200_1800000000_000004.txt"), Some(123));
+        assert_eq!(feed_id_of(".123_200.txt.5.0.tmp"), None);
+        assert_eq!(feed_id_of("notes.txt"), None);
+        assert_eq!(feed_id_of("123_200.json"), None);
+        assert_eq!(feed_id_with_extension("123_200.json", "json"), Some(123));
+    }
+
+    #[test]
+    fn replace_overwrites_same_status() {
+        let dir = temp_dir("replace");
+        let namer = FeedFileNamer::new(NamingPolicy::Replace, 1_800_000_000);
+        write(&namer, &dir, 7, 200, "first");
+        write(&namer, &dir, 7, 200, "second");
+        write(&namer, &dir, 7, 304, "");
+        assert_eq!(file_names(&dir), vec!["7_200.txt", "7_304.txt"]);
+        assert_eq!(fs::read_to_string(dir.join("7_200.txt")).unwrap(), "second");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn queue_keeps_every_result_in_order() {
+        let dir = temp_dir("queue");
+        let namer = FeedFileNamer::new(NamingPolicy::Queue, 1_800_000_000);
+        write(&namer, &dir, 7, 200, "first");
+        //Reporting where a result would go doesn't use up a name
+        assert_eq!(namer.preview_name(7, 304), "7_304_1800000000_000001.txt");
+        assert_eq!(namer.preview_name(7, 304), "7_304_1800000000_000001.txt");
+        write(&namer, &dir, 7, 200, "second");
+        assert_eq!(
+            file_names(&dir),
+            vec!["7_200_1800000000_000000.txt", "7_200_1800000000_000001.txt"]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn supersede_removes_older_results_for_the_feed() {
+        let dir = temp_dir("supersede");
+        fs::write(dir.join("7_304.txt"), "from an earlier run").unwrap();
+        fs::write(dir.join("8_200.txt"), "another feed").unwrap();
+        let namer = FeedFileNamer::new(NamingPolicy::Supersede, 1_800_000_000);
+        assert_eq!(namer.index_dir(&dir).unwrap(), 2);
+
+        write(&namer, &dir, 7, 200, "first");
+        let latest = write(&namer, &dir, 7, 200, "second");
+        assert_eq!(file_names(&dir), vec!["7_200_1800000000_000001.txt", "8_200.txt"]);
+        assert_eq!(fs::read_to_string(latest).unwrap(), "second");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn supersede_keeps_bodies_a_bodyless_result_follows() {
+        let dir = temp_dir("supersede-body");
+        let namer = FeedFileNamer::new(NamingPolicy::Supersede, 1_800_000_000);
+        write(&namer, &dir, 7, 200, "<rss/>");
+        write(&namer, &dir, 7, 304, "");
+        write(&namer, &dir, 7, 500, "");
+        assert_eq!(
+            file_names(&dir),
+            vec!["7_200_1800000000_000000.txt", "7_500_1800000000_000002.txt"]
+        );
+
+        write(&namer, &dir, 7, 203, "<rss/>");
+        assert_eq!(file_names(&dir), vec!["7_203_1800000000_000003.txt"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
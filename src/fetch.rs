@@ -0,0 +1,382 @@
+//! The shared HTTP fetch path: one `reqwest::Client` per run, with redirects
+//! followed (and recorded) per request instead of by a per-client policy.
+
+use std::error::Error;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use reqwest::header::{self, HeaderMap, HeaderValue};
+use reqwest::{redirect, Client, Response, Url};
+use tracing::{field, info_span, Instrument};
+
+use crate::error::FetchError;
+use crate::otel;
+use crate::signing::WebBotAuthSigner;
+use crate::timing::{self, ConnectTiming, RequestPhases, TimingResolver, TimingSessions};
+
+/// Maximum number of redirects followed for a single feed request.
+pub const MAX_REDIRECTS: usize = 10;
+
+/// Feed formats we advertise. Some origins/WAFs reject requests that send no
+/// Accept header (reqwest sends none by default) with a 415; a real browser
+/// always sends one. This won't defeat IP-based bot challenges, but it fixes
+/// feeds whose front-end requires a sane Accept.
+pub const ACCEPT: &str =
+    "application/rss+xml, application/atom+xml, application/xml;q=0.9, text/xml;q=0.9, */*;q=0.8";
+
+/// One redirect response seen while following a feed URL.
+#[derive(Debug, Clone)]
+pub struct RedirectHop {
+    /// HTTP status of the redirect response (301, 302, 303, 307 or 308).
+    pub status: u16,
+    /// The absolute URL the redirect pointed to.
+    pub location: Url,
+}
+
+impl RedirectHop {
+    /// Whether the redirect says the resource has moved for good.
+    pub fn is_permanent(&self) -> bool {
+        self.status == 301 || self.status == 308
+    }
+}
+
+/// The hop giving a feed's new permanent home: the last one in the unbroken
+/// run of permanent redirects from the origin. A temporary redirect ends the
+/// run, since everything after it may change back at any time.
+pub fn permanent_destination(hops: &[RedirectHop]) -> Option<&RedirectHop> {
+    hops.iter().take_while(|hop| hop.is_permanent()).last()
+}
+
+/// Most of a redirect's body we'll read so its connection can be reused. A
+/// bigger one is dropped, closing the connection instead.
+pub const MAX_DRAINED_BODY: u64 = 64 * 1024;
+
+/// Default limit on establishing a connection.
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(20);
+/// Default limit on a whole request, body included.
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Build the client shared by every feed request in a run. The client never
+/// follows redirects itself (see `send_following_redirects`), so a single
+/// instance can serve all feeds and keep its connection pool and TLS sessions
+/// warm across feeds on the same host. Its resolver, connector and TLS
+/// session cache are hooked up to `timing`, so each request's phases can be
+/// timed. Proxies are taken from the usual environment variables.
+pub fn build_client(user_agent: &str) -> Result<Client, Box<dyn Error>> {
+    build_client_with_timeouts(user_agent, CONNECT_TIMEOUT, REQUEST_TIMEOUT)
+}
+
+/// `build_client` with non-default timeouts.
+pub fn build_client_with_timeouts(
+    user_agent: &str,
+    connect_timeout: Duration,
+    request_timeout: Duration,
+) -> Result<Client, Box<dyn Error>> {
+    let mut headers = HeaderMap::new();
+    headers.insert(header::USER_AGENT, HeaderValue::from_str(user_agent)?);
+    headers.insert(header::ACCEPT, HeaderValue::from_static(ACCEPT));
+
+    let client = Client::builder()
+        .use_rustls_tls()
+        .connect_timeout(connect_timeout)
+        .timeout(request_timeout)
+        .pool_idle_timeout(Duration::from_secs(20))
+        .default_headers(headers)
+        .gzip(true)
+        .redirect(redirect::Policy::none())
+        .dns_resolver(Arc::new(TimingResolver))
+        .connector_layer(ConnectTiming)
+        .use_preconfigured_tls(tls_config()?)
+        .build()?;
+    Ok(client)
+}
+
+/// What reqwest would set up for rustls by itself, except that sessions are
+/// cached in `TimingSessions` so the handshake can be timed.
+fn tls_config() -> Result<rustls::ClientConfig, rustls::Error> {
+    let roots = rustls::RootCertStore { roots: webpki_roots::TLS_SERVER_ROOTS.to_vec() };
+    let mut config = rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    config.resumption = rustls::client::Resumption::store(TimingSessions::new(256));
+    Ok(config)
+}
+
+/// If `res` is a redirect we should follow, the absolute URL it points to.
+fn redirect_target(res: &Response) -> Option<Url> {
+    match res.status().as_u16() {
+        301 | 302 | 303 | 307 | 308 => {}
+        _ => return None,
+    }
+    let location = res.headers().get(header::LOCATION)?.to_str().ok()?;
+    res.url().join(location).ok()
+}
+
+/// Read what's left of a redirect's body, so its connection goes back to the
+/// pool for the next hop instead of being closed.
+async fn drain(mut res: Response) {
+    if res.content_length().is_some_and(|length| length > MAX_DRAINED_BODY) {
+        return;
+    }
+    let mut drained = 0;
+    while let Ok(Some(chunk)) = res.chunk().await {
+        drained += chunk.len() as u64;
+        if drained > MAX_DRAINED_BODY {
+            return;
+        }
+    }
+}
+
+/// GET `url`, following up to `MAX_REDIRECTS` redirects by hand. `headers` are
+/// sent on every hop (they override the client defaults), and each hop is
+/// signed for its own `@authority` when a signer is configured. Every redirect
+/// is appended to `hops` as it is seen, so the caller still has the chain when
+/// a later hop fails. `timeout` bounds the whole chain, not each hop. The
+/// phases of the last request made (see `timing`) are left in `phases`, also
+/// when it fails. Each hop is made in a `hop` span, with its phases exported
+/// under it when it's traced. Failures come back classified (see `FetchError`).
+pub async fn send_following_redirects(
+    client: &Client,
+    url: &str,
+    headers: &HeaderMap,
+    signer: Option<&WebBotAuthSigner>,
+    timeout: Duration,
+    hops: &mut Vec<RedirectHop>,
+    phases: &mut RequestPhases,
+) -> Result<Response, FetchError> {
+    let deadline = Instant::now() + timeout;
+    let mut current = Url::parse(url).map_err(|e| FetchError::InvalidUrl(format!("{}: {}", e, url)))?;
+
+    loop {
+        let mut req = client
+            .get(current.clone())
+            .headers(headers.clone())
+            .timeout(deadline.saturating_duration_since(Instant::now()));
+
+        //Attach Web Bot Auth signature headers per-request (the signature binds the
+        //target @authority and a created/expires window). On any error we simply
+        //send the request unsigned.
+        if let Some(signer) = signer {
+            if let Ok(now) = SystemTime::now().duration_since(UNIX_EPOCH) {
+                for (name, value) in signer.sign(&current, now.as_secs()) {
+                    req = req.header(name, value);
+                }
+            }
+        }
+
+        let hop = info_span!("hop", url = %current, status = field::Empty, error = field::Empty);
+        let (res, timed) = timing::timed(current.scheme() == "https", req.send()).instrument(hop.clone()).await;
+        *phases = timed;
+        //The lookup only had the host name to go on
+        if let (Some(addr), Some(port)) = (phases.remote_addr.as_mut(), current.port_or_known_default()) {
+            addr.set_port(port);
+        }
+        otel::export_phases(&hop, phases);
+        let connected = phases.request.is_some();
+        let res = res.map_err(|e| match FetchError::from_reqwest(&e, false) {
+            //The deadline can run out while the connection is still being made
+            FetchError::ReadTimeout(message) if !connected => FetchError::ConnectTimeout(message),
+            e => e,
+        });
+        let res = res.inspect_err(|e| {
+            hop.record("error", e.reason());
+            phases.ttfb = None;
+        })?;
+        hop.record("status", res.status().as_u16());
+        phases.remote_addr = res.remote_addr().or(phases.remote_addr);
+        let next = match redirect_target(&res) {
+            Some(next) => next,
+            None => return Ok(res),
+        };
+
+        if hops.len() >= MAX_REDIRECTS {
+            return Err(FetchError::TooManyRedirects(hops.len()));
+        }
+        hops.push(RedirectHop {
+            status: res.status().as_u16(),
+            location: next.clone(),
+        });
+        drain(res).instrument(hop).await;
+        current = next;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Serve canned responses on a local port: `route` maps a request path to
+    /// the raw status line and extra headers to send back.
+    async fn serve(route: fn(&str) -> String) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut sock, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut buf = vec![0u8; 4096];
+                    let n = sock.read(&mut buf).await.unwrap_or(0);
+                    let req = String::from_utf8_lossy(&buf[..n]).to_string();
+                    let path = req.split_whitespace().nth(1).unwrap_or("/").to_string();
+                    let head = route(&path);
+                    let resp = format!("{}\r\ncontent-length: 2\r\nconnection: close\r\n\r\nok", head);
+                    let _ = sock.write_all(resp.as_bytes()).await;
+                });
+            }
+        });
+        format!("http://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn follows_and_records_redirect_chain() {
+        let base = serve(|path| match path {
+            "/a" => "HTTP/1.1 301 Moved Permanently\r\nlocation: /b".to_string(),
+            "/b" => "HTTP/1.1 302 Found\r\nlocation: /c".to_string(),
+            _ => "HTTP/1.1 200 OK".to_string(),
+        })
+        .await;
+        let client = build_client("test").unwrap();
+        let mut hops = Vec::new();
+        let res = send_following_redirects(
+            &client,
+            &format!("{}/a", base),
+            &HeaderMap::new(),
+            None,
+            Duration::from_secs(5),
+            &mut hops,
+            &mut RequestPhases::default(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(res.status().as_u16(), 200);
+        assert_eq!(res.url().path(), "/c");
+        let chain: Vec<(u16, &str)> = hops.iter().map(|h| (h.status, h.location.path())).collect();
+        assert_eq!(chain, vec![(301, "/b"), (302, "/c")]);
+    }
+
+    #[tokio::test]
+    async fn reuses_the_connection_across_redirects() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let connections = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let accepted = connections.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut sock, _) = listener.accept().await.unwrap();
+                accepted.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let mut buf = vec![0u8; 4096];
+                    while let Ok(n) = sock.read(&mut buf).await {
+                        if n == 0 {
+                            break;
+                        }
+                        let req = String::from_utf8_lossy(&buf[..n]).to_string();
+                        let head = match req.split_whitespace().nth(1) {
+                            Some("/a") => "HTTP/1.1 301 Moved Permanently\r\nlocation: /b",
+                            Some("/b") => "HTTP/1.1 302 Found\r\nlocation: /c",
+                            _ => "HTTP/1.1 200 OK",
+                        };
+                        //Big enough that it isn't all read along with the headers
+                        let body = "moved, see the location header\n".repeat(1000);
+                        let resp = format!("{}\r\ncontent-length: {}\r\n\r\n{}", head, body.len(), body);
+                        let _ = sock.write_all(resp.as_bytes()).await;
+                    }
+                });
+            }
+        });
+
+        let client = build_client("test").unwrap();
+        let mut hops = Vec::new();
+        let res = send_following_redirects(
+            &client,
+            &format!("{}/a", base),
+            &HeaderMap::new(),
+            None,
+            Duration::from_secs(5),
+            &mut hops,
+            &mut RequestPhases::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(res.url().path(), "/c");
+        assert_eq!(connections.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    fn hop(status: u16, location: &str) -> RedirectHop {
+        RedirectHop {
+            status,
+            location: Url::parse(location).unwrap(),
+        }
+    }
+
+    #[test]
+    fn permanent_destination_stops_at_first_temporary_hop() {
+        let chain = vec![
+            hop(301, "https://b.example/feed"),
+            hop(308, "https://c.example/feed"),
+            hop(302, "https://cdn.example/feed?token=1"),
+            hop(301, "https://d.example/feed"),
+        ];
+        assert_eq!(permanent_destination(&chain).unwrap().location.as_str(), "https://c.example/feed");
+
+        let temporary_first = vec![hop(307, "https://b.example/feed"), hop(301, "https://c.example/feed")];
+        assert!(permanent_destination(&temporary_first).is_none());
+        assert!(permanent_destination(&[]).is_none());
+    }
+
+    #[tokio::test]
+    async fn deadline_before_the_handshake_is_a_connect_timeout() {
+        //Takes the connection but never answers, so an https handshake never finishes
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut held = Vec::new();
+            while let Ok((sock, _)) = listener.accept().await {
+                held.push(sock);
+            }
+        });
+        let client = build_client_with_timeouts("test", Duration::from_secs(20), Duration::from_secs(20)).unwrap();
+        let fetch = |scheme: &'static str| {
+            let client = client.clone();
+            async move {
+                let mut phases = RequestPhases::default();
+                let url = format!("{}://{}/feed", scheme, addr);
+                let res = send_following_redirects(&client, &url, &HeaderMap::new(), None, Duration::from_millis(500), &mut Vec::new(), &mut phases).await;
+                (res.unwrap_err(), phases)
+            }
+        };
+
+        let (err, phases) = fetch("https").await;
+        assert_eq!(err.status_code(), crate::error::ERRORCODE_CONNECT_TIMEOUT);
+        assert!(phases.tls.is_some());
+        //Connected over plain http, it's waiting on the response
+        let (err, _) = fetch("http").await;
+        assert_eq!(err.status_code(), crate::error::ERRORCODE_READ_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_redirects() {
+        let base = serve(|_| "HTTP/1.1 307 Temporary Redirect\r\nlocation: /loop".to_string()).await;
+        let client = build_client("test").unwrap();
+        let mut hops = Vec::new();
+        let err = send_following_redirects(
+            &client,
+            &format!("{}/start", base),
+            &HeaderMap::new(),
+            None,
+            Duration::from_secs(5),
+            &mut hops,
+            &mut RequestPhases::default(),
+        )
+        .await
+        .unwrap_err();
+
+        assert_eq!(err.reason(), "too_many_redirects");
+        assert_eq!(hops.len(), MAX_REDIRECTS);
+    }
+}
@@ -0,0 +1,401 @@
+//! Per-host politeness for the poller: caps in-flight requests per hostname
+//! (and optionally per resolved IP), spaces out request starts to the same
+//! host, and queues feeds per host so one big or slow platform can't fill
+//! every slot, while the run's slots still go out in queue order.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use reqwest::Url;
+use tokio::sync::{oneshot, OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+
+/// Politeness limits applied to every feed request.
+#[derive(Debug, Clone)]
+pub struct PolitenessConfig {
+    /// Maximum requests in flight to one hostname.
+    pub per_host_limit: usize,
+    /// Maximum requests in flight to one resolved IP address, if set. Catches
+    /// many vanity hostnames that all point at the same hosting platform.
+    pub per_ip_limit: Option<usize>,
+    /// Minimum gap between the starts of two requests to the same hostname.
+    pub min_host_delay: Duration,
+}
+
+impl Default for PolitenessConfig {
+    fn default() -> Self {
+        Self {
+            per_host_limit: 4,
+            per_ip_limit: None,
+            min_host_delay: Duration::from_millis(250),
+        }
+    }
+}
+
+/// The scheduling key for a feed URL: its lowercased hostname, or the raw
+/// string when it can't be parsed (so bad URLs still get a slot of their own).
+pub fn host_key(url: &str) -> String {
+    match Url::parse(url) {
+        Ok(parsed) => parsed.host_str().unwrap_or("").to_lowercase(),
+        Err(_) => url.to_string(),
+    }
+}
+
+/// Split `items` into one queue per host, in the order each host first
+/// appears, keeping each host's items in their original relative order.
+pub fn group_by_host<T, F>(items: Vec<T>, host_of: F) -> Vec<VecDeque<T>>
+where
+    F: Fn(&T) -> String,
+{
+    let mut order: HashMap<String, usize> = HashMap::new();
+    let mut groups: Vec<VecDeque<T>> = Vec::new();
+    for item in items {
+        let next = groups.len();
+        let index = *order.entry(host_of(&item)).or_insert(next);
+        if index == next {
+            groups.push(VecDeque::new());
+        }
+        groups[index].push_back(item);
+    }
+    groups
+}
+
+struct HostSlot {
+    permits: Arc<Semaphore>,
+    next_start: Mutex<Instant>,
+}
+
+/// Held for the duration of one feed request; dropping it frees the slot.
+pub struct HostPermit {
+    _host: OwnedSemaphorePermit,
+    _ip: Option<OwnedSemaphorePermit>,
+}
+
+/// Hands out per-host (and per-IP) request slots for a run.
+pub struct HostLimiter {
+    config: PolitenessConfig,
+    hosts: Mutex<HashMap<String, Arc<HostSlot>>>,
+    ips: Mutex<HashMap<IpAddr, Arc<Semaphore>>>,
+    resolved: Mutex<HashMap<String, Option<IpAddr>>>,
+}
+
+impl HostLimiter {
+    pub fn new(config: PolitenessConfig) -> Self {
+        Self {
+            config,
+            hosts: Mutex::new(HashMap::new()),
+            ips: Mutex::new(HashMap::new()),
+            resolved: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Most requests one host may have in flight.
+    pub fn per_host_limit(&self) -> usize {
+        self.config.per_host_limit.max(1)
+    }
+
+    fn slot(&self, host: &str) -> Arc<HostSlot> {
+        let mut hosts = self.hosts.lock().unwrap();
+        hosts
+            .entry(host.to_string())
+            .or_insert_with(|| {
+                Arc::new(HostSlot {
+                    permits: Arc::new(Semaphore::new(self.per_host_limit())),
+                    next_start: Mutex::new(Instant::now()),
+                })
+            })
+            .clone()
+    }
+
+    /// The first address `host` resolves to, looked up once per run.
+    async fn resolve(&self, host: &str, port: u16) -> Option<IpAddr> {
+        if let Some(ip) = self.resolved.lock().unwrap().get(host) {
+            return *ip;
+        }
+        let ip = match tokio::net::lookup_host((host, port)).await {
+            Ok(mut addrs) => addrs.next().map(|addr| addr.ip()),
+            Err(_) => None,
+        };
+        self.resolved.lock().unwrap().insert(host.to_string(), ip);
+        ip
+    }
+
+    /// Wait for a free slot on the host (and IP) serving `url`, then for the
+    /// host's minimum delay since the previous request start.
+    pub async fn acquire(&self, url: &str) -> HostPermit {
+        let host = host_key(url);
+        let slot = self.slot(&host);
+        let host_permit = slot
+            .permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("host semaphore is never closed");
+
+        let mut ip_permit = None;
+        if let Some(limit) = self.config.per_ip_limit {
+            let port = Url::parse(url)
+                .ok()
+                .and_then(|parsed| parsed.port_or_known_default())
+                .unwrap_or(443);
+            if let Some(ip) = self.resolve(&host, port).await {
+                let permits = self
+                    .ips
+                    .lock()
+                    .unwrap()
+                    .entry(ip)
+                    .or_insert_with(|| Arc::new(Semaphore::new(limit.max(1))))
+                    .clone();
+                ip_permit = Some(
+                    permits
+                        .acquire_owned()
+                        .await
+                        .expect("ip semaphore is never closed"),
+                );
+            }
+        }
+
+        //Reserve our start time under the lock, then sleep outside it
+        let start = {
+            let mut next_start = slot.next_start.lock().unwrap();
+            let start = (*next_start).max(Instant::now());
+            *next_start = start + self.config.min_host_delay;
+            start
+        };
+        tokio::time::sleep_until(start).await;
+
+        HostPermit {
+            _host: host_permit,
+            _ip: ip_permit,
+        }
+    }
+}
+
+/// The run's slots, shared by every host's queue. One that comes free goes to
+/// the waiting feed furthest up the queue (the lowest `rank`), not to
+/// whichever asked first, so the queue's order holds across hosts.
+pub struct RunSlots {
+    state: Mutex<RunSlotState>,
+}
+
+struct RunSlotState {
+    free: usize,
+    asked: u64,
+    waiting: BTreeMap<(usize, u64), oneshot::Sender<()>>,
+}
+
+/// One of the run's slots; dropping it hands it on.
+pub struct RunSlot<'a> {
+    slots: &'a RunSlots,
+}
+
+/// A feed waiting for a slot. If it's dropped before taking the slot it was
+/// given, the slot is handed on.
+struct Waiting<'a> {
+    slots: &'a RunSlots,
+    key: (usize, u64),
+    given: oneshot::Receiver<()>,
+    taken: bool,
+}
+
+impl RunSlots {
+    pub fn new(slots: usize) -> Self {
+        Self { state: Mutex::new(RunSlotState { free: slots, asked: 0, waiting: BTreeMap::new() }) }
+    }
+
+    /// Wait for a slot for the feed at `rank` in the queue.
+    pub async fn acquire(&self, rank: usize) -> RunSlot<'_> {
+        let mut waiting = {
+            let mut state = self.state.lock().unwrap();
+            if state.free > 0 {
+                state.free -= 1;
+                return RunSlot { slots: self };
+            }
+            let (sender, given) = oneshot::channel();
+            let key = (rank, state.asked);
+            state.asked += 1;
+            state.waiting.insert(key, sender);
+            Waiting { slots: self, key, given, taken: false }
+        };
+        (&mut waiting.given).await.expect("waiting feeds are only dropped by their own wait");
+        waiting.taken = true;
+        RunSlot { slots: self }
+    }
+
+    fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+        while let Some((_, sender)) = state.waiting.pop_first() {
+            if sender.send(()).is_ok() {
+                return;
+            }
+        }
+        state.free += 1;
+    }
+}
+
+impl Drop for RunSlot<'_> {
+    fn drop(&mut self) {
+        self.slots.release();
+    }
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        if self.taken {
+            return;
+        }
+        let mut state = self.slots.state.lock().unwrap();
+        //Not in the queue any more means a slot was already given to us
+        if state.waiting.remove(&self.key).is_none() && self.given.try_recv().is_ok() {
+            drop(state);
+            self.slots.release();
+        }
+    }
+}
+
+/// The slots a feed check holds while it has a request out: one on its host
+/// (from a `HostLimiter`) and one of the run's. A check waiting to retry gives
+/// both back, so a host that is throttling us doesn't keep other hosts' feeds
+/// waiting.
+pub struct CheckSlots<'a> {
+    limiter: &'a HostLimiter,
+    run: &'a RunSlots,
+    url: &'a str,
+    rank: usize,
+    held: Option<(HostPermit, RunSlot<'a>)>,
+}
+
+impl<'a> CheckSlots<'a> {
+    /// Slots for checking `url`, the feed at `rank` in the queue, not yet
+    /// taken.
+    pub fn new(limiter: &'a HostLimiter, run: &'a RunSlots, url: &'a str, rank: usize) -> Self {
+        Self { limiter, run, url, rank, held: None }
+    }
+
+    /// Wait for the host's slot and then the run's, unless they're held
+    /// already.
+    pub async fn take(&mut self) {
+        if self.held.is_none() {
+            let permit = self.limiter.acquire(self.url).await;
+            let slot = self.run.acquire(self.rank).await;
+            self.held = Some((permit, slot));
+        }
+    }
+
+    /// Give both slots back until the next `take`.
+    pub fn give_back(&mut self) {
+        self.held = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn host_key_lowercases_and_falls_back() {
+        assert_eq!(host_key("https://Anchor.FM/s/1/podcast/rss"), "anchor.fm");
+        assert_eq!(host_key("not a url"), "not a url");
+    }
+
+    #[test]
+    fn groups_keep_first_seen_order() {
+        let urls = vec!["b/1", "a/1", "b/2", "c/1", "a/2"];
+        let groups = group_by_host(urls, |u| u.split('/').next().unwrap().to_string());
+        assert_eq!(groups, vec![vec!["b/1", "b/2"], vec!["a/1", "a/2"], vec!["c/1"]]);
+    }
+
+    #[tokio::test]
+    async fn caps_in_flight_requests_per_host() {
+        let limiter = HostLimiter::new(PolitenessConfig {
+            per_host_limit: 1,
+            per_ip_limit: None,
+            min_host_delay: Duration::from_millis(0),
+        });
+        let first = limiter.acquire("https://example.com/a").await;
+        let blocked = tokio::time::timeout(
+            Duration::from_millis(50),
+            limiter.acquire("https://example.com/b"),
+        )
+        .await;
+        assert!(blocked.is_err());
+
+        //Other hosts are unaffected
+        let _other = limiter.acquire("https://example.org/a").await;
+
+        drop(first);
+        let _second = limiter.acquire("https://example.com/b").await;
+    }
+
+    #[tokio::test]
+    async fn run_slots_go_out_in_queue_order() {
+        let run = RunSlots::new(1);
+        let order = Mutex::new(Vec::new());
+        let first = run.acquire(0).await;
+
+        //Asked for out of order, as when each host's queue starts its own feeds
+        let feed = |rank: usize| {
+            let (run, order) = (&run, &order);
+            async move {
+                let _slot = run.acquire(rank).await;
+                order.lock().unwrap().push(rank);
+                tokio::task::yield_now().await;
+            }
+        };
+        //One gives up waiting, and the slot passes over it
+        let gave_up = tokio::time::timeout(Duration::from_millis(10), run.acquire(1));
+        let release = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            drop(first);
+        };
+        let (gave_up, ..) = tokio::join!(gave_up, feed(7), feed(3), feed(5), feed(2), release);
+        assert!(gave_up.is_err());
+        assert_eq!(*order.lock().unwrap(), vec![2, 3, 5, 7]);
+
+        //Every slot came back
+        let _a = run.acquire(0).await;
+        assert!(tokio::time::timeout(Duration::from_millis(10), run.acquire(0)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn a_check_waiting_to_retry_doesnt_hold_up_other_hosts() {
+        let limiter = HostLimiter::new(PolitenessConfig {
+            per_host_limit: 1,
+            per_ip_limit: None,
+            min_host_delay: Duration::from_millis(0),
+        });
+        let run = RunSlots::new(1);
+        let mut throttled = CheckSlots::new(&limiter, &run, "https://throttled.example.com/feed", 0);
+        throttled.take().await;
+
+        //While it backs off, another host's feed gets the run's only slot
+        let backing_off = async {
+            throttled.give_back();
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            throttled.take().await;
+        };
+        let other = async {
+            let mut other = CheckSlots::new(&limiter, &run, "https://example.org/feed", 1);
+            tokio::time::timeout(Duration::from_millis(100), other.take()).await.is_ok()
+        };
+        let ((), got_in) = tokio::join!(backing_off, other);
+        assert!(got_in);
+        assert!(throttled.held.is_some());
+    }
+
+    #[tokio::test]
+    async fn spaces_out_requests_to_the_same_host() {
+        let limiter = HostLimiter::new(PolitenessConfig {
+            per_host_limit: 4,
+            per_ip_limit: None,
+            min_host_delay: Duration::from_millis(100),
+        });
+        let started = Instant::now();
+        let _a = limiter.acquire("https://example.com/a").await;
+        let _b = limiter.acquire("https://example.com/b").await;
+        let _c = limiter.acquire("https://example.com/c").await;
+        assert!(started.elapsed() >= Duration::from_millis(200));
+    }
+}
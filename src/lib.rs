@@ -1 +1,18 @@
+pub mod breaker;
+pub mod config;
+pub mod daemon;
+pub mod encoding;
+pub mod error;
+pub mod feedfile;
+pub mod fetch;
+pub mod hosts;
+pub mod metrics;
+pub mod otel;
+pub mod queue;
+pub mod relocation;
+pub mod retry;
+pub mod schedule;
 pub mod signing;
+pub mod sink;
+pub mod summary;
+pub mod timing;
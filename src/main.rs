@@ -4,42 +4,224 @@ extern crate chrono;
 extern crate reqwest;
 
 use std::error::Error;
+use std::collections::BTreeMap;
 use std::fmt;
-use std::fs::File;
-//use std::fs::create_dir;
-use std::io::Write;
-use std::time::{SystemTime, UNIX_EPOCH, Duration};
-use rusqlite::{Connection};
-use reqwest::{header, redirect};
+use std::fs::create_dir_all;
+use std::io::{IsTerminal, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH, Duration, Instant};
+use reqwest::header;
 use futures::StreamExt;
-use httpdate;
-use std::sync::Arc;
-use aggrivator::signing::WebBotAuthSigner;
+use clap::{Parser, Subcommand};
+use sha2::{Digest, Sha256};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{debug, error, info, info_span, warn, Instrument, Span};
+use tracing_subscriber::layer::{Layer, SubscriberExt};
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::EnvFilter;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use aggrivator::breaker::{is_host_failure, Admission, BreakerConfig, CircuitBreaker};
+use aggrivator::config::Config;
+use aggrivator::daemon::{self, Daemon, SignalState};
+use aggrivator::encoding;
+use aggrivator::error::FetchError;
+use aggrivator::feedfile::{clean_temp_files, FeedFileNamer, NamingPolicy, STALE_TEMP_AGE};
+use aggrivator::fetch::{build_client, build_client_with_timeouts, permanent_destination, send_following_redirects, RedirectHop};
+use aggrivator::hosts::{group_by_host, host_key, CheckSlots, HostLimiter, PolitenessConfig, RunSlots};
+use aggrivator::metrics::{spawn_server as spawn_metrics_server, Metrics};
+use aggrivator::otel::{self, ExportConfig};
+use aggrivator::relocation::{FeedMove, MoveScanner, CONTENT_MOVE_STATUS};
+use aggrivator::retry::{self, RetryPolicy};
+use aggrivator::schedule::{self, CheckOutcome, ScheduleConfig, ScheduleHints};
+use aggrivator::queue::{find_feed, open_queue_db, read_go_flag, select_feeds, Podcast, QueueSelection, QueueUpdate, QueueWriter};
+use aggrivator::signing::{generate_key_pem, WebBotAuthSigner};
+use aggrivator::sink::{self, FeedResult, FileSink, JsonDirSink, JsonLinesSink, PendingResult, ResultSink, SinkKind, SqliteSink};
+use aggrivator::summary::{FeedRecord, RunSummary, SummaryReport};
+use aggrivator::timing::{RequestPhases, Timings};
 
 
 
 //##: Global definitions
 const USERAGENT: &str = concat!("Aggrivator (PodcastIndex.org)/v", env!("CARGO_PKG_VERSION"));
-//70 megabytes
-const MAX_BODY_LENGTH: usize = 73400320; 
-//static DIR_FEED_FILES: &str = "feeds";
-//static DIR_REDIRECT_FILES: &str = "redirects";
-const ERRORCODE_GENERAL_CONNECTION_FAILURE: u16 = 666;
-const ERRORCODE_GENERAL_DOWNLOAD_FAILURE: u16 = 667;
-const ERRORCODE_GENERAL_FILE_SIZE_EXCEEDED: u16 = 668;
+//Body text is handed to the sink in batches of about this much
+const WRITE_BATCH: usize = 64 * 1024;
 
 
-struct Podcast {
-    id: u64,
-    url: String,
-    title: String,
-    last_modified: u64,
-    etag: String,
-}
-
 #[derive(Debug)]
 struct HydraError(String);
 
+//Command line. Every setting lives in the config file and AGGRIVATOR_* env vars (see config.rs).
+#[derive(Parser)]
+#[command(name = "aggrivator", version, about = "The feed polling agent for Podcast Index.")]
+struct Cli {
+    /// Config file to load [default: aggrivator.toml, if it exists]
+    #[arg(short, long, global = true, env = "AGGRIVATOR_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Make the requests, but write no feed files and nothing back to the queue. What would have
+    /// been written is tallied and printed at the end.
+    #[arg(long, global = true, env = "AGGRIVATOR_DRY_RUN")]
+    dry_run: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Poll the due feeds in the queue once, then exit (the default)
+    Run,
+    /// Keep polling the queue for due feeds until stopped
+    Daemon,
+    /// Check the given feeds, by queue id or url, one at a time and print a detailed report of
+    /// each. Nothing is written back to the queue.
+    Fetch {
+        #[arg(required = true, value_name = "ID|URL")]
+        feeds: Vec<String>,
+        /// Leave out the stored ETag/Last-Modified, so the server has to send the whole feed
+        #[arg(long)]
+        force: bool,
+        /// Write the feed files like a run would, instead of just reporting them
+        #[arg(long)]
+        write: bool,
+    },
+    /// Request a url exactly the way the poller would and print what came back. Writes nothing.
+    Probe { url: String },
+    /// Generate a Web Bot Auth signing key and print the JWKS to publish for it
+    Keygen {
+        #[arg(default_value = "signing-key.pem")]
+        out: PathBuf,
+    },
+    /// Print the JWKS for the configured signing key
+    Jwks,
+    /// Load and validate the configuration, then print it
+    CheckConfig,
+}
+
+//Everything a run is configured with. Daemon mode builds it again on SIGHUP.
+struct Settings {
+    config: Config,
+    signer: Option<Arc<WebBotAuthSigner>>,
+    politeness: PolitenessConfig,
+    retry_policy: RetryPolicy,
+    breaker_config: BreakerConfig,
+    schedule_config: Option<ScheduleConfig>,
+    selection: QueueSelection,
+    dry_run: bool,
+    //Made once at startup, so a daemon's counters carry on across reloads
+    metrics: Option<Arc<Metrics>>,
+    //Shared with the signal watcher, so a stop can cut a retry wait short
+    signals: Arc<SignalState>,
+}
+
+//What a daemon keeps from one pass over the queue to the next: the client and its connection
+//pool, the per-host limits and circuit breaker state, and the feed file namer
+struct Poller {
+    ctx: FetchContext,
+    limiter: HostLimiter,
+    breaker: CircuitBreaker,
+    cool_down: Duration,
+    concurrency: usize,
+}
+
+//Everything a feed check needs that is shared across the run
+struct FetchContext {
+    client: reqwest::Client,
+    signer: Option<Arc<WebBotAuthSigner>>,
+    max_body_length: usize,
+    //Only for showing results as feed files in reports; the file sink has its own copy
+    legacy_header: bool,
+    retry: RetryPolicy,
+    request_timeout: Duration,
+    output: Output,
+    //Filled in by each check when set. Only `fetch` sets it, and it checks one feed at a time.
+    report: Option<Mutex<FeedReport>>,
+    metrics: Option<Arc<Metrics>>,
+    signals: Arc<SignalState>,
+}
+
+//Where finished results go
+enum Output {
+    Sink(Arc<dyn ResultSink>),
+    //`fetch` without --write: results are reported with where the sink would put them, but never written
+    Report(Arc<dyn ResultSink>),
+    //--dry-run: results are counted, but never written. No sink is even opened.
+    Tally(DryRunTally),
+}
+
+//What a dry run would have written
+#[derive(Default)]
+struct DryRunTally {
+    //Files and bytes by status code
+    by_status: Mutex<BTreeMap<u16, (usize, usize)>>,
+    redirect_stubs: AtomicUsize,
+}
+
+//A result on its way out: begun in the sink, or only counted when nothing is being written.
+//Body text is batched up before it goes to the sink.
+struct FeedFile {
+    pending: Option<Box<dyn PendingResult>>,
+    result: FeedResult,
+    body_length: usize,
+    batch: Vec<u8>,
+}
+
+//What `fetch` prints about one check
+#[derive(Default)]
+struct FeedReport {
+    request_headers: Vec<(String, String)>,
+    attempts: u32,
+    hops: Vec<RedirectHop>,
+    status: Option<u16>,
+    final_url: String,
+    response_headers: Vec<(String, String)>,
+    response_time: Duration,
+    retry_wait: Duration,
+    timings: Timings,
+    files: Vec<ReportedFile>,
+}
+
+//A result a check wrote, or would have written
+struct ReportedFile {
+    //Where it went or would go. Unknown on a dry run.
+    location: Option<String>,
+    written: bool,
+    header: String,
+    body_length: usize,
+}
+
+//How much of a streamed body made it to disk
+enum StreamedBody {
+    Complete(Box<BodySummary>),
+    TooLarge(usize),
+}
+
+//What we found in a body that was stored in full
+struct BodySummary {
+    //Begun but not yet committed, until the Timing line is finished
+    file: Option<FeedFile>,
+    length: usize,
+    feed_move: Option<FeedMove>,
+    hints: ScheduleHints,
+    hash: String,
+}
+
+//Running counts for the end-of-run summary
+#[derive(Default)]
+struct RunTally {
+    queued: usize,
+    updated: AtomicUsize,
+    not_updated: AtomicUsize,
+    errors: AtomicUsize,
+    deferred: AtomicUsize,
+    skipped: AtomicUsize,
+    summary: RunSummary,
+    duration: Duration,
+}
+
 #[allow(dead_code)]
 struct PodcastCheckResult {
     id: u64,
@@ -49,6 +231,11 @@ struct PodcastCheckResult {
     last_modified_string: String,
     last_modified_timestamp: u64,
     etag: String,
+    permanent_url: Option<String>,
+    attempts: u32,
+    body_bytes: u64,
+    outcome: CheckOutcome,
+    hints: ScheduleHints,
 }
 
 //##: Implement
@@ -60,182 +247,1219 @@ impl fmt::Display for HydraError {
 
 impl Error for HydraError {}
 
+impl FeedFile {
+    //Add to the body, handing it to the sink a batch at a time
+    async fn write_body(&mut self, text: &[u8]) -> std::io::Result<()> {
+        self.body_length += text.len();
+        if self.pending.is_some() {
+            self.batch.extend_from_slice(text);
+        }
+        if self.batch.len() >= WRITE_BATCH {
+            self.write_batch().await?;
+        }
+        Ok(())
+    }
+
+    async fn write_batch(&mut self) -> std::io::Result<()> {
+        let batch = std::mem::take(&mut self.batch);
+        if let Some(mut pending) = self.pending.take() {
+            if !batch.is_empty() {
+                pending = blocking(move || pending.write_all(&batch).map(|_| pending)).await?;
+            }
+            self.pending = Some(pending);
+        }
+        Ok(())
+    }
+}
+
 
-//##: Build the optional Web Bot Auth signer from env config. Signing is opt-in:
+//Sink I/O runs on tokio's blocking pool, so a slow disk (or an fsync) doesn't hold up the
+//feeds in flight
+async fn blocking<T, F>(work: F) -> std::io::Result<T>
+where
+    F: FnOnce() -> std::io::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(work).await.map_err(std::io::Error::other)?
+}
+
+
+//##: Build the optional Web Bot Auth signer from the config. Signing is opt-in:
 //##: if no key is configured or it fails to load, we run unsigned (as before).
-fn build_signer() -> Option<Arc<WebBotAuthSigner>> {
-    let key_path = match std::env::var("AGGRIVATOR_SIGNING_KEY") {
-        Ok(p) if !p.is_empty() => p,
-        _ => {
-            println!("Web Bot Auth signing disabled (AGGRIVATOR_SIGNING_KEY not set)");
+fn build_signer(config: &Config) -> Option<Arc<WebBotAuthSigner>> {
+    let key_path = match &config.signing.key {
+        Some(p) => p.to_string_lossy().to_string(),
+        None => {
+            info!("Web Bot Auth signing disabled (no signing key configured)");
             return None;
         }
     };
-    let agent = std::env::var("AGGRIVATOR_SIGNATURE_AGENT")
-        .unwrap_or_else(|_| "https://podcastindex.org".to_string());
-    let ttl: u64 = std::env::var("AGGRIVATOR_SIGNATURE_TTL")
-        .ok()
-        .and_then(|s| s.parse().ok())
-        .unwrap_or(300);
-    match WebBotAuthSigner::from_pem_file(&key_path, agent, ttl) {
+    match WebBotAuthSigner::from_pem_file(&key_path, config.signing.agent.clone(), config.signing.ttl_secs) {
         Ok(signer) => {
-            println!("Web Bot Auth signing enabled (keyid={})", signer.keyid());
+            info!(keyid = %signer.keyid(), "Web Bot Auth signing enabled");
             Some(Arc::new(signer))
         }
         Err(e) => {
-            eprintln!(
-                "Web Bot Auth signing disabled: failed to load key [{}]: {}",
-                key_path, e
+            error!(key = %key_path, error = %e, "Web Bot Auth signing disabled: failed to load key");
+            None
+        }
+    }
+}
+
+
+//##: Send log lines to stderr, as text or one JSON object per line. Every line about a feed
+//##: carries the feed's span (feed_id and host), and the attempt span when there is one.
+//##: When a collector is configured the spans are also exported as traces; the provider
+//##: doing that is returned so the last of them can be flushed on the way out.
+fn init_logging(config: &Config) -> Option<SdkTracerProvider> {
+    let filter = EnvFilter::try_new(&config.log.level).unwrap_or_else(|_| EnvFilter::new("info"));
+    let output = tracing_subscriber::fmt::layer()
+        .with_writer(std::io::stderr)
+        .with_ansi(std::io::stderr().is_terminal());
+    let output = match config.log.format.as_str() {
+        "json" => output.json().flatten_event(true).with_current_span(false).with_span_list(true).boxed(),
+        _ => output.with_target(false).boxed(),
+    };
+
+    //Build the exporter first, but only say how that went once there's somewhere to say it
+    let exporting = config.otlp.endpoint.as_ref().map(|endpoint| -> Result<SdkTracerProvider, Box<dyn Error>> {
+        let export = ExportConfig {
+            endpoint: endpoint.clone(),
+            json: config.otlp.protocol == "http/json",
+            sample_ratio: config.otlp.sample_ratio,
+            service_name: config.otlp.service_name.clone(),
+        };
+        Ok(otel::provider(&export, build_client(USERAGENT)?)?)
+    });
+    let (provider, export_error) = match exporting {
+        Some(Ok(provider)) => (Some(provider), None),
+        Some(Err(e)) => (None, Some(e)),
+        None => (None, None),
+    };
+    tracing_subscriber::registry()
+        .with(output.with_filter(filter))
+        .with(provider.as_ref().map(otel::layer))
+        .init();
+
+    if let Some(e) = export_error {
+        error!(error = %e, "Can't export traces");
+    }
+    if let Some(provider) = &provider {
+        otel::install(provider);
+        info!(endpoint = config.otlp.endpoint.as_deref().unwrap_or(""), "Exporting traces");
+    }
+    provider
+}
+
+
+//##: Send the spans still waiting to the collector
+fn flush_traces(provider: Option<SdkTracerProvider>) {
+    if let Some(provider) = provider {
+        if let Err(e) = provider.shutdown() {
+            warn!(error = %e, "Couldn't flush traces");
+        }
+    }
+}
+
+
+//##: Load the config file and env overrides, refusing to go on with settings that don't make sense
+fn load_config(path: Option<&Path>) -> Result<Config, Box<dyn Error>> {
+    let config = Config::load(path, |name| std::env::var(name).ok())?;
+    match config.problems().as_slice() {
+        [] => Ok(config),
+        problems => Err(Box::new(HydraError(format!("Invalid configuration: {}", problems.join("; "))))),
+    }
+}
+
+
+//##: Build the per-host politeness limits from the config
+fn build_politeness(config: &Config) -> PolitenessConfig {
+    let politeness = config.politeness();
+    info!(
+        "Politeness: {} per host, {} per IP, {}ms between requests to a host",
+        politeness.per_host_limit,
+        politeness.per_ip_limit.map(|n| n.to_string()).unwrap_or_else(|| "unlimited".to_string()),
+        politeness.min_host_delay.as_millis()
+    );
+    politeness
+}
+
+
+//##: Build the retry policy for 429s, gateway errors, timeouts and resets from the config
+fn build_retry_policy(config: &Config) -> RetryPolicy {
+    let policy = config.retry_policy();
+    info!(
+        "Retries: {} attempts per feed, {}ms base backoff, {}s max wait, {}s per feed",
+        policy.max_attempts,
+        policy.base_delay.as_millis(),
+        policy.max_delay.as_secs(),
+        policy.max_total.as_secs()
+    );
+    policy
+}
+
+
+//##: Build the per-host circuit breaker settings from the config. A threshold of 0 turns it off.
+fn build_breaker_config(config: &Config) -> BreakerConfig {
+    let breaker = config.breaker_config();
+    match breaker.failure_threshold {
+        0 => info!("Circuit breaker: off"),
+        threshold => info!(
+            "Circuit breaker: opens after {} failures in a row from a host, {}s cool-down",
+            threshold,
+            breaker.cool_down.as_secs()
+        ),
+    }
+    breaker
+}
+
+
+//##: Build the adaptive scheduling bounds from the config. Turning it off leaves the queue to
+//##: the lastcheck based rules alone.
+fn build_schedule_config(config: &Config) -> Option<ScheduleConfig> {
+    match config.schedule_config() {
+        Some(schedule) => {
+            info!(
+                "Adaptive scheduling: every {}m to {}d per feed, starting at {}h",
+                schedule.min_interval_secs / 60,
+                schedule.max_interval_secs / 86400,
+                schedule.initial_interval_secs / 3600
             );
+            Some(schedule)
+        }
+        None => {
+            info!("Adaptive scheduling: off");
             None
         }
     }
 }
 
 
+//##: Build the queue selection rules from the config. Queue mode `all` (the default) polls
+//##: every feed by id like the original poller; `due` only picks the feeds that are due.
+fn build_queue_selection(config: &Config) -> QueueSelection {
+    let selection = config.queue_selection();
+    info!("Queue selection: {:?}", selection);
+    selection
+}
+
+
+//##: Open the queue writer if write-back mode is enabled. Without it the queue db is
+//##: only read, and something outside the poller has to refresh it between runs. The
+//##: adaptive schedule is kept by the writer, so it only learns with write-back on.
+fn build_queue_writer(settings: &Settings) -> Option<QueueWriter> {
+    if !settings.config.write_back || settings.dry_run {
+        return None;
+    }
+    let writer = open_queue_writer(settings)?;
+    info!(queue_db = %settings.config.queue_db.display(), "Queue write-back enabled");
+    Some(writer)
+}
+
+
+//##: Start a queue writer thread on the queue db
+fn open_queue_writer(settings: &Settings) -> Option<QueueWriter> {
+    let sqlite_file = settings.config.queue_db.to_string_lossy();
+    match QueueWriter::spawn(&sqlite_file, settings.config.write_back_batch, settings.schedule_config.clone()) {
+        Ok(writer) => Some(writer),
+        Err(e) => {
+            error!(queue_db = %sqlite_file, error = %e, "Queue write-back disabled: failed to open the queue db");
+            None
+        }
+    }
+}
+
+
+//##: Flush the results still waiting to be written back and stop the writer thread
+fn finish_queue_writer(writer: QueueWriter) {
+    match writer.finish() {
+        Ok(count) => info!(count, "Wrote check results back to the queue"),
+        Err(e) => error!(error = %e, "Error writing check results back to the queue"),
+    }
+}
+
+
+//##: Build every setting a run needs from the config
+fn build_settings(config: Config) -> Settings {
+    Settings {
+        signer: build_signer(&config),
+        politeness: build_politeness(&config),
+        retry_policy: build_retry_policy(&config),
+        breaker_config: build_breaker_config(&config),
+        schedule_config: build_schedule_config(&config),
+        selection: build_queue_selection(&config),
+        dry_run: false,
+        metrics: None,
+        signals: Arc::default(),
+        config,
+    }
+}
+
+
+//##: Set up the shared client, host limits and circuit breaker that feed checks run through
+fn build_poller(settings: &Settings) -> Result<Poller, Box<dyn Error>> {
+    let config = &settings.config;
+
+    //One client for the whole run so connections and TLS sessions are reused across feeds
+    let ctx = FetchContext {
+        client: build_client_with_timeouts(USERAGENT, config.connect_timeout(), config.request_timeout())?,
+        signer: settings.signer.clone(),
+        max_body_length: config.max_body_length,
+        legacy_header: config.legacy_header(),
+        retry: settings.retry_policy.clone(),
+        request_timeout: config.request_timeout(),
+        output: match settings.dry_run {
+            true => Output::Tally(DryRunTally::default()),
+            false => Output::Sink(build_sink(config)?),
+        },
+        report: None,
+        metrics: settings.metrics.clone(),
+        signals: settings.signals.clone(),
+    };
+
+    Ok(Poller {
+        ctx,
+        limiter: HostLimiter::new(settings.politeness.clone()),
+        breaker: CircuitBreaker::new(settings.breaker_config.clone()),
+        cool_down: settings.breaker_config.cool_down,
+        concurrency: config.concurrency,
+    })
+}
+
+
+//##: Open the sink results go to, as picked by `output.sink`. The file and JSON directory sinks
+//##: share the feed file naming, and so the file policy.
+fn build_sink(config: &Config) -> Result<Arc<dyn ResultSink>, Box<dyn Error>> {
+    let kind = config.sink_kind();
+    let namer = match kind {
+        SinkKind::JsonDir => FeedFileNamer::new(config.naming_policy(), unix_now()).with_extension("json"),
+        _ => FeedFileNamer::new(config.naming_policy(), unix_now()),
+    };
+
+    //Superseding needs to know which results from earlier runs are still waiting on the parser
+    if namer.policy() == NamingPolicy::Supersede {
+        for directory in config.output_dirs() {
+            if let Err(e) = namer.index_dir(&directory) {
+                warn!(dir = %directory.display(), error = %e, "Error indexing existing feed files");
+            }
+        }
+    }
+
+    let sink: Arc<dyn ResultSink> = match kind {
+        SinkKind::Files => Arc::new(FileSink::new(
+            config.feeds_dir.clone(),
+            config.redirects_dir.clone(),
+            namer,
+            config.fsync,
+            config.legacy_header(),
+        )),
+        SinkKind::JsonLines => Arc::new(JsonLinesSink::stdout()),
+        SinkKind::Sqlite => Arc::new(SqliteSink::open(&config.output.sqlite_db).map_err(|e| {
+            HydraError(format!("Can't open results db [{}]: {}", config.output.sqlite_db.display(), e))
+        })?),
+        SinkKind::JsonDir => Arc::new(JsonDirSink::new(config.output.json_dir.clone(), namer, config.fsync)),
+    };
+    Ok(sink)
+}
+
+
 //##: -------------------- Main() -----------------------
 //##: ---------------------------------------------------
 #[tokio::main]
 async fn main() {
-    //Globals
-    let sqlite_file: &str = "feed_poller_queue.db";
+    let cli = Cli::parse();
+    let command = cli.command.unwrap_or(Command::Run);
+
+    //Checking the config has to work on a config that doesn't load
+    if let Command::CheckConfig = command {
+        std::process::exit(check_config(cli.config.as_deref()));
+    }
+    let config = match load_config(cli.config.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
 
-    //Make sure folders we need exist.
-    // TODO: need env and error intelligence for containerizing
-    // match create_dir(DIR_FEED_FILES) {
-    //     Ok()
-    // }
-    // create_dir(DIR_REDIRECT_FILES)?;
+    //Everything from here on logs through tracing, to stderr
+    let tracer = init_logging(&config);
+
+    //The admin commands don't poll anything
+    match command {
+        Command::Keygen { out } => std::process::exit(keygen(&config, &out)),
+        Command::Jwks => std::process::exit(print_jwks(&config)),
+        Command::Probe { url } => {
+            let code = probe(config, &url).await;
+            flush_traces(tracer);
+            std::process::exit(code);
+        }
+        _ => {}
+    }
 
     //Announce what we are
-    println!("{}", USERAGENT);
-    println!("{}\n", "-".repeat(USERAGENT.len()));
+    let _ = writeln!(console(&config), "{}\n{}\n", USERAGENT, "-".repeat(USERAGENT.len()));
+    if let Some(source) = &config.source {
+        info!(config = %source.display(), "Loaded config file");
+    }
+
+    //A dry run keeps the queue where it is, so a daemon would just check the same feeds every pass
+    if cli.dry_run {
+        if let Command::Daemon = command {
+            error!("A dry run can't be used with daemon mode.");
+            std::process::exit(2);
+        }
+        info!("Dry run: no feed files or queue updates will be written.");
+    } else {
+        //Make sure folders we need exist, and that we can write to them
+        if let Err(e) = prepare_output_dirs(&config) {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+
+        //Clear out anything a previous run was part way through writing
+        clean_stale_temp_files(&config);
+    }
+
+    //Stop starting new feeds on SIGTERM/SIGINT, letting the ones in flight finish
+    let halted = Arc::new(AtomicBool::new(false));
+    let signals = Arc::new(SignalState::default());
+    tokio::spawn(watch_signals(signals.clone(), halted.clone()));
 
     //Fetch urls
-    let signer = build_signer();
-    let podcasts = get_feeds_from_sql(sqlite_file);
-    match podcasts {
-        Ok(podcasts) => {
-            match fetch_feeds(podcasts, signer).await {
-                Ok(_) => {}
-                Err(_) => {}
+    let mut settings = Settings { dry_run: cli.dry_run, signals: signals.clone(), ..build_settings(config) };
+
+    //Serve metrics for as long as we're polling
+    if let Some(addr) = settings.config.metrics_addr() {
+        let metrics = Arc::new(Metrics::new());
+        match spawn_metrics_server(metrics.clone(), addr) {
+            Ok(bound) => info!("Serving metrics at http://{}/metrics", bound),
+            Err(e) => {
+                error!(%addr, error = %e, "Can't serve metrics");
+                std::process::exit(1);
             }
         }
-        Err(e) => println!("{}", e),
+        settings.metrics = Some(metrics);
     }
+    match command {
+        Command::Daemon => run_daemon(cli.config.as_deref(), settings, &signals, &halted).await,
+        Command::Fetch { feeds, force, write } => fetch_listed_feeds(&settings, &feeds, force, write, &halted).await,
+        _ => run_once(&settings, &signals, &halted).await,
+    }
+    flush_traces(tracer);
 }
 //##: ---------------------------------------------------
 
 
-//##: Take in a vector of Podcasts and attempt to pull each one of them that is update
+//##: Poll whatever is due in the queue, once
+async fn run_once(settings: &Settings, signals: &SignalState, halted: &Arc<AtomicBool>) {
+    let sqlite_file = settings.config.queue_db.to_string_lossy().to_string();
+    let queue_writer = build_queue_writer(settings);
+
+    //Honor the operator kill switch before we start anything
+    match go_flag(&sqlite_file) {
+        Ok(true) => {}
+        Ok(false) => {
+            warn!(queue_db = %sqlite_file, "status.go is cleared. Not starting a run.");
+            return;
+        }
+        Err(e) => {
+            error!(queue_db = %sqlite_file, error = %e, "Error reading status.go");
+            return;
+        }
+    }
+
+    //Keep watching the kill switch while the run is going
+    let watcher = tokio::spawn(watch_go_flag(
+        sqlite_file.clone(),
+        halted.clone(),
+        Duration::from_secs(settings.config.status_poll_secs),
+    ));
+
+    let podcasts = get_feeds_from_sql(&sqlite_file, &settings.selection);
+    match (podcasts, build_poller(settings)) {
+        (Ok(podcasts), Ok(poller)) => {
+            let tally = fetch_feeds(&poller, podcasts, queue_writer.as_ref(), halted).await;
+            report_run(settings, &tally, signals.halt_reason(halted));
+            print_dry_run_tally(&poller.ctx);
+        }
+        (Err(e), _) => error!("{}", e),
+        (_, Err(e)) => error!("{}", e),
+    }
+    watcher.abort();
+
+    //Flush whatever results are still waiting to be written back
+    if let Some(writer) = queue_writer {
+        finish_queue_writer(writer);
+    }
+}
+
+
+//##: Check the feeds named on the command line, by queue id or url, one at a time through the
+//##: same path a run uses, and print a report of each. A url that isn't in the queue is checked
+//##: as feed 0 with no validators, and its files are never written. `force` leaves the stored
+//##: validators out, and the feed files are only reported unless `write` is set.
+async fn fetch_listed_feeds(settings: &Settings, feeds: &[String], force: bool, write: bool, halted: &AtomicBool) {
+    let sqlite_file = settings.config.queue_db.to_string_lossy().to_string();
+    let mut podcasts = Vec::new();
+    for feed in feeds {
+        match lookup_feed(&sqlite_file, feed) {
+            Ok(Some(podcast)) => podcasts.push(podcast),
+            Ok(None) if feed.parse::<u64>().is_ok() => error!(feed_id = %feed, "No feed with this id in the queue"),
+            Ok(None) => podcasts.push(Podcast {
+                id: 0,
+                url: feed.clone(),
+                title: "".to_string(),
+                last_modified: 0,
+                etag: "".to_string(),
+            }),
+            Err(e) => error!(%feed, error = %e, "Error looking up the feed in the queue"),
+        }
+    }
+    let mut ctx = match build_poller(settings) {
+        Ok(poller) => poller.ctx,
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
+    };
+
+    for podcast in podcasts {
+        if halted.load(Ordering::SeqCst) {
+            break;
+        }
+
+        //Files for feed 0 would land next to the real ones for the parser to pick up, so a url
+        //that isn't in the queue only ever has its files reported
+        if write && podcast.id == 0 {
+            warn!(url = %podcast.url, "Not in the queue, so its files are only reported, not written.");
+        }
+        ctx.output = match ctx.output {
+            Output::Sink(sink) | Output::Report(sink) if write && podcast.id != 0 => Output::Sink(sink),
+            Output::Sink(sink) | Output::Report(sink) => Output::Report(sink),
+            output => output,
+        };
+        let (etag, last_modified) = match force {
+            true => ("", 0),
+            false => (podcast.etag.as_str(), podcast.last_modified),
+        };
+        ctx.report = Some(Mutex::default());
+        println!("\nFeed: [{}|{}|{}]", podcast.id, podcast.title, podcast.url);
+        let started = Instant::now();
+        let outcome = check_feed_is_updated(&ctx, &podcast.url, etag, last_modified, podcast.id, &mut Timings::default(), None)
+            .instrument(feed_span(&podcast))
+            .await;
+        let elapsed = started.elapsed();
+        if let Some(report) = ctx.report.take() {
+            print_feed_report(&podcast, &outcome, &report.into_inner().unwrap(), elapsed);
+        }
+    }
+    print_dry_run_tally(&ctx);
+}
+
+
+//##: Print what a single check sent, got back and wrote
+fn print_feed_report(podcast: &Podcast, outcome: &Result<PodcastCheckResult, FetchError>, report: &FeedReport, elapsed: Duration) {
+    println!("\n===== Feed [{}] =====", podcast.id);
+    println!("GET {}", podcast.url);
+    if report.request_headers.is_empty() {
+        println!("  (no conditional headers)");
+    }
+    for (name, value) in &report.request_headers {
+        println!("  {}: {}", name, value);
+    }
+    for hop in &report.hops {
+        println!("  -> {} {}", hop.status, hop.location);
+    }
+
+    match outcome {
+        Ok(result) => {
+            println!("Status: {} ({})", result.status_code, if result.updated { "updated" } else { "not updated" });
+            println!("Final url: {}", report.final_url);
+            if let Some(url) = &result.permanent_url {
+                println!("Moved to: {}", url);
+            }
+        }
+        Err(e) => println!("Failed: {} [{}]", e, e.status_code()),
+    }
+    if report.status.is_some() {
+        println!("Response headers:");
+        for (name, value) in &report.response_headers {
+            println!("  {}: {}", name, value);
+        }
+    }
+
+    println!("Attempts: {}", report.attempts);
+    print!("Timing: {}ms to response headers", report.response_time.as_millis());
+    if !report.retry_wait.is_zero() {
+        print!(", {}ms waiting to retry", report.retry_wait.as_millis());
+    }
+    println!(", {}ms in all", elapsed.as_millis());
+    if let Some(addr) = report.timings.remote_addr {
+        println!("Remote address: {}", addr);
+    }
+    println!("Phases: {}", report.timings);
+
+    for file in &report.files {
+        let verb = if file.written { "Wrote" } else { "Would write" };
+        match &file.location {
+            Some(location) => println!("{}: {} ({} body bytes)", verb, location, file.body_length),
+            None => println!("{}: ({} body bytes)", verb, file.body_length),
+        }
+        for line in file.header.lines() {
+            println!("  | {}", line);
+        }
+    }
+}
+
+
+//##: Find a feed in the queue db by id or url
+fn lookup_feed(sqlite_file: &str, id_or_url: &str) -> Result<Option<Podcast>, Box<dyn Error>> {
+    let sql = open_queue_db(sqlite_file)?;
+    Ok(find_feed(&sql, id_or_url)?)
+}
+
+
+//##: Request one url through the production client, redirect handling and signer and print what
+//##: came back. Nothing is written anywhere.
+async fn probe(config: Config, url: &str) -> i32 {
+    let settings = build_settings(config);
+    let client = match build_client_with_timeouts(USERAGENT, settings.config.connect_timeout(), settings.config.request_timeout()) {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("Error building client: {}", e);
+            return 1;
+        }
+    };
+    let mut hops: Vec<RedirectHop> = Vec::new();
+    let mut phases = RequestPhases::default();
+    let started = Instant::now();
+    let response = send_following_redirects(
+        &client,
+        url,
+        &header::HeaderMap::new(),
+        settings.signer.as_deref(),
+        settings.config.request_timeout(),
+        &mut hops,
+        &mut phases,
+    ).await;
+
+    println!("\nGET {}", url);
+    for hop in &hops {
+        println!("  -> {} {}", hop.status, hop.location);
+    }
+    if let Some(addr) = phases.remote_addr {
+        println!("Remote address: {}", addr);
+    }
+    println!("Timing: {}", phases.timings());
+    match response {
+        Ok(res) => {
+            println!("Status: {} ({}ms)", res.status(), started.elapsed().as_millis());
+            println!("Final url: {}", res.url());
+            for (name, value) in res.headers() {
+                println!("  {}: {}", name, value.to_str().unwrap_or("<binary>"));
+            }
+            match res.bytes().await {
+                Ok(body) => println!("Body: {} bytes", body.len()),
+                Err(e) => println!("Body: {}", FetchError::from_reqwest(&e, true)),
+            }
+            0
+        }
+        Err(e) => {
+            println!("Failed: {} [{}] ({}ms)", e, e.status_code(), started.elapsed().as_millis());
+            1
+        }
+    }
+}
+
+
+//##: Write a new signing key to `out` and print the JWKS to publish for it. Never overwrites a key.
+fn keygen(config: &Config, out: &Path) -> i32 {
+    //Only the poller should be able to read the key, and creating it can't replace one already there
+    let file = std::fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(out);
+    let mut file = match file {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+            eprintln!("Refusing to overwrite existing key file: {}", out.display());
+            return 1;
+        }
+        Err(e) => {
+            eprintln!("Error creating key file [{}]: {}", out.display(), e);
+            return 1;
+        }
+    };
+    let written = generate_key_pem().and_then(|pem| Ok(file.write_all(pem.as_bytes())?));
+    if let Err(e) = written {
+        eprintln!("Error writing key file [{}]: {}", out.display(), e);
+        let _ = std::fs::remove_file(out);
+        return 1;
+    }
+    println!("Wrote Ed25519 private key to {}", out.display());
+    match WebBotAuthSigner::from_pem_file(&out.to_string_lossy(), config.signing.agent.clone(), config.signing.ttl_secs) {
+        Ok(signer) => {
+            println!("keyid: {}", signer.keyid());
+            println!("\nPublish this JWKS at /.well-known/http-message-signatures-directory:\n");
+            println!("{}", serde_json::to_string_pretty(&signer.jwks()).unwrap_or_default());
+            0
+        }
+        Err(e) => {
+            eprintln!("Error loading the new key: {}", e);
+            1
+        }
+    }
+}
+
+
+//##: Print the JWKS for the configured signing key
+fn print_jwks(config: &Config) -> i32 {
+    let key_path = match &config.signing.key {
+        Some(path) => path.to_string_lossy().to_string(),
+        None => {
+            eprintln!("No signing key configured.");
+            return 1;
+        }
+    };
+    match WebBotAuthSigner::from_pem_file(&key_path, config.signing.agent.clone(), config.signing.ttl_secs) {
+        Ok(signer) => {
+            println!("{}", serde_json::to_string_pretty(&signer.jwks()).unwrap_or_default());
+            0
+        }
+        Err(e) => {
+            eprintln!("Error loading signing key [{}]: {}", key_path, e);
+            1
+        }
+    }
+}
+
+
+//##: Load the config, report everything wrong with it, and print the effective settings
+fn check_config(path: Option<&Path>) -> i32 {
+    let config = match Config::load(path, |name| std::env::var(name).ok()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    };
+    let mut problems = config.problems();
+    if let Some(key) = &config.signing.key {
+        if let Err(e) = WebBotAuthSigner::from_pem_file(&key.to_string_lossy(), config.signing.agent.clone(), config.signing.ttl_secs) {
+            problems.push(format!("signing key [{}] can't be loaded: {}", key.display(), e));
+        }
+    }
+    for directory in [&config.feeds_dir, &config.redirects_dir] {
+        if directory.exists() && !directory.is_dir() {
+            problems.push(format!("[{}] exists and is not a directory", directory.display()));
+        }
+    }
+    if !config.queue_db.is_file() {
+        problems.push(format!("queue db [{}] doesn't exist", config.queue_db.display()));
+    }
+
+    match &config.source {
+        Some(source) => println!("# Loaded from {} with env overrides", source.display()),
+        None => println!("# Built-in defaults with env overrides"),
+    }
+    println!("{}", config.to_toml());
+    for problem in &problems {
+        eprintln!("Problem: {}", problem);
+    }
+    match problems.is_empty() {
+        true => 0,
+        false => 1,
+    }
+}
+
+
+//##: Create the output directories if they're missing and make sure we can write to them, so a
+//##: bad mount fails at startup rather than on every feed
+fn prepare_output_dirs(config: &Config) -> Result<(), Box<dyn Error>> {
+    for directory in &config.output_dirs() {
+        create_dir_all(directory)
+            .map_err(|e| HydraError(format!("Can't create output directory [{}]: {}", directory.display(), e)))?;
+        let probe = directory.join(format!(".aggrivator-write-check.{}", std::process::id()));
+        std::fs::write(&probe, b"")
+            .and_then(|_| std::fs::remove_file(&probe))
+            .map_err(|e| HydraError(format!("Can't write to output directory [{}]: {}", directory.display(), e)))?;
+    }
+    Ok(())
+}
+
+
+//##: Poll the queue until told to stop. Each pass re-reads the db, so newly added feeds are
+//##: picked up, and checks whatever is due using the same client, host limits and breaker
+//##: state as the last pass. Results are always written back, since that's what moves a
+//##: feed's next check into the future. A cleared status.go pauses the daemon rather than
+//##: stopping it. SIGHUP reloads the config file and env before the next pass.
+async fn run_daemon(config_path: Option<&Path>, settings: Settings, signals: &SignalState, halted: &Arc<AtomicBool>) {
+    let poller = match build_poller(&settings) {
+        Ok(poller) => poller,
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
+    };
+    info!("Daemon mode: checking the queue for due feeds every {}s.", settings.config.daemon_interval_secs);
+    if settings.config.queue.mode == "all" {
+        warn!("Queue mode is all, so every pass checks every feed. Set queue.mode = \"due\" to only check the feeds that are due.");
+    }
+
+    let mut poller_daemon = PollerDaemon { config_path, settings, poller, signals, halted };
+    daemon::run(&mut poller_daemon, signals).await;
+}
+
+
+//What the daemon loop keeps between passes
+struct PollerDaemon<'a> {
+    config_path: Option<&'a Path>,
+    settings: Settings,
+    poller: Poller,
+    signals: &'a SignalState,
+    halted: &'a Arc<AtomicBool>,
+}
+
+impl Daemon for PollerDaemon<'_> {
+    fn reload(&mut self) -> Result<(), Box<dyn Error>> {
+        let (settings, poller) = reload_settings(self.config_path, &self.settings)?;
+        self.settings = settings;
+        self.poller = poller;
+        Ok(())
+    }
+
+    fn interval(&self) -> Duration {
+        Duration::from_secs(self.settings.config.daemon_interval_secs)
+    }
+
+    async fn pass(&mut self) {
+        let sqlite_file = self.settings.config.queue_db.to_string_lossy().to_string();
+        match go_flag(&sqlite_file) {
+            Ok(true) => {
+                self.signals.resume(self.halted);
+                run_daemon_pass(&sqlite_file, &self.settings, &self.poller, self.signals, self.halted).await;
+            }
+            Ok(false) => info!(queue_db = %sqlite_file, "status.go is cleared. Waiting."),
+            Err(e) => error!(queue_db = %sqlite_file, error = %e, "Error reading status.go"),
+        }
+    }
+}
+
+
+//##: Load the config again and build fresh settings and poller from it. The metrics carry over,
+//##: since their server can't move, and so do the signals.
+fn reload_settings(config_path: Option<&Path>, current: &Settings) -> Result<(Settings, Poller), Box<dyn Error>> {
+    let config = load_config(config_path)?;
+    prepare_output_dirs(&config)?;
+    let settings = Settings {
+        metrics: current.metrics.clone(),
+        signals: current.signals.clone(),
+        ..build_settings(config)
+    };
+    let poller = build_poller(&settings)?;
+    Ok((settings, poller))
+}
+
+
+//##: One pass of the daemon over whatever is due right now
+async fn run_daemon_pass(sqlite_file: &str, settings: &Settings, poller: &Poller, signals: &SignalState, halted: &Arc<AtomicBool>) {
+    let podcasts = match get_feeds_from_sql(sqlite_file, &settings.selection) {
+        Ok(podcasts) if podcasts.is_empty() => return,
+        Ok(podcasts) => podcasts,
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
+    };
+    let queue_writer = open_queue_writer(settings);
+    let watcher = tokio::spawn(watch_go_flag(
+        sqlite_file.to_string(),
+        halted.clone(),
+        Duration::from_secs(settings.config.status_poll_secs),
+    ));
+    let tally = fetch_feeds(poller, podcasts, queue_writer.as_ref(), halted).await;
+    watcher.abort();
+    report_run(settings, &tally, signals.halt_reason(halted));
+    if let Some(writer) = queue_writer {
+        finish_queue_writer(writer);
+    }
+}
+
+
+//##: Turn SIGTERM and SIGINT into a graceful stop: no new feeds are started, but requests in
+//##: flight are allowed to finish. A second one exits straight away. SIGHUP asks a daemon to
+//##: reload its settings.
+async fn watch_signals(signals: Arc<SignalState>, halted: Arc<AtomicBool>) {
+    let (mut terminate, mut interrupt, mut hangup) = match (
+        signal(SignalKind::terminate()),
+        signal(SignalKind::interrupt()),
+        signal(SignalKind::hangup()),
+    ) {
+        (Ok(terminate), Ok(interrupt), Ok(hangup)) => (terminate, interrupt, hangup),
+        _ => {
+            error!("Error installing signal handlers. Signals will not drain the run.");
+            return;
+        }
+    };
+    loop {
+        tokio::select! {
+            _ = terminate.recv() => {}
+            _ = interrupt.recv() => {}
+            _ = hangup.recv() => {
+                info!("SIGHUP received, settings will be reloaded before the next pass.");
+                signals.hang_up();
+                continue;
+            }
+        }
+        if signals.stop(&halted) {
+            warn!("Second stop signal received, exiting without waiting.");
+            std::process::exit(1);
+        }
+        info!("Stop signal received. No new feeds will be started; letting in-flight requests finish.");
+    }
+}
+
+
+//##: Remove half-written feed files left behind by a run that died mid-write
+fn clean_stale_temp_files(config: &Config) {
+    for directory in &config.output_dirs() {
+        match clean_temp_files(directory, STALE_TEMP_AGE) {
+            Ok(0) => {}
+            Ok(count) => info!(count, dir = %directory.display(), "Removed stale temp files"),
+            Err(e) => warn!(dir = %directory.display(), error = %e, "Error cleaning temp files"),
+        }
+    }
+}
+
+
+//##: Read status.go from the queue db
+fn go_flag(sqlite_file: &str) -> Result<bool, Box<dyn Error>> {
+    let sql = open_queue_db(sqlite_file)?;
+    Ok(read_go_flag(&sql)?)
+}
+
+
+//##: Poll status.go every `interval` and raise `halted` once it has been cleared. A read
+//##: error is logged and ignored so a locked db can't stop a healthy run.
+async fn watch_go_flag(sqlite_file: String, halted: Arc<AtomicBool>, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        let file = sqlite_file.clone();
+        match tokio::task::spawn_blocking(move || go_flag(&file).map_err(|e| e.to_string())).await {
+            Ok(Ok(true)) => {}
+            Ok(Ok(false)) => {
+                info!("status.go was cleared. No new feeds will be started; letting in-flight requests finish.");
+                halted.store(true, Ordering::SeqCst);
+                return;
+            }
+            Ok(Err(e)) => warn!(error = %e, "Error reading status.go"),
+            Err(e) => warn!(error = %e, "Error reading status.go"),
+        }
+    }
+}
+
+
+//##: Print what a run did
+fn print_run_tally(out: &mut dyn Write, tally: &RunTally, halted: Option<&str>) -> SummaryReport {
+    let heading = match halted {
+        Some(reason) => format!("----- Run halted by {}. -----", reason),
+        None => "----- Run complete. -----".to_string(),
+    };
+    let report = tally.summary.report(tally.duration, unix_now());
+    let _ = write!(
+        out,
+        "\n{}\n  Queued:      {}\n  Updated:     {}\n  Not updated: {}\n  Errors:      {}\n  Deferred:    {}\n  Not started: {}\n{}",
+        heading,
+        tally.queued,
+        tally.updated.load(Ordering::SeqCst),
+        tally.not_updated.load(Ordering::SeqCst),
+        tally.errors.load(Ordering::SeqCst),
+        tally.deferred.load(Ordering::SeqCst),
+        tally.skipped.load(Ordering::SeqCst),
+        report,
+    );
+    report
+}
+
+
+//##: Where the banner and run summaries are printed: stdout, unless the results are going there
+fn console(config: &Config) -> Box<dyn Write> {
+    match config.sink_kind() {
+        SinkKind::JsonLines => Box::new(std::io::stderr()),
+        _ => Box::new(std::io::stdout()),
+    }
+}
+
+
+//##: Print the end of run summary, and write it out as JSON for dashboards if that's configured
+fn report_run(settings: &Settings, tally: &RunTally, halted: Option<&str>) {
+    let report = print_run_tally(&mut console(&settings.config), tally, halted);
+    if let (Some(path), false) = (&settings.config.summary_file, settings.dry_run) {
+        if let Err(e) = report.write_json(path) {
+            error!(file = %path.display(), error = %e, "Error writing run summary");
+        }
+    }
+}
+
+
+//##: Print what a dry run would have written, if this was one
+fn print_dry_run_tally(ctx: &FetchContext) {
+    let tally = match &ctx.output {
+        Output::Tally(tally) => tally,
+        _ => return,
+    };
+    let by_status = tally.by_status.lock().unwrap();
+    println!("\n----- Dry run, nothing was written. Would have written: -----");
+    for (status, (files, bytes)) in by_status.iter() {
+        println!("  {}: {} files, {} bytes", status, files, bytes);
+    }
+    let files: usize = by_status.values().map(|(files, _)| files).sum();
+    let bytes: usize = by_status.values().map(|(_, bytes)| bytes).sum();
+    println!("  Total: {} files, {} bytes", files, bytes);
+    println!("  Redirect stubs: {}", tally.redirect_stubs.load(Ordering::SeqCst));
+}
+
+
+//##: Take in a vector of Podcasts and attempt to pull each one of them that is update. Once
+//##: `halted` is raised no new feeds are started, but in-flight requests run to completion.
 async fn fetch_feeds(
+    poller: &Poller,
     podcasts: Vec<Podcast>,
-    signer: Option<Arc<WebBotAuthSigner>>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let fetches = futures::stream::iter(
-        podcasts.into_iter().map(|podcast| {
-            let signer = signer.clone();
+    queue_writer: Option<&QueueWriter>,
+    halted: &AtomicBool,
+) -> RunTally {
+    //Each host gets its own queue, and never has more than a few requests in flight. A feed
+    //only takes one of the run's slots once its host has room, so a slow host's backlog waits
+    //in its own queue instead of filling every slot. A feed waiting to retry gives both back.
+    let Poller { ctx, limiter, breaker, cool_down, concurrency } = poller;
+    let mut tally = RunTally { queued: podcasts.len(), ..RunTally::default() };
+    let started = Instant::now();
+    record_metrics(ctx, |metrics| metrics.set_queue_depth(podcasts.len()));
+    let slots = RunSlots::new(*concurrency);
+
+    //Feeds keep their place in the queue as their rank, which decides who gets the next free slot
+    let hosts = group_by_host(podcasts.into_iter().enumerate().collect(), |(_, podcast)| host_key(&podcast.url));
+    let fetches = hosts.into_iter().map(|feeds| {
+        futures::stream::iter(feeds).for_each_concurrent(limiter.per_host_limit(), |(rank, podcast)| {
+            let tally = &tally;
+            let slots = &slots;
+            let span = feed_span(&podcast);
             async move {
-                match check_feed_is_updated(&podcast.url, &podcast.etag.as_str(), podcast.last_modified, podcast.id, signer.as_deref()).await {
-                    Ok(resp) => {
-                        match resp {
-                            true => println!("  Feed: [{}|{}|{}] is updated.", podcast.id, podcast.title, podcast.url),
-                            false => println!("  Feed: [{}|{}|{}] is NOT updated.", podcast.id, podcast.title, podcast.url),
+                if halted.load(Ordering::SeqCst) {
+                    return;
+                }
+                //Don't wait on a slot for a host that keeps failing, just put the feed off
+                let host = host_key(&podcast.url);
+                let admission = breaker.admit(&host);
+                if admission == Admission::Deferred {
+                    record_metrics(ctx, |metrics| metrics.feed_dequeued());
+                    defer_feed(ctx, &podcast, &host, tally).await;
+                    return;
+                }
+                //A probe that is never sent is given back when this goes, so the host can't stay half-open
+                let mut probe = (admission == Admission::Probe).then(|| breaker.probe(&host));
+                let mut held = CheckSlots::new(limiter, slots, &podcast.url, rank);
+                held.take().await;
+                record_metrics(ctx, |metrics| metrics.feed_dequeued());
+                //We may have been waiting on the host a while, so check again before starting
+                if halted.load(Ordering::SeqCst) {
+                    return;
+                }
+                if probe.is_none() {
+                    match breaker.admit(&host) {
+                        Admission::Deferred => {
+                            defer_feed(ctx, &podcast, &host, tally).await;
+                            return;
+                        }
+                        Admission::Probe => probe = Some(breaker.probe(&host)),
+                        Admission::Allowed => {}
+                    }
+                }
+                if probe.is_some() {
+                    info!("Host cooled down, probing with this feed.");
+                }
+
+                let check_started = Instant::now();
+                let in_flight = ctx.metrics.as_ref().map(|metrics| metrics.check_started());
+                let mut timings = Timings::default();
+                //Boxed so the feeds still waiting in their host's queue stay small
+                let outcome = Box::pin(check_feed_is_updated(ctx, &podcast.url, podcast.etag.as_str(), podcast.last_modified, podcast.id, &mut timings, Some(&mut held))).await;
+                drop(in_flight);
+                let status = match &outcome {
+                    Ok(result) => result.status_code,
+                    Err(e) => e.status_code(),
+                };
+                let error = outcome.as_ref().err().map(|e| e.reason());
+                let elapsed = check_started.elapsed();
+                record_metrics(ctx, |metrics| metrics.check_finished(status, error, elapsed));
+                tally.summary.record(FeedRecord {
+                    feed_id: podcast.id,
+                    url: podcast.url.clone(),
+                    host: host.clone(),
+                    status,
+                    error: error.map(str::to_string),
+                    updated: outcome.as_ref().is_ok_and(|result| result.updated),
+                    bytes: outcome.as_ref().map_or(0, |result| result.body_bytes),
+                    elapsed,
+                    timings,
+                });
+                let opened = match probe {
+                    Some(probe) => probe.record(is_host_failure(status)),
+                    None => breaker.record(&host, is_host_failure(status)),
+                };
+                if opened {
+                    warn!("Host keeps failing, deferring its feeds for {}s.", cool_down.as_secs());
+                }
+
+                match outcome {
+                    Ok(result) => {
+                        match result.updated {
+                            true => tally.updated.fetch_add(1, Ordering::SeqCst),
+                            false => tally.not_updated.fetch_add(1, Ordering::SeqCst),
+                        };
+                        match result.updated {
+                            true => info!(status, attempts = result.attempts, title = %podcast.title, url = %podcast.url, "Feed is updated."),
+                            false => info!(status, attempts = result.attempts, title = %podcast.title, url = %podcast.url, "Feed is NOT updated."),
+                        }
+                        if let Some(writer) = queue_writer {
+                            writer.record(queue_update_for(&podcast, &result));
                         }
                     }
                     Err(e) => {
-                        println!("ERROR downloading: [{}], {:#?}", podcast.url, e);
-                        if let Err(e) = write_feed_file(
-                            podcast.id,
-                            ERRORCODE_GENERAL_DOWNLOAD_FAILURE,
-                            0,
-                            "".to_string(),
-                            podcast.url,
-                            &"".to_string()
-                        ) {
-                            eprintln!("Error writing download error feed file: {:#?}", e);
+                        tally.errors.fetch_add(1, Ordering::SeqCst);
+                        if let Some(writer) = queue_writer {
+                            writer.record(QueueUpdate {
+                                id: podcast.id,
+                                checked_at: unix_now(),
+                                status: e.status_code(),
+                                last_modified: podcast.last_modified,
+                                etag: podcast.etag.clone(),
+                                new_url: None,
+                                outcome: CheckOutcome::Unknown,
+                                hints: ScheduleHints::default(),
+                            });
                         }
                     },
                 }
-            }
+            }.instrument(span)
         })
-    ).buffer_unordered(100).collect::<Vec<()>>();
-    fetches.await;
-    Ok(())
+    });
+    futures::future::join_all(fetches).await;
+
+    let finished = tally.updated.load(Ordering::SeqCst)
+        + tally.not_updated.load(Ordering::SeqCst)
+        + tally.errors.load(Ordering::SeqCst)
+        + tally.deferred.load(Ordering::SeqCst);
+    tally.skipped.store(tally.queued - finished, Ordering::SeqCst);
+    tally.duration = started.elapsed();
+    record_metrics(ctx, |metrics| metrics.set_queue_depth(0));
+    tally
 }
 
 
-//##: Get a list of podcasts from the downloaded sqlite db
-fn get_feeds_from_sql(sqlite_file: &str) -> Result<Vec<Podcast>, Box<dyn Error>> {
-    //Locals
-    let mut podcasts: Vec<Podcast> = Vec::new();
-
-    //Restrict to feeds that have updated in a reasonable amount of time
-    let _since_time: u64 = match SystemTime::now().duration_since(UNIX_EPOCH) {
-        Ok(n) => n.as_secs() - (86400 * 90),
-        Err(_) => panic!("SystemTime before UNIX EPOCH!"),
+//##: Record a feed that was put off because its host's circuit is open. Nothing is written back
+//##: to the queue, since the feed wasn't actually checked.
+async fn defer_feed(ctx: &FetchContext, podcast: &Podcast, host: &str, tally: &RunTally) {
+    info!(title = %podcast.title, url = %podcast.url, "Feed deferred, host is failing.");
+    tally.deferred.fetch_add(1, Ordering::SeqCst);
+    let e = FetchError::HostDeferred(host.to_string());
+    let etag = match podcast.etag.as_str() {
+        "" => "[[NO_ETAG]]",
+        etag => etag,
     };
+    if let Err(e) = write_error_file(ctx, podcast.id, &e, podcast.last_modified, etag, &podcast.url, &[]).await {
+        error!(error = %e, "Error writing deferred feed file");
+    }
+}
+
+
+//##: The span every log line about one feed is made in. Each request attempt gets its own span inside it.
+fn feed_span(podcast: &Podcast) -> Span {
+    info_span!("feed", feed_id = podcast.id, host = %host_key(&podcast.url))
+}
 
+
+//##: Add to the report of the feed being checked, if one is being kept
+fn note_report(ctx: &FetchContext, fill: impl FnOnce(&mut FeedReport)) {
+    if let Some(report) = &ctx.report {
+        fill(&mut report.lock().unwrap());
+    }
+}
+
+
+//##: Update the metrics, if they're being kept
+fn record_metrics(ctx: &FetchContext, record: impl FnOnce(&Metrics)) {
+    if let Some(metrics) = &ctx.metrics {
+        record(metrics);
+    }
+}
+
+
+//##: Current unix time in seconds
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+
+//##: Work out what to persist for a checked feed. Validators only change when the response
+//##: actually carried a representation (or a 304 refreshed them); error responses keep the
+//##: previous ones so the next run can still make a conditional request.
+fn queue_update_for(podcast: &Podcast, result: &PodcastCheckResult) -> QueueUpdate {
+    let response_etag = if result.etag == "[[NO_ETAG]]" { None } else { Some(result.etag.clone()) };
+    let (last_modified, etag) = match result.status_code {
+        200 | 203 | 214 | 204 => (result.last_modified_timestamp, response_etag.unwrap_or_default()),
+        304 => (result.last_modified_timestamp, response_etag.unwrap_or_else(|| podcast.etag.clone())),
+        _ => (podcast.last_modified, podcast.etag.clone()),
+    };
+    QueueUpdate {
+        id: podcast.id,
+        checked_at: unix_now(),
+        status: result.status_code,
+        last_modified,
+        etag,
+        new_url: result.permanent_url.clone(),
+        outcome: result.outcome.clone(),
+        hints: result.hints.clone(),
+    }
+}
+
+
+//##: Get a list of podcasts from the downloaded sqlite db
+fn get_feeds_from_sql(sqlite_file: &str, selection: &QueueSelection) -> Result<Vec<Podcast>, Box<dyn Error>> {
     //Connect to the PI sqlite database file
-    let sql = Connection::open(sqlite_file);
+    let sql = open_queue_db(sqlite_file);
     match sql {
         Ok(sql) => {
-            println!("----- Got some podcasts. -----\n");
-
             //Run the query and store the result
-            let sql_text: String = format!("SELECT id, \
-                                                   url, \
-                                                   title, \
-                                                   lastmod, \
-                                                   etag \
-                                            FROM podcasts \
-                                            ORDER BY id ASC");
-            let stmt = sql.prepare(sql_text.as_str());
-            match stmt {
-                Ok(mut dbresults) => {
-                    let podcast_iter = dbresults.query_map([], |row| {
-                        Ok(Podcast {
-                            id: row.get(0).unwrap(),
-                            url: row.get(1).unwrap(),
-                            title: row.get(2).unwrap(),
-                            last_modified: row.get(3).unwrap(),
-                            etag: row.get(4).unwrap(),
-                        })
-                    }).unwrap();
-
-                    //Iterate the list and store
-                    for podcast in podcast_iter {
-                        let pod: Podcast = podcast.unwrap();
-                        podcasts.push(pod);
-                    }
-                }
-                Err(e) => return Err(Box::new(HydraError(format!("Error running SQL query: [{}]", e).into())))
-            }
+            let podcasts = match select_feeds(&sql, selection, unix_now()) {
+                Ok(podcasts) => podcasts,
+                Err(e) => return Err(Box::new(HydraError(format!("Error running SQL query: [{}]", e))))
+            };
+            info!("Got {} podcasts.", podcasts.len());
 
             //sql.close();
 
-            return Ok(podcasts);
+            Ok(podcasts)
         }
-        Err(e) => return Err(Box::new(HydraError(format!("Error running SQL query: [{}]", e).into())))
+        Err(e) => Err(Box::new(HydraError(format!("Error running SQL query: [{}]", e))))
     }
 }
 
 
-//##: Do a conditional request if possible, using the etag and last-modified values from the previous run
-async fn check_feed_is_updated(url: &str, etag: &str, last_modified: u64, feed_id: u64, signer: Option<&WebBotAuthSigner>) -> Result<bool, Box<dyn Error>> {
-    //let mut podcast_check_result: PodcastCheckResult;
+//##: Do a conditional request if possible, using the etag and last-modified values from the previous run.
+//##: Where the time went in the final request, and the address it went to, are left in `timings`
+//##: whether or not the check succeeds. The run's `slots`, if any, are given back while waiting to retry.
+async fn check_feed_is_updated(
+    ctx: &FetchContext,
+    url: &str,
+    etag: &str,
+    last_modified: u64,
+    feed_id: u64,
+    timings: &mut Timings,
+    mut slots: Option<&mut CheckSlots<'_>>,
+) -> Result<PodcastCheckResult, FetchError> {
 
-    //Build the initial query headers
+    //Build the per-request conditional headers. The User-Agent and Accept headers are
+    //defaults on the shared client.
     let mut headers = header::HeaderMap::new();
-    headers.insert("User-Agent", header::HeaderValue::from_static(USERAGENT));
-
-    //Advertise the feed formats we actually want. Some origins/WAFs reject requests
-    //that send no Accept header (reqwest sends none by default) with a 415; a real
-    //browser always sends one. This won't defeat IP-based bot challenges, but it
-    //fixes feeds whose front-end requires a sane Accept.
-    headers.insert("Accept", header::HeaderValue::from_static(
-        "application/rss+xml, application/atom+xml, application/xml;q=0.9, text/xml;q=0.9, */*;q=0.8"
-    ));
 
     //Create an http header compatible timestamp value to send with the conditional request based on
     //the `last_modified` of the feed we're checking
@@ -243,106 +1467,195 @@ async fn check_feed_is_updated(url: &str, etag: &str, last_modified: u64, feed_i
         let ts_secs = Duration::from_secs(last_modified);
         let ts = SystemTime::UNIX_EPOCH.checked_add(ts_secs).unwrap();
         let if_modified_since_time = httpdate::fmt_http_date(ts);
-        println!("  [{}|{}] If-Modified-Since: {:?}", feed_id, last_modified, if_modified_since_time);
+        debug!(last_modified, "If-Modified-Since: {}", if_modified_since_time);
         headers.insert("If-Modified-Since", header::HeaderValue::from_str(if_modified_since_time.as_str()).unwrap());
     }
 
     //Create an http header compatible etag value to send with the conditional request based on
     //the `etag` of the feed we're checking
     if !etag.is_empty() {
-        println!("  [{}] If-None-Match: {:?}", feed_id, etag);
-        headers.insert("If-None-Match", header::HeaderValue::from_str(etag).unwrap());
+        debug!("If-None-Match: {}", etag);
+        if let Ok(value) = header::HeaderValue::from_str(etag) {
+            headers.insert("If-None-Match", value);
+        }
     }
 
-    //Custom redirect policy so we can intercept redirect requests
-    let feed_id2 = feed_id.clone();
-    let custom = redirect::Policy::custom(move |attempt| {
-        let status_code = attempt.status().as_u16();
+    note_report(ctx, |report| {
+        report.request_headers = headers.iter()
+            .map(|(name, value)| (name.to_string(), value.to_str().unwrap_or("<binary>").to_string()))
+            .collect();
+    });
 
-        //Bail out if we reach 10 or more redirects
-        if attempt.previous().len() > 9 {
-            return attempt.error("Error - Too many redirects");
-        }
+    //Send the request, following redirects by hand so each hop is recorded for this feed. Rate
+    //limits, gateway errors, timeouts and resets are retried with backoff (or as long as the
+    //server's Retry-After asks) until the retry policy runs out.
+    let mut hops: Vec<RedirectHop> = Vec::new();
+    let mut attempts: u32 = 0;
+    let started = Instant::now();
+    let mut attempt_started;
+    let mut attempt_span;
+    let mut retry_wait = Duration::ZERO;
+    let mut phases = RequestPhases::default();
+    let response = loop {
+        attempts += 1;
+        attempt_span = info_span!("attempt", attempt = attempts);
+        hops.clear();
+        attempt_started = Instant::now();
+        record_metrics(ctx, |metrics| metrics.request_sent(ctx.signer.is_some()));
+        let response = send_following_redirects(
+            &ctx.client,
+            url,
+            &headers,
+            ctx.signer.as_deref(),
+            ctx.request_timeout,
+            &mut hops,
+            &mut phases,
+        ).instrument(attempt_span.clone()).await;
 
-        //If this is a permanent redirect, drop a stub file so that the parser can come by later
-        //and pick up these url changes
-        if status_code == 301 || status_code == 308 {
-            if let Err(e) = write_feed_file(
-                feed_id2,
-                status_code,
-                0,
-                "".to_string(),
-                attempt.url().to_string(),
-                &"".to_string()
-            ) {
-                eprintln!("Error writing redirect file: {:#?}", e);
+        let (retry_after, why) = match &response {
+            Ok(res) if retry::is_retryable_status(res.status().as_u16()) => {
+                (retry::retry_after(res.headers()), res.status().as_u16().to_string())
             }
+            Err(e) if e.is_transient() => (None, e.reason().to_string()),
+            _ => break response,
+        };
+        match ctx.retry.next_delay(attempts, started.elapsed(), retry_after) {
+            Some(delay) => {
+                attempt_span.in_scope(|| info!(why = %why, "Attempt failed, retrying in {}ms.", delay.as_millis()));
+                //Nothing to send while waiting, so other feeds can have our slots meanwhile
+                if let Some(slots) = slots.as_mut() {
+                    slots.give_back();
+                }
+                //A stop shouldn't have to wait out the backoff; the failed attempt stands instead
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => retry_wait += delay,
+                    _ = ctx.signals.stopped() => {
+                        attempt_span.in_scope(|| info!(why = %why, "Stopping, so not retrying."));
+                        break response;
+                    }
+                }
+                if let Some(slots) = slots.as_mut() {
+                    slots.take().await;
+                }
+            }
+            None => break response,
         }
+    };
 
-        //Keep going
-        attempt.follow()
-    });
+    //Everything from here on is about the attempt that had the final say
+    *timings = phases.timings();
+    timings.total = Some(attempt_started.elapsed());
+    let last = FinalAttempt { response, attempts, hops, started: attempt_started, retry_wait };
+    finish_check(ctx, url, last_modified, feed_id, timings, last).instrument(attempt_span).await
+}
+
+
+//The attempt that had the final say in a check, and what it took to get there
+struct FinalAttempt {
+    response: Result<reqwest::Response, FetchError>,
+    attempts: u32,
+    hops: Vec<RedirectHop>,
+    started: Instant,
+    retry_wait: Duration,
+}
 
-    //Build the query client
-    let client = reqwest::Client::builder()
-        .use_rustls_tls()
-        .connect_timeout(Duration::from_secs(20))
-        .timeout(Duration::from_secs(30))
-        .pool_idle_timeout(Duration::from_secs(20))
-        .default_headers(headers)
-        .gzip(true)
-        .redirect(custom)
-        .build()
-        .unwrap();
+
+//Record the final attempt of a check: its redirect stubs, then the feed file for the response
+//(or the error), and the values to store for the next run
+async fn finish_check(
+    ctx: &FetchContext,
+    url: &str,
+    last_modified: u64,
+    feed_id: u64,
+    timings: &mut Timings,
+    last: FinalAttempt,
+) -> Result<PodcastCheckResult, FetchError> {
+    let FinalAttempt { response, attempts, hops, started: attempt_started, retry_wait } = last;
 
     //Default response header values to use in case we can't get something during
     //the request. These are safe fallbacks.
     let mut r_etag = "[[NO_ETAG]]".to_string();
     let mut r_modified = last_modified;
-    let mut r_url = url.to_string();
-
-    //Attach Web Bot Auth signature headers per-request (the signature binds the
-    //target @authority and a created/expires window, so it cannot be a client
-    //default header). On any error we simply send the request unsigned.
-    let mut req = client.get(url);
-    if let Some(signer) = signer {
-        if let Ok(parsed) = reqwest::Url::parse(url) {
-            if let Ok(now) = SystemTime::now().duration_since(UNIX_EPOCH) {
-                for (name, value) in signer.sign(&parsed, now.as_secs()) {
-                    req = req.header(name, value);
-                }
-            }
+    let mut r_modified_string = "".to_string();
+
+    note_report(ctx, |report| {
+        report.attempts = attempts;
+        report.hops = hops.clone();
+        report.response_time = attempt_started.elapsed();
+        report.retry_wait = retry_wait;
+        report.timings = *timings;
+    });
+
+    //Every file for this check carries the attempt count and the whole redirect chain, so the
+    //parser can audit url changes, and where the time went so slow hosts stand out
+    let mut check_lines: Vec<(&str, String)> = vec![("Attempts", attempts.to_string())];
+    check_lines.extend(hops.iter().map(|hop| ("Redirect", format!("{} {}", hop.status, hop.location))));
+    if let Some(addr) = timings.remote_addr {
+        check_lines.push(("Remote-Addr", addr.to_string()));
+    }
+    check_lines.push((sink::TIMING_LINE, timings.to_string()));
+
+    //The feed's new home is as far as the permanent redirects from the original url go. A
+    //temporary hop ends it, so we never move a feed to an intermediate or temporary url.
+    //Drop a single stub file for it so that the parser can come by later and pick it up.
+    let permanent_url = permanent_destination(&hops).map(|hop| hop.location.to_string());
+    if let Some(destination) = permanent_destination(&hops) {
+        info!(location = %destination.location, "Permanently moved.");
+        check_lines.push(("Canonical-Url", destination.location.to_string()));
+        let mut stub_lines = vec![("Redirect-Source", "http".to_string())];
+        stub_lines.extend_from_slice(&check_lines);
+        if let Err(e) = write_feed_file(
+            ctx,
+            feed_id,
+            destination.status,
+            0,
+            "",
+            destination.location.as_str(),
+            &stub_lines,
+        ).await {
+            error!(error = %e, "Error writing redirect file");
+        } else {
+            record_metrics(ctx, |metrics| metrics.redirect_stub_written("http"));
         }
     }
-    let response = req.send().await;
+
     match response {
         Ok(res) => {
-            println!("  Response Status: [{}]", res.status());
+            debug!(status = res.status().as_u16(), "Response received.");
             let response_http_status = res.status().as_u16();
+            let r_url = res.url().to_string();
+            note_report(ctx, |report| {
+                report.status = Some(response_http_status);
+                report.final_url = r_url.clone();
+                report.response_headers = res.headers().iter()
+                    .map(|(name, value)| (name.to_string(), value.to_str().unwrap_or("<binary>").to_string()))
+                    .collect();
+            });
 
-            //Default header values
-            r_etag = "[[NO_ETAG]]".to_string();
-            r_modified = last_modified;
-            r_url = res.url().to_string();
+            //Freshness hints for the scheduler. The feed's own hints are added once the body is read.
+            let mut hints = schedule::hints_from_headers(res.headers(), SystemTime::now());
+            let mut outcome = CheckOutcome::Unknown;
+            let mut body_bytes = 0;
 
             //Change detection using headers
             for (key, val) in res.headers().into_iter() {
                 if key == "last-modified" && !val.is_empty() {
-                    println!("  Last-Modified: {:#?}", val);
+                    debug!("Last-Modified: {:?}", val);
 
                     //See if we can get a parseable date-time string from the header value, and if
                     //so, try to parse that to a unix epoch value for storing
                     if let Ok(headerval) = val.to_str() {
+                        r_modified_string = headerval.to_string();
                         if let Ok(timestamp) = httpdate::parse_http_date(headerval) {
                             if let Ok(systime) = timestamp.duration_since(UNIX_EPOCH) {
                                 r_modified = systime.as_secs();
-                                println!("  r_modified: {:#?}", r_modified);
+                                debug!(r_modified, "Parsed Last-Modified.");
                             }
                         }
                     }
                 }
                 if key == "etag" && !val.is_empty() {
-                    println!("  ETag: {:#?}", val);
+                    debug!("ETag: {:?}", val);
 
                     //If there is a sane value here, that's our guy so we extract it
                     if let Ok(headerval) = val.to_str() {
@@ -351,116 +1664,368 @@ async fn check_feed_is_updated(url: &str, etag: &str, last_modified: u64, feed_i
                 }
             }
 
-            //Take appropriate action depending on the response status
-            let mut body = "".to_string();
-            match response_http_status {
+            //Take appropriate action depending on the response status. A body over the size
+            //limit isn't stored, so the check records the size exceeded status instead.
+            let mut status_code = response_http_status;
+            let updated = match response_http_status {
                 //Standard OK (perhaps with a transform) - response body included
                 200 | 203 | 214 => {
-                    body = res.text_with_charset("utf-8").await?; //TODO: handle errors
-                    if let Err(e) = write_feed_file(feed_id, response_http_status, r_modified, r_etag, r_url, &body) {
-                        eprintln!("Error writing OK feed file: {:#?}", e);
+                    //The body's own times aren't known until it is in, so the sink gets them at commit
+                    timings.total = None;
+                    set_timing_line(&mut check_lines, timings);
+                    let body_started = Instant::now();
+                    let streamed = stream_feed_file(ctx, res, feed_id, r_modified, &r_etag, &r_url, &check_lines)
+                        .instrument(info_span!("body"))
+                        .await;
+                    timings.body = Some(body_started.elapsed());
+                    timings.total = Some(attempt_started.elapsed());
+                    note_report(ctx, |report| report.timings = *timings);
+                    set_timing_line(&mut check_lines, timings);
+                    let streamed = match streamed {
+                        Ok(StreamedBody::Complete(mut summary)) => match summary.file.take() {
+                            Some(feed_file) => finish_body_file(ctx, feed_file, timings).await.map(|_| StreamedBody::Complete(summary)),
+                            None => Ok(StreamedBody::Complete(summary)),
+                        },
+                        other => other,
+                    };
+                    match streamed {
+                        Ok(StreamedBody::Complete(summary)) => {
+                            debug!(bytes = summary.length, "Content downloaded.");
+                            body_bytes = summary.length as u64;
+                            record_metrics(ctx, |metrics| metrics.body_downloaded(body_bytes));
+                            hints.merge(summary.hints);
+                            outcome = CheckOutcome::Fetched(Some(summary.hash));
+
+                            //The feed may say it has moved without the server ever redirecting. Leave a
+                            //stub for that, unless it just names where we already are, but don't write it
+                            //back to the queue: only the server moving the feed changes its url.
+                            let current = [url, r_url.as_str(), permanent_url.as_deref().unwrap_or(url)];
+                            if let Some(feed_move) = summary.feed_move.filter(|feed_move| feed_move.is_elsewhere(&current)) {
+                                info!(location = %feed_move.url, signal = feed_move.signal.as_str(), "Feed content says it moved.");
+                                let mut stub_lines = vec![
+                                    ("Redirect-Source", format!("content; signal={}", feed_move.signal.as_str())),
+                                ];
+                                stub_lines.extend_from_slice(&check_lines);
+                                if let Err(e) = write_feed_file(ctx, feed_id, CONTENT_MOVE_STATUS, 0, "", feed_move.url.as_str(), &stub_lines).await {
+                                    error!(error = %e, "Error writing content redirect file");
+                                } else {
+                                    record_metrics(ctx, |metrics| metrics.redirect_stub_written("content"));
+                                }
+                            }
+                            true
+                        },
+                        Ok(StreamedBody::TooLarge(length)) => {
+                            warn!(bytes = length, limit = ctx.max_body_length, "Content too large, not stored.");
+                            body_bytes = length as u64;
+                            let e = FetchError::SizeExceeded(length as u64);
+                            status_code = e.status_code();
+                            record_metrics(ctx, |metrics| metrics.size_exceeded());
+                            if let Err(e) = write_error_file(ctx, feed_id, &e, r_modified, &r_etag, &r_url, &check_lines).await {
+                                error!(error = %e, "Error writing size exceeded feed file");
+                            }
+                            false
+                        }
+                        Err(e) => {
+                            warn!(status = e.status_code(), reason = e.reason(), url = %r_url, error = %e, "Error downloading feed.");
+                            if let Err(e) = write_error_file(ctx, feed_id, &e, r_modified, &r_etag, &r_url, &check_lines).await {
+                                error!(error = %e, "Error writing download error feed file");
+                            }
+                            return Err(e);
+                        }
                     }
-                    println!("  - Content downloaded.");
-                    return Ok(true);
                 },
                 //No content - no response body
                 204 => {
-                    if let Err(e) = write_feed_file(feed_id, response_http_status, r_modified, r_etag, r_url, &body) {
-                        eprintln!("Error writing 204 feed file: {:#?}", e);
+                    if let Err(e) = write_feed_file(ctx, feed_id, response_http_status, r_modified, &r_etag, &r_url, &check_lines).await {
+                        error!(error = %e, "Error writing 204 feed file");
                     }
-                    println!("  - No content.");
-                    return Ok(true);
+                    debug!("No content.");
+                    true
                 },
                 //Content not modified - no response body
                 304 => {
-                    if let Err(e) = write_feed_file(feed_id, response_http_status, r_modified, r_etag, r_url, &body) {
-                        eprintln!("Error writing 304 feed file: {:#?}", e);
+                    if let Err(e) = write_feed_file(ctx, feed_id, response_http_status, r_modified, &r_etag, &r_url, &check_lines).await {
+                        error!(error = %e, "Error writing 304 feed file");
                     }
-                    println!("  - Content not modified.");
-                    return Ok(false);
+                    debug!("Content not modified.");
+                    outcome = CheckOutcome::NotModified;
+                    false
                 },
                 //Request error - no response body
                 400..=499 => {
-                    if let Err(e) = write_feed_file(feed_id, response_http_status, r_modified, r_etag, r_url, &body) {
-                        eprintln!("Error writing client error feed file: {:#?}", e);
+                    if let Err(e) = write_feed_file(ctx, feed_id, response_http_status, r_modified, &r_etag, &r_url, &check_lines).await {
+                        error!(error = %e, "Error writing client error feed file");
                     }
-                    println!("  - Request error.");
-                    return Ok(false);
+                    debug!("Request error.");
+                    false
                 },
                 //Server error - no response body
                 500..=999 => {
-                    if let Err(e) = write_feed_file(feed_id, response_http_status, r_modified, r_etag, r_url, &body) {
-                        eprintln!("Error writing server feed file: {:#?}", e);
+                    if let Err(e) = write_feed_file(ctx, feed_id, response_http_status, r_modified, &r_etag, &r_url, &check_lines).await {
+                        error!(error = %e, "Error writing server feed file");
                     }
-                    println!("  - Server error.");
-                    return Ok(false);
+                    debug!("Server error.");
+                    false
                 },
                 //Something else that we don't handle
                 _ => {
-                    if let Err(e) = write_feed_file(feed_id, response_http_status, r_modified, r_etag, r_url, &body) {
-                        eprintln!("Error writing server feed file: {:#?}", e);
+                    if let Err(e) = write_feed_file(ctx, feed_id, response_http_status, r_modified, &r_etag, &r_url, &check_lines).await {
+                        error!(error = %e, "Error writing server feed file");
                     }
-                    println!("  - Unhandled status code.");
-                    return Ok(false);
+                    debug!("Unhandled status code.");
+                    false
                 }
-            }
+            };
+
+            Ok(PodcastCheckResult {
+                id: feed_id,
+                url: r_url,
+                updated,
+                status_code,
+                last_modified_string: r_modified_string,
+                last_modified_timestamp: r_modified,
+                etag: r_etag,
+                permanent_url,
+                attempts,
+                body_bytes,
+                outcome,
+                hints,
+            })
         }
         Err(e) => {
-            eprintln!("Error: [{}]", e);
-            if let Err(e) = write_feed_file(
-                feed_id,
-                ERRORCODE_GENERAL_CONNECTION_FAILURE,
-                r_modified,
-                r_etag,
-                r_url,
-                &"".to_string()
-            ) {
-                eprintln!("Error writing connection error feed file: {:#?}", e);
+            warn!(status = e.status_code(), reason = e.reason(), url = %url, error = %e, "Error downloading feed.");
+            if let Err(e) = write_error_file(ctx, feed_id, &e, r_modified, &r_etag, url, &check_lines).await {
+                error!(error = %e, "Error writing connection error feed file");
             }
-            return Err(Box::new(HydraError(format!("Error downloading feed: [{}]", e).into())));
+            Err(e)
         }
     }
 }
 
 
-//Write a feed file out to the filesystem with metadata and body
-fn write_feed_file(feed_id: u64, status_code: u16, r_modified: u64, r_etag: String, r_url: String, body: &String)
-    -> Result<bool, Box<dyn Error>>
-{
-    //Holds the current status code, which can change later
-    let mut status_code_prefix = status_code;
-
+//Start a result in the sink. For feed files the first four header lines are fixed
+//(last-modified, etag, url, time written); any `extra` lines follow them as
+//`X-Aggrivator-<name>: <value>` unless the legacy four line header has been asked for.
+//Nothing appears in the sink until the result is committed.
+async fn create_feed_file(
+    ctx: &FetchContext,
+    feed_id: u64,
+    status_code: u16,
+    r_modified: u64,
+    r_etag: &str,
+    r_url: &str,
+    extra: &[(&str, String)],
+) -> std::io::Result<FeedFile> {
     //What time is it now
     let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
 
-    //If the body exceeds the maximum we're willing to handle, rename the file as an error code file
-    let body_length =  body.len();
-    println!("Body length: {}\n", body_length);
-    if body_length > MAX_BODY_LENGTH {
-        status_code_prefix = ERRORCODE_GENERAL_FILE_SIZE_EXCEEDED;
-    }
+    let result = FeedResult {
+        feed_id,
+        status: status_code,
+        last_modified: r_modified,
+        etag: r_etag.to_string(),
+        url: r_url.to_string(),
+        checked_at: now,
+        lines: extra.iter().map(|(name, value)| (name.to_string(), value.clone())).collect(),
+    };
+    let pending = match &ctx.output {
+        Output::Sink(sink) => {
+            let (sink, begun) = (sink.clone(), result.clone());
+            Some(blocking(move || sink.begin(&begun)).await?)
+        }
+        Output::Report(_) | Output::Tally(_) => None,
+    };
+
+    Ok(FeedFile { pending, result, body_length: 0, batch: Vec::new() })
+}
 
-    //What directory to place this file in
-    let mut directory = "feeds";
-    if status_code_prefix == 301 || status_code_prefix == 308 {
-        directory = "redirects";
+
+//Commit a finished result to the sink (or just count it, when nothing is being written), and
+//say where it went
+async fn commit_feed_file(ctx: &FetchContext, mut feed_file: FeedFile) -> std::io::Result<Option<String>> {
+    feed_file.write_batch().await?;
+    let FeedFile { pending, result, body_length, .. } = feed_file;
+    let header = sink::file_header(&result, ctx.legacy_header);
+    let span = info_span!("write", status = result.status, bytes = header.len() + body_length);
+    if let Output::Tally(tally) = &ctx.output {
+        let mut by_status = tally.by_status.lock().unwrap();
+        let (files, bytes) = by_status.entry(result.status).or_default();
+        *files += 1;
+        *bytes += header.len() + body_length;
+        if result.is_redirect_stub() {
+            tally.redirect_stubs.fetch_add(1, Ordering::SeqCst);
+        }
     }
+    let written = pending.is_some();
+    let location = match (pending, &ctx.output) {
+        (Some(pending), _) => {
+            let finished = result.clone();
+            Some(blocking(move || pending.commit(&finished)).instrument(span).await?)
+        }
+        (None, Output::Report(sink)) => Some(sink.location(&result)),
+        (None, _) => None,
+    };
+    note_report(ctx, |report| report.files.push(ReportedFile { location: location.clone(), written, header, body_length }));
+    Ok(location)
+}
 
-    //The filename is the feed id and the http response status
-    let file_name = format!("{}/{}_{}.txt", directory, feed_id, status_code_prefix);
 
-    //Create the file TODO: Needs error checking on these unwraps
-    let mut feed_file = File::create(file_name)?;
-    feed_file.write_all(format!("{}\n", r_modified).as_bytes())?;
-    feed_file.write_all(format!("{}\n", r_etag).as_bytes())?;
-    feed_file.write_all(format!("{}\n", r_url).as_bytes())?;
-    feed_file.write_all(format!("{}\n", now).as_bytes())?;
+//Finish the Timing line of a streamed result with the body and total times, then commit it
+async fn finish_body_file(ctx: &FetchContext, mut feed_file: FeedFile, timings: &Timings) -> Result<(), FetchError> {
+    feed_file.result.set_line(sink::TIMING_LINE, timings.to_string());
+    commit_feed_file(ctx, feed_file)
+        .await
+        .map(|_| ())
+        .map_err(|e| FetchError::LocalWrite(format!("Error writing feed file: {}", e)))
+}
+
 
-    //If the body was too long, don't write it
-    if status_code_prefix != ERRORCODE_GENERAL_FILE_SIZE_EXCEEDED {
-        feed_file.write_all(body.as_bytes())?;
+//Swap the Timing line for one with the latest times in it
+fn set_timing_line(lines: &mut [(&str, String)], timings: &Timings) {
+    if let Some((_, value)) = lines.iter_mut().find(|(name, _)| *name == sink::TIMING_LINE) {
+        *value = timings.to_string();
     }
+}
+
+
+//Write a feed file with just the metadata header (redirect stubs, errors and bodyless responses)
+async fn write_feed_file(
+    ctx: &FetchContext,
+    feed_id: u64,
+    status_code: u16,
+    r_modified: u64,
+    r_etag: &str,
+    r_url: &str,
+    extra: &[(&str, String)],
+) -> std::io::Result<bool> {
+    let feed_file = create_feed_file(ctx, feed_id, status_code, r_modified, r_etag, r_url, extra).await?;
+    commit_feed_file(ctx, feed_file).await?;
+    Ok(true)
+}
 
 
+//Write a feed file for a failed fetch. The error's pseudo-status goes in the file name and
+//its reason and detail go in an `X-Aggrivator-Error` header line.
+async fn write_error_file(
+    ctx: &FetchContext,
+    feed_id: u64,
+    error: &FetchError,
+    r_modified: u64,
+    r_etag: &str,
+    r_url: &str,
+    extra: &[(&str, String)],
+) -> std::io::Result<bool> {
+    let mut lines = vec![("Error", format!("{}; {}", error.reason(), error.detail()))];
+    lines.extend_from_slice(extra);
+    let feed_file = create_feed_file(ctx, feed_id, error.status_code(), r_modified, r_etag, r_url, &lines).await?;
+    commit_feed_file(ctx, feed_file).await?;
     Ok(true)
-}
\ No newline at end of file
+}
+
+
+//Stream a response body into a feed file chunk by chunk, transcoding it to utf-8 as it arrives.
+//The encoding comes from a BOM, the Content-Type charset or the XML declaration (in that order)
+//and is recorded in the file header. The start of the text is also scanned for in-feed move
+//signals (itunes:new-feed-url and friends) and scheduling hints (ttl, sy:updatePeriod and friends),
+//and the text is hashed so the scheduler can tell a real change from a server that ignores
+//conditional requests. The file is left for the caller to finish and commit once the whole body
+//is on disk. If the body turns out to be bigger than the limit we stop downloading and drop the
+//partial file, leaving the caller to record the size exceeded status.
+async fn stream_feed_file(
+    ctx: &FetchContext,
+    mut res: reqwest::Response,
+    feed_id: u64,
+    r_modified: u64,
+    r_etag: &str,
+    r_url: &str,
+    extra: &[(&str, String)],
+) -> Result<StreamedBody, FetchError> {
+    let status_code = res.status().as_u16();
+
+    //Don't even start on a body that announces itself as too big
+    if let Some(length) = res.content_length() {
+        if length > ctx.max_body_length as u64 {
+            return Ok(StreamedBody::TooLarge(length as usize));
+        }
+    }
+
+    //Hold back the start of the body until there's enough of it to find an XML declaration
+    let content_type = res.headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string());
+    let mut pending: Vec<u8> = Vec::new();
+    let mut finished = false;
+    while pending.len() < encoding::SNIFF_LENGTH {
+        match res.chunk().await.map_err(|e| FetchError::from_reqwest(&e, true))? {
+            Some(bytes) => pending.extend_from_slice(&bytes),
+            None => {
+                finished = true;
+                break;
+            }
+        }
+        if pending.len() > ctx.max_body_length {
+            return Ok(StreamedBody::TooLarge(pending.len()));
+        }
+    }
+    let detected = encoding::detect(content_type.as_deref(), &pending);
+    debug!(encoding = detected.encoding.name(), source = detected.source.as_str(), "Detected body encoding.");
+    let mut decoder = detected.encoding.new_decoder();
+
+    let mut lines = vec![("Encoding", format!("{}; source={}", detected.encoding.name(), detected.source.as_str()))];
+    lines.extend_from_slice(extra);
+    let mut feed_file = create_feed_file(ctx, feed_id, status_code, r_modified, r_etag, r_url, &lines).await.map_err(|e| FetchError::LocalWrite(format!("Error creating feed file: {}", e)))?;
+    let mut body_length: usize = pending.len();
+    let mut text = String::new();
+    let mut first = true;
+    let mut scanner = MoveScanner::new();
+    let mut hasher = Sha256::new();
+
+    loop {
+        let bytes = match first {
+            true => std::mem::take(&mut pending).into(),
+            false => match res.chunk().await {
+                Ok(Some(bytes)) => bytes,
+                Ok(None) => {
+                    finished = true;
+                    Default::default()
+                }
+                Err(e) => return Err(FetchError::from_reqwest(&e, true)),
+            },
+        };
+        if !first {
+            body_length += bytes.len();
+        }
+        if body_length > ctx.max_body_length {
+            return Ok(StreamedBody::TooLarge(body_length));
+        }
+
+        text.clear();
+        text.reserve(decoder.max_utf8_buffer_length(bytes.len()).unwrap_or(bytes.len() * 3));
+        let _ = decoder.decode_to_string(&bytes, &mut text, finished);
+        scanner.push(&text);
+        hasher.update(text.as_bytes());
+
+        //The declaration is in the first stretch of text. Now that the body is utf-8 it
+        //has to say so, or a parser that honors it would decode the body a second time.
+        if first {
+            if let Some(relabeled) = encoding::relabel_xml_declaration(&text) {
+                text = relabeled;
+            }
+            first = false;
+        }
+        feed_file.write_body(text.as_bytes()).await.map_err(|e| FetchError::LocalWrite(format!("Error writing feed file: {}", e)))?;
+
+        if finished {
+            break;
+        }
+    }
+
+    Ok(StreamedBody::Complete(Box::new(BodySummary {
+        file: Some(feed_file),
+        length: body_length,
+        hints: schedule::hints_from_feed(scanner.channel_head()),
+        feed_move: scanner.finish(),
+        hash: format!("{:x}", hasher.finalize()),
+    })))
+}
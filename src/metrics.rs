@@ -0,0 +1,257 @@
+//! Prometheus metrics for the poller, and a small http server that exposes
+//! them at `/metrics` for scraping. Metrics only observe a run; nothing here
+//! changes what gets fetched or written.
+
+use std::convert::Infallible;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::body::Incoming;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use tokio::net::TcpListener;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+
+/// Upper bounds of the check duration buckets, in seconds.
+const DURATION_BUCKETS: &[f64] = &[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0];
+
+/// Every metric the poller keeps, registered in its own registry.
+pub struct Metrics {
+    registry: Registry,
+    checks: IntCounterVec,
+    in_flight: IntGauge,
+    check_duration: Histogram,
+    downloaded_bytes: IntCounter,
+    redirect_stubs: IntCounterVec,
+    size_exceeded: IntCounter,
+    requests: IntCounterVec,
+    queue_depth: IntGauge,
+}
+
+/// Holds a check in the in-flight gauge until dropped.
+pub struct InFlight<'a>(&'a IntGauge);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+        let checks = IntCounterVec::new(
+            Opts::new("aggrivator_checks_total", "Feed checks finished, by result status and error reason"),
+            &["status", "error"],
+        )
+        .unwrap();
+        let in_flight = IntGauge::new("aggrivator_checks_in_flight", "Feed checks in progress").unwrap();
+        let check_duration = Histogram::with_opts(
+            HistogramOpts::new("aggrivator_check_duration_seconds", "Time spent checking a feed, retries included")
+                .buckets(DURATION_BUCKETS.to_vec()),
+        )
+        .unwrap();
+        let downloaded_bytes = IntCounter::new("aggrivator_downloaded_bytes_total", "Feed body bytes downloaded").unwrap();
+        let redirect_stubs = IntCounterVec::new(
+            Opts::new("aggrivator_redirect_stubs_total", "Redirect stubs written, by where the move came from"),
+            &["source"],
+        )
+        .unwrap();
+        let size_exceeded = IntCounter::new("aggrivator_size_exceeded_total", "Bodies over the size limit (668)").unwrap();
+        let requests = IntCounterVec::new(
+            Opts::new("aggrivator_requests_total", "Requests sent, retries included, by whether they were signed"),
+            &["signed"],
+        )
+        .unwrap();
+        let queue_depth = IntGauge::new("aggrivator_queue_depth", "Feeds selected for this run and not started yet").unwrap();
+
+        registry.register(Box::new(checks.clone())).unwrap();
+        registry.register(Box::new(in_flight.clone())).unwrap();
+        registry.register(Box::new(check_duration.clone())).unwrap();
+        registry.register(Box::new(downloaded_bytes.clone())).unwrap();
+        registry.register(Box::new(redirect_stubs.clone())).unwrap();
+        registry.register(Box::new(size_exceeded.clone())).unwrap();
+        registry.register(Box::new(requests.clone())).unwrap();
+        registry.register(Box::new(queue_depth.clone())).unwrap();
+
+        Self {
+            registry,
+            checks,
+            in_flight,
+            check_duration,
+            downloaded_bytes,
+            redirect_stubs,
+            size_exceeded,
+            requests,
+            queue_depth,
+        }
+    }
+
+    /// A feed check is starting. It counts as in flight until the guard is dropped.
+    pub fn check_started(&self) -> InFlight<'_> {
+        self.in_flight.inc();
+        InFlight(&self.in_flight)
+    }
+
+    /// A feed check finished with `status`, or with the pseudo-status of an
+    /// error with reason `error`. Hosts are left out of the labels: a queue has
+    /// far too many of them, and the run summary already names the slow ones.
+    pub fn check_finished(&self, status: u16, error: Option<&str>, elapsed: Duration) {
+        self.checks.with_label_values(&[&status.to_string(), error.unwrap_or("")]).inc();
+        self.check_duration.observe(elapsed.as_secs_f64());
+    }
+
+    /// A request went out, with or without Web Bot Auth signature headers.
+    pub fn request_sent(&self, signed: bool) {
+        self.requests.with_label_values(&[if signed { "true" } else { "false" }]).inc();
+    }
+
+    pub fn body_downloaded(&self, bytes: u64) {
+        self.downloaded_bytes.inc_by(bytes);
+    }
+
+    /// A redirect stub was written. `source` is `http` or `content`.
+    pub fn redirect_stub_written(&self, source: &str) {
+        self.redirect_stubs.with_label_values(&[source]).inc();
+    }
+
+    pub fn size_exceeded(&self) {
+        self.size_exceeded.inc();
+    }
+
+    pub fn set_queue_depth(&self, depth: usize) {
+        self.queue_depth.set(depth as i64);
+    }
+
+    pub fn feed_dequeued(&self) {
+        self.queue_depth.dec();
+    }
+
+    /// Everything in the Prometheus text format.
+    pub fn encode(&self) -> String {
+        let mut buffer = Vec::new();
+        let encoder = TextEncoder::new();
+        //Writing to a Vec can't fail, and our metrics are always well formed
+        let _ = encoder.encode(&self.registry.gather(), &mut buffer);
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Serve `metrics` at `http://addr/metrics` in the background. Returns the
+/// address actually bound (useful with port 0). Has to be called from within
+/// the tokio runtime.
+pub fn spawn_server(metrics: Arc<Metrics>, addr: SocketAddr) -> io::Result<SocketAddr> {
+    let listener = std::net::TcpListener::bind(addr)?;
+    listener.set_nonblocking(true)?;
+    let listener = TcpListener::from_std(listener)?;
+    let bound = listener.local_addr()?;
+    tokio::spawn(async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    //Usually out of file descriptors, so give some a chance to close
+                    tracing::warn!(error = %e, "Metrics server couldn't accept a connection");
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            };
+            let metrics = metrics.clone();
+            tokio::spawn(async move {
+                let service = service_fn(move |req| respond(metrics.clone(), req));
+                if let Err(e) = http1::Builder::new().serve_connection(TokioIo::new(stream), service).await {
+                    tracing::debug!(error = %e, "Metrics connection failed");
+                }
+            });
+        }
+    });
+    Ok(bound)
+}
+
+async fn respond(metrics: Arc<Metrics>, req: Request<Incoming>) -> Result<Response<Full<Bytes>>, Infallible> {
+    let response = match (req.method(), req.uri().path()) {
+        (&Method::GET, "/metrics") => Response::builder()
+            .header("Content-Type", TextEncoder::new().format_type())
+            .body(Full::from(metrics.encode())),
+        _ => Response::builder().status(StatusCode::NOT_FOUND).body(Full::from("Not found\n")),
+    };
+    Ok(response.unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    #[test]
+    fn counts_checks_and_in_flight() {
+        let metrics = Metrics::new();
+        {
+            let _guard = metrics.check_started();
+            assert!(metrics.encode().contains("aggrivator_checks_in_flight 1"));
+            metrics.request_sent(true);
+            metrics.check_finished(200, None, Duration::from_millis(300));
+            metrics.body_downloaded(1234);
+        }
+        let _guard = metrics.check_started();
+        metrics.request_sent(false);
+        metrics.check_finished(670, Some("dns_nxdomain"), Duration::from_millis(5));
+        metrics.size_exceeded();
+        metrics.redirect_stub_written("http");
+        drop(_guard);
+
+        let text = metrics.encode();
+        assert!(text.contains("aggrivator_checks_in_flight 0"));
+        assert!(text.contains(r#"aggrivator_checks_total{error="",status="200"} 1"#));
+        assert!(text.contains(r#"aggrivator_checks_total{error="dns_nxdomain",status="670"} 1"#));
+        assert!(text.contains("aggrivator_check_duration_seconds_count 2"));
+        assert!(text.contains(r#"aggrivator_requests_total{signed="true"} 1"#));
+        assert!(text.contains(r#"aggrivator_requests_total{signed="false"} 1"#));
+        assert!(text.contains("aggrivator_downloaded_bytes_total 1234"));
+        assert!(text.contains("aggrivator_size_exceeded_total 1"));
+        assert!(text.contains(r#"aggrivator_redirect_stubs_total{source="http"} 1"#));
+    }
+
+    #[test]
+    fn tracks_queue_depth() {
+        let metrics = Metrics::new();
+        metrics.set_queue_depth(3);
+        metrics.feed_dequeued();
+        assert!(metrics.encode().contains("aggrivator_queue_depth 2"));
+    }
+
+    #[tokio::test]
+    async fn serves_metrics_over_http() {
+        let metrics = Arc::new(Metrics::new());
+        metrics.size_exceeded();
+        let addr = spawn_server(metrics, "127.0.0.1:0".parse().unwrap()).unwrap();
+
+        let get = |path: &'static str| async move {
+            let mut sock = TcpStream::connect(addr).await.unwrap();
+            let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", path);
+            sock.write_all(request.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            sock.read_to_string(&mut response).await.unwrap();
+            response
+        };
+        let response = get("/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert!(response.contains("aggrivator_size_exceeded_total 1"));
+        assert!(get("/other").await.starts_with("HTTP/1.1 404"));
+    }
+}
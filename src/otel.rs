@@ -0,0 +1,228 @@
+//! OpenTelemetry trace export over OTLP/HTTP. Each feed check's `feed` span
+//! becomes the root of a trace; the `attempt`, `hop`, `body` and `write` spans
+//! under it come across from tracing as they are, and each hop gets child spans
+//! for the phases in `timing`, built afterwards from the times measured.
+//! Spans go out through the poller's own reqwest client, so there's only the
+//! one HTTP and TLS stack to build and keep up to date.
+
+use std::convert::TryInto;
+use std::time::{Instant, SystemTime};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use http::{Request, Response};
+use opentelemetry::trace::{Span as _, TraceContextExt, Tracer, TracerProvider as _};
+use opentelemetry::global;
+use opentelemetry_http::{HttpClient, HttpError};
+use opentelemetry_otlp::{ExporterBuildError, Protocol, SpanExporter, WithExportConfig, WithHttpConfig};
+use opentelemetry_sdk::trace::{Sampler, SdkTracerProvider};
+use opentelemetry_sdk::Resource;
+use tracing::{Level, Subscriber};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::layer::Layer;
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::timing::RequestPhases;
+
+/// The instrumentation scope every span is reported under.
+const SCOPE: &str = "aggrivator";
+
+/// Where traces go and how many of them.
+#[derive(Debug, Clone)]
+pub struct ExportConfig {
+    /// The collector's OTLP/HTTP base url, e.g. `http://localhost:4318`.
+    /// `/v1/traces` is added unless it's already there.
+    pub endpoint: String,
+    /// Send JSON instead of protobuf.
+    pub json: bool,
+    /// The share of feed checks traced, from 0 to 1.
+    pub sample_ratio: f64,
+    pub service_name: String,
+}
+
+/// Sends the exporter's batches with `client`. The batch processor exports
+/// from a thread of its own, so the requests are run on the tokio runtime the
+/// provider was built on.
+#[derive(Debug)]
+struct Exporting {
+    client: reqwest::Client,
+    runtime: tokio::runtime::Handle,
+}
+
+#[async_trait]
+impl HttpClient for Exporting {
+    async fn send_bytes(&self, request: Request<Bytes>) -> Result<Response<Bytes>, HttpError> {
+        let client = self.client.clone();
+        let sent = self.runtime.spawn(async move {
+            let res = client.execute(request.try_into()?).await?;
+            let (status, headers) = (res.status(), res.headers().clone());
+            let mut response = Response::new(res.bytes().await?);
+            *response.status_mut() = status;
+            *response.headers_mut() = headers;
+            Ok::<_, reqwest::Error>(response)
+        });
+        Ok(sent.await??)
+    }
+}
+
+/// Build a provider that batches spans to the collector in the background,
+/// sending them with `client`. It has to be built from within the tokio
+/// runtime. Nothing is sent until spans are made through `layer` (or the
+/// global tracer once `install` has been called).
+pub fn provider(config: &ExportConfig, client: reqwest::Client) -> Result<SdkTracerProvider, ExporterBuildError> {
+    let runtime = tokio::runtime::Handle::try_current().map_err(|e| ExporterBuildError::InternalFailure(e.to_string()))?;
+    let endpoint = match config.endpoint.trim_end_matches('/') {
+        endpoint if endpoint.ends_with("/v1/traces") => endpoint.to_string(),
+        endpoint => format!("{}/v1/traces", endpoint),
+    };
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_http_client(Exporting { client, runtime })
+        .with_endpoint(endpoint)
+        .with_protocol(if config.json { Protocol::HttpJson } else { Protocol::HttpBinary })
+        .build()?;
+    Ok(SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(config.sample_ratio))))
+        .with_resource(Resource::builder().with_service_name(config.service_name.clone()).build())
+        .build())
+}
+
+/// Make `provider` the one the phase spans are made with.
+pub fn install(provider: &SdkTracerProvider) {
+    global::set_tracer_provider(provider.clone());
+}
+
+/// A layer sending the poller's spans to `provider`. It keeps its own filter,
+/// so traces don't depend on the log level.
+pub fn layer<S>(provider: &SdkTracerProvider) -> impl Layer<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    tracing_opentelemetry::layer()
+        .with_tracer(provider.tracer(SCOPE))
+        .with_filter(Targets::new().with_target(SCOPE, Level::INFO))
+}
+
+/// Add a child span to `hop` for each phase of its request. Does nothing
+/// unless `hop` is being traced.
+pub fn export_phases(hop: &tracing::Span, phases: &RequestPhases) {
+    let parent = hop.context();
+    if !parent.span().span_context().is_sampled() {
+        return;
+    }
+    let tracer = global::tracer(SCOPE);
+    let named = [("dns", phases.dns), ("connect", phases.connect), ("tls", phases.tls), ("request", phases.request)];
+    for (name, phase) in named {
+        if let Some(phase) = phase {
+            tracer
+                .span_builder(name)
+                .with_start_time(wall_clock(phase.start))
+                .start_with_context(&tracer, &parent)
+                .end_with_timestamp(wall_clock(phase.end));
+        }
+    }
+}
+
+/// The wall clock time at `at`, for spans made after the fact.
+fn wall_clock(at: Instant) -> SystemTime {
+    SystemTime::now() - at.elapsed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use crate::timing::Phase;
+
+    /// A collector stand-in: answers every request with a 200 and passes the
+    /// request line and body on.
+    fn collector() -> (String, mpsc::Receiver<(String, String)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let (sender, received) = mpsc::channel();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            length = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                stream.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n").unwrap();
+                let _ = sender.send((request_line.trim().to_string(), String::from_utf8_lossy(&body).to_string()));
+            }
+        });
+        (endpoint, received)
+    }
+
+    #[test]
+    fn exports_feed_spans_and_phases() {
+        let (endpoint, received) = collector();
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let _runtime = runtime.enter();
+        let client = crate::fetch::build_client("aggrivator-test").unwrap();
+        let provider = provider(
+            &ExportConfig {
+                endpoint,
+                json: true,
+                sample_ratio: 1.0,
+                service_name: "aggrivator-test".to_string(),
+            },
+            client,
+        )
+        .unwrap();
+        install(&provider);
+        let subscriber = tracing_subscriber::registry().with(layer(&provider));
+
+        tracing::subscriber::with_default(subscriber, || {
+            let feed = tracing::info_span!("feed", feed_id = 7u64);
+            let _feed = feed.enter();
+            let hop = tracing::info_span!("hop");
+            let start = Instant::now() - Duration::from_millis(50);
+            let phases = RequestPhases {
+                dns: Some(Phase { start, end: start + Duration::from_millis(5) }),
+                connect: Some(Phase { start: start + Duration::from_millis(5), end: start + Duration::from_millis(20) }),
+                tls: None,
+                request: Some(Phase { start: start + Duration::from_millis(20), end: start + Duration::from_millis(50) }),
+                ttfb: Some(Phase { start, end: start + Duration::from_millis(50) }),
+                remote_addr: None,
+            };
+            export_phases(&hop, &phases);
+        });
+        provider.force_flush().unwrap();
+
+        let (request_line, body) = received.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(request_line, "POST /v1/traces HTTP/1.1");
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let spans = json["resourceSpans"][0]["scopeSpans"][0]["spans"].as_array().unwrap();
+        let names: Vec<&str> = spans.iter().map(|span| span["name"].as_str().unwrap()).collect();
+        for name in ["feed", "hop", "dns", "connect", "request"] {
+            assert!(names.contains(&name), "{} missing from {:?}", name, names);
+        }
+        assert!(!names.contains(&"tls"));
+        let trace_ids: Vec<&str> = spans.iter().map(|span| span["traceId"].as_str().unwrap()).collect();
+        assert!(trace_ids.iter().all(|id| *id == trace_ids[0]));
+        provider.shutdown().unwrap();
+    }
+}
@@ -0,0 +1,628 @@
+//! The sqlite feed queue (`feed_poller_queue.db`): choosing which feeds to
+//! poll from the `podcasts` table, and persisting fetch outcomes back into it
+//! so validators improve from run to run.
+
+use std::error::Error;
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::schedule::{self, CheckOutcome, ScheduleConfig, ScheduleHints};
+
+const DAY_SECS: u64 = 86400;
+const HOUR_SECS: u64 = 3600;
+
+/// A feed picked from the queue for polling.
+#[derive(Debug, Clone)]
+pub struct Podcast {
+    pub id: u64,
+    pub url: String,
+    pub title: String,
+    pub last_modified: u64,
+    pub etag: String,
+}
+
+/// What to do with feeds flagged `dead` in the queue.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeadPolicy {
+    /// Poll them like any other feed.
+    Include,
+    /// Never poll them.
+    Skip,
+    /// Poll a random `percent` of them each run, and only those not checked in
+    /// the last `recheck_secs`, so a revived feed is eventually noticed.
+    Sample { percent: u8, recheck_secs: u64 },
+}
+
+/// How the selected feeds are ordered.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QueueOrder {
+    /// By feed id, the historical order.
+    Id,
+    /// Most recently active tier first, then by priority and popularity.
+    Priority,
+}
+
+/// Rules for building a run's poll queue from the `podcasts` table.
+///
+/// Feeds are tiered by the age of their `lastupdate`: active (updated within
+/// `active_days`), stale (within `stale_days`) and dormant (older). Stale and
+/// dormant feeds are only due once their minimum check interval has passed.
+#[derive(Debug, Clone)]
+pub struct QueueSelection {
+    pub dead: DeadPolicy,
+    /// Only select feeds whose `lastcheck + update_frequency` has passed.
+    pub only_due: bool,
+    /// Seconds represented by one unit of the `update_frequency` column.
+    pub update_frequency_unit_secs: u64,
+    pub active_days: u64,
+    pub stale_days: u64,
+    pub stale_interval_secs: u64,
+    pub dormant_interval_secs: u64,
+    pub order: QueueOrder,
+    /// Cap on the number of feeds selected for one run.
+    pub limit: Option<usize>,
+    /// Once a feed has a learned next-due time in the `schedule` table, use
+    /// that instead of the `lastcheck` based rules above.
+    pub adaptive: bool,
+}
+
+impl Default for QueueSelection {
+    /// Every feed in id order, exactly as the poller originally selected them.
+    /// Each of the other rules is opt-in; `due` turns them all on.
+    fn default() -> Self {
+        Self {
+            dead: DeadPolicy::Include,
+            only_due: false,
+            update_frequency_unit_secs: 1,
+            active_days: 90,
+            stale_days: 365,
+            stale_interval_secs: 0,
+            dormant_interval_secs: 0,
+            order: QueueOrder::Id,
+            limit: None,
+            adaptive: false,
+        }
+    }
+}
+
+impl QueueSelection {
+    /// Every feed in id order. The same as `default`.
+    pub fn everything() -> Self {
+        Self::default()
+    }
+
+    /// Only the feeds that are due: most dead feeds skipped, stale and dormant
+    /// feeds held to a day and a week between checks, the learned schedule
+    /// used where there is one, and the busiest feeds first.
+    pub fn due() -> Self {
+        Self {
+            dead: DeadPolicy::Sample {
+                percent: 5,
+                recheck_secs: 7 * DAY_SECS,
+            },
+            only_due: true,
+            stale_interval_secs: 24 * HOUR_SECS,
+            dormant_interval_secs: 7 * DAY_SECS,
+            order: QueueOrder::Priority,
+            adaptive: true,
+            ..Self::default()
+        }
+    }
+
+    /// The SELECT statement for these rules, and the named parameters it uses.
+    /// `with_schedule` says whether the database has a `schedule` table to join.
+    fn statement(&self, with_schedule: bool, now: u64) -> (String, Vec<(&'static str, i64)>) {
+        let mut params: Vec<(&'static str, i64)> = vec![
+            (":now", now as i64),
+            (":active_age", (self.active_days * DAY_SECS) as i64),
+            (":stale_age", (self.stale_days * DAY_SECS) as i64),
+        ];
+        let mut conditions: Vec<String> = Vec::new();
+        let mut due: Vec<&str> = Vec::new();
+        match self.dead {
+            DeadPolicy::Include => {}
+            DeadPolicy::Skip => conditions.push("COALESCE(dead, 0) = 0".to_string()),
+            DeadPolicy::Sample { percent, recheck_secs } => {
+                conditions.push(
+                    "(COALESCE(dead, 0) = 0 \
+                      OR (abs(random() % 100) < :dead_percent \
+                          AND COALESCE(lastcheck, 0) <= :now - :dead_recheck))"
+                        .to_string(),
+                );
+                params.push((":dead_percent", percent as i64));
+                params.push((":dead_recheck", recheck_secs as i64));
+            }
+        }
+        if self.only_due {
+            due.push("COALESCE(lastcheck, 0) + COALESCE(update_frequency, 0) * :frequency_unit <= :now");
+            params.push((":frequency_unit", self.update_frequency_unit_secs as i64));
+        }
+        if self.stale_interval_secs > 0 || self.dormant_interval_secs > 0 {
+            due.push(
+                "COALESCE(lastcheck, 0) <= :now - CASE tier WHEN 0 THEN 0 \
+                                                            WHEN 1 THEN :stale_interval \
+                                                            ELSE :dormant_interval END",
+            );
+            params.push((":stale_interval", self.stale_interval_secs as i64));
+            params.push((":dormant_interval", self.dormant_interval_secs as i64));
+        }
+        let scheduled = self.adaptive && with_schedule;
+        match (scheduled, due.is_empty()) {
+            //Feeds the scheduler hasn't seen yet fall back to the lastcheck rules
+            (true, false) => conditions.push(format!(
+                "CASE WHEN next_due IS NULL THEN ({}) ELSE next_due <= :now END",
+                due.join(" AND ")
+            )),
+            (true, true) => conditions.push("COALESCE(next_due, 0) <= :now".to_string()),
+            (false, _) => conditions.extend(due.iter().map(|condition| condition.to_string())),
+        }
+        let source = match scheduled {
+            true => "podcasts LEFT JOIN schedule ON schedule.podcast_id = podcasts.id",
+            false => "podcasts",
+        };
+        let filter = match conditions.is_empty() {
+            true => "".to_string(),
+            false => format!(" WHERE {}", conditions.join(" AND ")),
+        };
+
+        let order = match self.order {
+            QueueOrder::Id => "id ASC",
+            QueueOrder::Priority => "tier ASC, COALESCE(priority, 0) DESC, COALESCE(popularity, 0) DESC, id ASC",
+        };
+        let limit = match self.limit {
+            Some(limit) => format!(" LIMIT {}", limit),
+            None => "".to_string(),
+        };
+
+        let sql = format!(
+            "SELECT id, url, title, lastmod, etag FROM ( \
+                 SELECT *, CASE WHEN COALESCE(lastupdate, 0) >= :now - :active_age THEN 0 \
+                                WHEN COALESCE(lastupdate, 0) >= :now - :stale_age THEN 1 \
+                                ELSE 2 END AS tier \
+                 FROM {}){} \
+             ORDER BY {}{}",
+            source, filter, order, limit
+        );
+        (sql, params)
+    }
+}
+
+/// Run the queue selection against an open queue database.
+pub fn select_feeds(conn: &Connection, selection: &QueueSelection, now: u64) -> rusqlite::Result<Vec<Podcast>> {
+    let (sql, params) = selection.statement(selection.adaptive && schedule::has_schedule_table(conn)?, now);
+    let mut stmt = conn.prepare(&sql)?;
+    let bound: Vec<(&str, &dyn rusqlite::ToSql)> = params
+        .iter()
+        .map(|(name, value)| (*name, value as &dyn rusqlite::ToSql))
+        .collect();
+
+    let rows = stmt.query_map(bound.as_slice(), podcast_from_row)?;
+    rows.collect()
+}
+
+/// Look a single feed up by id or url, whether it's due or not.
+pub fn find_feed(conn: &Connection, id_or_url: &str) -> rusqlite::Result<Option<Podcast>> {
+    let id = id_or_url.parse::<i64>().unwrap_or(-1);
+    conn.query_row(
+        "SELECT id, url, title, lastmod, etag FROM podcasts WHERE id = ?1 OR url = ?2 ORDER BY id = ?1 DESC LIMIT 1",
+        params![id, id_or_url],
+        podcast_from_row,
+    )
+    .optional()
+}
+
+/// Rows are `id, url, title, lastmod, etag`.
+fn podcast_from_row(row: &rusqlite::Row) -> rusqlite::Result<Podcast> {
+    Ok(Podcast {
+        id: row.get(0)?,
+        url: row.get(1)?,
+        title: row.get(2)?,
+        last_modified: row.get::<_, Option<u64>>(3)?.unwrap_or(0),
+        etag: row.get(4)?,
+    })
+}
+
+/// How long buffered updates may wait before being committed, even when the
+/// batch isn't full, so a long run keeps the DB reasonably current.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The outcome of one feed check, as persisted to the queue.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueueUpdate {
+    pub id: u64,
+    /// Unix time the check finished.
+    pub checked_at: u64,
+    /// HTTP status, or one of the poller's 6xx pseudo-statuses.
+    pub status: u16,
+    /// Last-Modified to send next time (Unix seconds, 0 for none).
+    pub last_modified: u64,
+    /// ETag to send next time (empty for none).
+    pub etag: String,
+    /// New feed URL, only when a 301/308 redirect moved the feed. A move the
+    /// feed announces in its content only gets a stub for the parser.
+    pub new_url: Option<String>,
+    /// What the check says about how often the feed changes.
+    pub outcome: CheckOutcome,
+    pub hints: ScheduleHints,
+}
+
+/// How long a connection to the queue waits on another process's lock before
+/// giving up. The queue db is refreshed and read by other tools while we run.
+pub const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Open the queue database, waiting out other writers' locks for up to
+/// `BUSY_TIMEOUT` instead of failing straight away.
+pub fn open_queue_db(sqlite_file: &str) -> rusqlite::Result<Connection> {
+    let conn = Connection::open(sqlite_file)?;
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    Ok(conn)
+}
+
+/// Read the operator kill switch, `status.go`. A cleared flag (0) means no
+/// new fetches should be started. A queue without a status table or row is
+/// treated as go, so older databases keep working.
+pub fn read_go_flag(conn: &Connection) -> rusqlite::Result<bool> {
+    let has_status = conn.query_row(
+        "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = 'status'",
+        [],
+        |row| row.get::<_, i64>(0),
+    )?;
+    if has_status == 0 {
+        return Ok(true);
+    }
+    let go: Option<i64> = match conn.query_row("SELECT go FROM status LIMIT 1", [], |row| row.get(0)) {
+        Ok(go) => go,
+        Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(true),
+        Err(e) => return Err(e),
+    };
+    Ok(go != Some(0))
+}
+
+/// Batches `QueueUpdate`s onto a dedicated writer thread, committing each
+/// batch in a single transaction. With a `ScheduleConfig` it also keeps each
+/// feed's row in the `schedule` table up to date.
+pub struct QueueWriter {
+    sender: Option<Sender<QueueUpdate>>,
+    handle: Option<JoinHandle<Result<usize, String>>>,
+}
+
+impl QueueWriter {
+    /// Open `sqlite_file` and start the writer thread. The `schedule` table
+    /// is created if scheduling is on and it doesn't exist yet.
+    pub fn spawn(sqlite_file: &str, batch_size: usize, schedule: Option<ScheduleConfig>) -> Result<Self, Box<dyn Error>> {
+        let conn = open_queue_db(sqlite_file)?;
+        if schedule.is_some() {
+            conn.execute_batch(schedule::SCHEDULE_SCHEMA)?;
+        }
+        let (sender, receiver) = channel::<QueueUpdate>();
+        let batch_size = batch_size.max(1);
+
+        let handle = std::thread::spawn(move || {
+            let mut conn = conn;
+            let mut pending: Vec<QueueUpdate> = Vec::with_capacity(batch_size);
+            let mut written = 0;
+            loop {
+                match receiver.recv_timeout(FLUSH_INTERVAL) {
+                    Ok(update) => {
+                        pending.push(update);
+                        if pending.len() < batch_size {
+                            continue;
+                        }
+                    }
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => {
+                        written += commit_batch(&mut conn, &pending, schedule.as_ref()).map_err(|e| e.to_string())?;
+                        return Ok(written);
+                    }
+                }
+                written += commit_batch(&mut conn, &pending, schedule.as_ref()).map_err(|e| e.to_string())?;
+                pending.clear();
+            }
+        });
+
+        Ok(Self {
+            sender: Some(sender),
+            handle: Some(handle),
+        })
+    }
+
+    /// Queue an update for the next batch. Never blocks on the database.
+    pub fn record(&self, update: QueueUpdate) {
+        if let Some(sender) = &self.sender {
+            //The writer thread only hangs up after a failed commit, which `finish` reports
+            let _ = sender.send(update);
+        }
+    }
+
+    /// Flush everything still pending and wait for the writer thread. Returns
+    /// the number of rows written over the writer's lifetime.
+    pub fn finish(mut self) -> Result<usize, Box<dyn Error>> {
+        drop(self.sender.take());
+        match self.handle.take().map(|handle| handle.join()) {
+            Some(Ok(result)) => Ok(result?),
+            Some(Err(_)) => Err("queue writer thread panicked".into()),
+            None => Ok(0),
+        }
+    }
+}
+
+fn commit_batch(conn: &mut Connection, updates: &[QueueUpdate], schedule: Option<&ScheduleConfig>) -> rusqlite::Result<usize> {
+    if updates.is_empty() {
+        return Ok(0);
+    }
+    let tx = conn.transaction()?;
+    {
+        let mut update_check = tx.prepare_cached(
+            "UPDATE podcasts \
+             SET lastcheck = ?1, lasthttpstatus = ?2, lastmod = ?3, etag = ?4 \
+             WHERE id = ?5",
+        )?;
+        //The url column is UNIQUE: if another row already has the new url, leave
+        //this one alone rather than failing the whole batch
+        let mut update_url = tx.prepare_cached("UPDATE OR IGNORE podcasts SET url = ?1 WHERE id = ?2")?;
+        for update in updates {
+            update_check.execute(params![
+                update.checked_at as i64,
+                update.status,
+                update.last_modified as i64,
+                update.etag,
+                update.id as i64
+            ])?;
+            if let Some(new_url) = &update.new_url {
+                update_url.execute(params![new_url, update.id as i64])?;
+            }
+            if let Some(config) = schedule {
+                schedule::record_check(&tx, update.id, &update.outcome, &update.hints, update.checked_at, config)?;
+            }
+        }
+    }
+    tx.commit()?;
+    Ok(updates.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCHEMA: &str = "CREATE TABLE podcasts (
+        id INTEGER PRIMARY KEY, url TEXT NOT NULL UNIQUE, title TEXT NOT NULL,
+        lastupdate INTEGER, lastmod INTEGER, lastcheck INTEGER, lasthttpstatus INTEGER,
+        dead INTEGER, popularity INTEGER, priority INTEGER, update_frequency INTEGER,
+        etag TEXT NOT NULL);";
+
+    fn temp_db(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("aggrivator-{}-{}.db", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch(SCHEMA).unwrap();
+        conn.execute_batch(
+            "INSERT INTO podcasts (id, url, title, lastmod, etag) VALUES (1, 'https://a/feed', 'A', 0, '');
+             INSERT INTO podcasts (id, url, title, lastmod, etag) VALUES (2, 'https://b/feed', 'B', 0, '');",
+        )
+        .unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    const NOW: u64 = 1_800_000_000;
+
+    /// A queue with one feed per interesting case. Columns: id, lastupdate
+    /// age (days), lastcheck age (hours), dead, priority, update_frequency.
+    fn selection_db() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(SCHEMA).unwrap();
+        let feeds: [(u64, u64, u64, u8, i64, u64); 7] = [
+            (1, 1, 48, 0, 0, 3600),      //active, due
+            (2, 1, 48, 0, 5, 3600),      //active, due, high priority
+            (3, 1, 0, 0, 9, 3600),       //active, checked just now so not due
+            (4, 200, 12, 0, 0, 0),       //stale, inside its 24h interval
+            (5, 200, 30, 0, 0, 0),       //stale, past its interval
+            (6, 1000, 30, 0, 0, 0),      //dormant, inside its 7 day interval
+            (7, 1, 48, 1, 0, 0),         //dead
+        ];
+        for (id, update_days, check_hours, dead, priority, frequency) in feeds.iter() {
+            conn.execute(
+                "INSERT INTO podcasts (id, url, title, lastupdate, lastcheck, dead, priority, update_frequency, lastmod, etag) \
+                 VALUES (?1, ?2, 'T', ?3, ?4, ?5, ?6, ?7, NULL, '')",
+                params![
+                    *id as i64,
+                    format!("https://{}/feed", id),
+                    (NOW - update_days * DAY_SECS) as i64,
+                    (NOW - check_hours * HOUR_SECS) as i64,
+                    dead,
+                    priority,
+                    *frequency as i64
+                ],
+            )
+            .unwrap();
+        }
+        conn
+    }
+
+    fn selected_ids(conn: &Connection, selection: &QueueSelection) -> Vec<u64> {
+        select_feeds(conn, selection, NOW).unwrap().iter().map(|p| p.id).collect()
+    }
+
+    #[test]
+    fn go_flag_reads_status_table() {
+        let conn = Connection::open_in_memory().unwrap();
+        assert!(read_go_flag(&conn).unwrap());
+        conn.execute_batch("CREATE TABLE status (go INTEGER PRIMARY KEY);").unwrap();
+        assert!(read_go_flag(&conn).unwrap());
+        conn.execute_batch("INSERT INTO status VALUES (1);").unwrap();
+        assert!(read_go_flag(&conn).unwrap());
+        conn.execute_batch("UPDATE status SET go = 0;").unwrap();
+        assert!(!read_go_flag(&conn).unwrap());
+    }
+
+    #[test]
+    fn everything_selects_all_feeds_by_id() {
+        let conn = selection_db();
+        assert_eq!(selected_ids(&conn, &QueueSelection::everything()), vec![1, 2, 3, 4, 5, 6, 7]);
+        assert_eq!(selected_ids(&conn, &QueueSelection::default()), vec![1, 2, 3, 4, 5, 6, 7]);
+
+        //Even with a learned schedule, unless it's asked for
+        conn.execute_batch(schedule::SCHEDULE_SCHEMA).unwrap();
+        conn.execute("INSERT INTO schedule (podcast_id, poll_interval, next_due) VALUES (1, 3600, ?1)", params![(NOW + 60) as i64])
+            .unwrap();
+        assert_eq!(selected_ids(&conn, &QueueSelection::default()), vec![1, 2, 3, 4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn due_selection_skips_feeds_that_are_not_due() {
+        let conn = selection_db();
+        let selection = QueueSelection {
+            dead: DeadPolicy::Skip,
+            ..QueueSelection::due()
+        };
+        assert_eq!(selected_ids(&conn, &selection), vec![2, 1, 5]);
+    }
+
+    #[test]
+    fn dead_feeds_can_be_sampled_and_capped() {
+        let conn = selection_db();
+        let selection = QueueSelection {
+            dead: DeadPolicy::Sample {
+                percent: 100,
+                recheck_secs: DAY_SECS,
+            },
+            order: QueueOrder::Id,
+            limit: Some(3),
+            ..QueueSelection::due()
+        };
+        assert_eq!(selected_ids(&conn, &selection), vec![1, 2, 5]);
+
+        let selection = QueueSelection { limit: None, ..selection };
+        assert_eq!(selected_ids(&conn, &selection), vec![1, 2, 5, 7]);
+    }
+
+    #[test]
+    fn finds_feeds_by_id_or_url() {
+        let conn = selection_db();
+        assert_eq!(find_feed(&conn, "4").unwrap().map(|p| p.url), Some("https://4/feed".to_string()));
+        assert_eq!(find_feed(&conn, "https://6/feed").unwrap().map(|p| p.id), Some(6));
+        assert!(find_feed(&conn, "https://elsewhere/feed").unwrap().is_none());
+    }
+
+    #[test]
+    fn learned_schedule_overrides_lastcheck_rules() {
+        let conn = selection_db();
+        let selection = QueueSelection {
+            dead: DeadPolicy::Skip,
+            order: QueueOrder::Id,
+            ..QueueSelection::due()
+        };
+        //No schedule table yet, so only the lastcheck rules apply
+        assert_eq!(selected_ids(&conn, &selection), vec![1, 2, 5]);
+
+        conn.execute_batch(schedule::SCHEDULE_SCHEMA).unwrap();
+        for (id, next_due) in [(1, NOW + 60), (3, NOW - 60), (6, NOW)] {
+            conn.execute(
+                "INSERT INTO schedule (podcast_id, poll_interval, next_due) VALUES (?1, 3600, ?2)",
+                params![id, next_due as i64],
+            )
+            .unwrap();
+        }
+        assert_eq!(selected_ids(&conn, &selection), vec![2, 3, 5, 6]);
+
+        let selection = QueueSelection { adaptive: false, ..selection };
+        assert_eq!(selected_ids(&conn, &selection), vec![1, 2, 5]);
+    }
+
+    #[test]
+    fn writer_keeps_the_schedule() {
+        let path = temp_db("schedule");
+        let writer = QueueWriter::spawn(&path, 10, Some(ScheduleConfig::default())).unwrap();
+        writer.record(QueueUpdate {
+            id: 1,
+            checked_at: NOW,
+            status: 304,
+            last_modified: 0,
+            etag: "".to_string(),
+            new_url: None,
+            outcome: CheckOutcome::NotModified,
+            hints: ScheduleHints {
+                declared_secs: Some(HOUR_SECS),
+                ..ScheduleHints::default()
+            },
+        });
+        assert_eq!(writer.finish().unwrap(), 1);
+
+        let conn = Connection::open(&path).unwrap();
+        let row: (i64, i64, i64) = conn
+            .query_row("SELECT poll_interval, next_due, checks FROM schedule WHERE podcast_id = 1", [], |r| {
+                Ok((r.get(0)?, r.get(1)?, r.get(2)?))
+            })
+            .unwrap();
+        assert_eq!(row, (HOUR_SECS as i64, (NOW + HOUR_SECS) as i64, 1));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn writes_check_results_and_moved_urls() {
+        let path = temp_db("writeback");
+        let writer = QueueWriter::spawn(&path, 1, None).unwrap();
+        writer.record(QueueUpdate {
+            id: 1,
+            checked_at: 1700000000,
+            status: 200,
+            last_modified: 1690000000,
+            etag: "\"abc\"".to_string(),
+            new_url: Some("https://a2/feed".to_string()),
+            outcome: CheckOutcome::Fetched(None),
+            hints: ScheduleHints::default(),
+        });
+        writer.record(QueueUpdate {
+            id: 2,
+            checked_at: 1700000001,
+            status: 304,
+            last_modified: 0,
+            etag: "".to_string(),
+            new_url: None,
+            outcome: CheckOutcome::NotModified,
+            hints: ScheduleHints::default(),
+        });
+        assert_eq!(writer.finish().unwrap(), 2);
+
+        let conn = Connection::open(&path).unwrap();
+        let row: (String, i64, i64, i64, String) = conn
+            .query_row(
+                "SELECT url, lastcheck, lasthttpstatus, lastmod, etag FROM podcasts WHERE id = 1",
+                [],
+                |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?, r.get(4)?)),
+            )
+            .unwrap();
+        assert_eq!(row, ("https://a2/feed".to_string(), 1700000000, 200, 1690000000, "\"abc\"".to_string()));
+        let status: i64 = conn
+            .query_row("SELECT lasthttpstatus FROM podcasts WHERE id = 2", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(status, 304);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn url_collisions_are_ignored() {
+        let path = temp_db("collide");
+        let writer = QueueWriter::spawn(&path, 10, None).unwrap();
+        writer.record(QueueUpdate {
+            id: 2,
+            checked_at: 1,
+            status: 200,
+            last_modified: 0,
+            etag: "".to_string(),
+            new_url: Some("https://a/feed".to_string()),
+            outcome: CheckOutcome::Unknown,
+            hints: ScheduleHints::default(),
+        });
+        assert_eq!(writer.finish().unwrap(), 1);
+
+        let conn = Connection::open(&path).unwrap();
+        let url: String = conn
+            .query_row("SELECT url FROM podcasts WHERE id = 2", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(url, "https://b/feed");
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,224 @@
+//! Spotting feeds that say they have moved in their content rather than with
+//! an HTTP redirect: `<itunes:new-feed-url>`, the older RSS
+//! `<redirect><newLocation>` and `<podcast:moved>`.
+
+use reqwest::Url;
+
+/// The pseudo-status a content move's redirect stub is written with. It isn't
+/// a redirect the server sent, so it mustn't look like a 301 to the parser.
+pub const CONTENT_MOVE_STATUS: u16 = 690;
+
+/// How much of the start of a body is kept for scanning. Relocation elements
+/// live in the channel, ahead of the items, so there's no need to go further.
+pub const SCAN_LIMIT: usize = 64 * 1024;
+
+/// Which in-feed element announced the move.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MoveSignal {
+    ItunesNewFeedUrl,
+    RedirectNewLocation,
+    PodcastMoved,
+}
+
+impl MoveSignal {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MoveSignal::ItunesNewFeedUrl => "itunes:new-feed-url",
+            MoveSignal::RedirectNewLocation => "redirect:newLocation",
+            MoveSignal::PodcastMoved => "podcast:moved",
+        }
+    }
+}
+
+/// A feed's own claim that it now lives somewhere else.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FeedMove {
+    pub url: Url,
+    pub signal: MoveSignal,
+}
+
+impl FeedMove {
+    /// Whether the move points away from every one of `current_urls` (the urls
+    /// the feed was just fetched from). Publishers often point `new-feed-url`
+    /// at the feed itself, which isn't a move, and often with a different
+    /// scheme or trailing slash than the url it was fetched from.
+    pub fn is_elsewhere(&self, current_urls: &[&str]) -> bool {
+        let target = same_feed_key(&self.url);
+        !current_urls
+            .iter()
+            .filter_map(|url| Url::parse(url).ok())
+            .any(|url| same_feed_key(&url) == target)
+    }
+}
+
+/// What two urls for the same feed have in common: http and https, a trailing
+/// slash and the fragment don't make it a different feed.
+fn same_feed_key(url: &Url) -> (Option<&str>, Option<u16>, &str, Option<&str>) {
+    (url.host_str(), url.port(), url.path().trim_end_matches('/'), url.query())
+}
+
+/// Elements we look for, as (required prefix, local name). A `None` prefix
+/// matches the local name under any prefix, or none.
+const SIGNALS: [(Option<&str>, &str, MoveSignal); 3] = [
+    (None, "new-feed-url", MoveSignal::ItunesNewFeedUrl),
+    (None, "newLocation", MoveSignal::RedirectNewLocation),
+    (Some("podcast"), "moved", MoveSignal::PodcastMoved),
+];
+
+/// Collects the start of a body as it is streamed and looks for a relocation
+/// once the channel header has been seen (or the body ends).
+#[derive(Default)]
+pub struct MoveScanner {
+    head: String,
+    full: bool,
+}
+
+impl MoveScanner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the next stretch of (already decoded) body text.
+    pub fn push(&mut self, text: &str) {
+        if self.full {
+            return;
+        }
+        let room = SCAN_LIMIT.saturating_sub(self.head.len());
+        let mut take = text.len().min(room);
+        while !text.is_char_boundary(take) {
+            take -= 1;
+        }
+        self.head.push_str(&text[..take]);
+        self.full = self.head.len() >= SCAN_LIMIT || self.head.contains("<item") || self.head.contains("<entry");
+    }
+
+    /// The channel header collected so far, for anything else that wants to
+    /// read channel-level elements.
+    pub fn channel_head(&self) -> &str {
+        channel_head(&self.head)
+    }
+
+    /// The move announced in the body, if any.
+    pub fn finish(self) -> Option<FeedMove> {
+        find_move(channel_head(&self.head))
+    }
+}
+
+/// The part of a feed before its first item or entry.
+fn channel_head(text: &str) -> &str {
+    let end = [text.find("<item"), text.find("<entry")].iter().flatten().min().copied();
+    &text[..end.unwrap_or(text.len())]
+}
+
+/// The first relocation element in `text` holding an absolute http(s) url.
+pub fn find_move(text: &str) -> Option<FeedMove> {
+    let mut rest = text;
+    while let Some(open) = rest.find('<') {
+        rest = &rest[open + 1..];
+        let name_end = rest
+            .find(|c: char| c.is_whitespace() || c == '>' || c == '/')
+            .unwrap_or(rest.len());
+        let (prefix, local) = match rest[..name_end].split_once(':') {
+            Some((prefix, local)) => (Some(prefix), local),
+            None => (None, &rest[..name_end]),
+        };
+        let signal = SIGNALS.iter().find(|(want_prefix, want_local, _)| {
+            local.eq_ignore_ascii_case(want_local) && (want_prefix.is_none() || *want_prefix == prefix)
+        });
+        if let Some((_, _, signal)) = signal {
+            let content_start = match rest.find('>') {
+                Some(close) if !rest[..close].ends_with('/') => close + 1,
+                _ => continue,
+            };
+            if let Some(url) = element_url(&rest[content_start..]) {
+                return Some(FeedMove { url, signal: *signal });
+            }
+        }
+    }
+    None
+}
+
+/// Parse the text content at the start of `content` (up to the next tag) as
+/// an absolute http(s) url.
+fn element_url(content: &str) -> Option<Url> {
+    let raw = match content.trim_start().strip_prefix("<![CDATA[") {
+        Some(cdata) => &cdata[..cdata.find("]]>")?],
+        None => &content[..content.find('<')?],
+    };
+    let text = raw
+        .trim()
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&");
+    let url = Url::parse(&text).ok()?;
+    match url.scheme() {
+        "http" | "https" => Some(url),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scan(current: &str, chunks: &[&str]) -> Option<FeedMove> {
+        let mut scanner = MoveScanner::new();
+        for chunk in chunks {
+            scanner.push(chunk);
+        }
+        scanner.finish().filter(|found| found.is_elsewhere(&[current]))
+    }
+
+    #[test]
+    fn finds_itunes_new_feed_url_across_chunks() {
+        let found = scan(
+            "https://old.example/feed",
+            &["<rss><channel><itunes:new-feed", "-url> https://new.example/feed?a=1&amp;b=2 </itunes:new-feed-url>"],
+        )
+        .unwrap();
+        assert_eq!(found.signal, MoveSignal::ItunesNewFeedUrl);
+        assert_eq!(found.url.as_str(), "https://new.example/feed?a=1&b=2");
+    }
+
+    #[test]
+    fn finds_redirect_new_location_and_podcast_moved() {
+        let redirect = find_move("<channel><redirect><newLocation><![CDATA[http://new.example/rss]]></newLocation></redirect>");
+        assert_eq!(redirect.unwrap().signal, MoveSignal::RedirectNewLocation);
+
+        let moved = find_move("<channel><podcast:moved>https://new.example/rss</podcast:moved>").unwrap();
+        assert_eq!(moved.signal, MoveSignal::PodcastMoved);
+        assert!(find_move("<channel><other:moved>https://new.example/rss</other:moved>").is_none());
+    }
+
+    #[test]
+    fn ignores_self_references_and_junk() {
+        assert!(scan(
+            "https://feeds.example/show",
+            &["<channel><itunes:new-feed-url>https://feeds.example/show</itunes:new-feed-url>"]
+        )
+        .is_none());
+        assert!(scan(
+            "http://feeds.example/show/",
+            &["<channel><itunes:new-feed-url>https://feeds.example/show</itunes:new-feed-url>"]
+        )
+        .is_none());
+        assert!(scan(
+            "https://feeds.example/show",
+            &["<channel><itunes:new-feed-url>https://feeds.example/show?format=mp3</itunes:new-feed-url>"]
+        )
+        .is_some());
+        assert!(find_move("<channel><itunes:new-feed-url>not a url</itunes:new-feed-url>").is_none());
+        assert!(find_move("<channel><itunes:new-feed-url/>").is_none());
+    }
+
+    #[test]
+    fn only_looks_at_the_channel_header() {
+        assert!(scan(
+            "https://old.example/feed",
+            &["<channel><item><description><itunes:new-feed-url>https://new.example/feed</itunes:new-feed-url>"]
+        )
+        .is_none());
+    }
+}
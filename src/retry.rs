@@ -0,0 +1,129 @@
+//! Retrying a feed check that failed for a reason likely to go away: 429 and
+//! 5xx gateway responses, timeouts and dropped connections. Honors
+//! `Retry-After`, otherwise backs off exponentially with jitter.
+
+use std::time::{Duration, SystemTime};
+
+use rand::Rng;
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+
+/// How hard to try a single feed before recording the failure.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Total attempts per feed, including the first. 1 disables retries.
+    pub max_attempts: u32,
+    /// Backoff before the first retry; doubled for each one after.
+    pub base_delay: Duration,
+    /// Longest we'll wait between two attempts, backoff or `Retry-After`.
+    pub max_delay: Duration,
+    /// Longest a feed may spend on attempts and waits altogether. A retry
+    /// that can't start within it isn't made.
+    pub max_total: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            max_total: Duration::from_secs(120),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The jittered backoff before retry number `retry` (1 for the first).
+    /// Somewhere between half and all of `base_delay * 2^(retry - 1)`, capped
+    /// at `max_delay`, so a burst of failures doesn't retry in lockstep.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let exponent = retry.saturating_sub(1).min(31);
+        let ceiling = self.base_delay.saturating_mul(1 << exponent).min(self.max_delay);
+        let half = ceiling / 2;
+        half + rand::thread_rng().gen_range(Duration::ZERO..=half)
+    }
+
+    /// How long to wait before another attempt, after `attempts` have been
+    /// made over `elapsed`, or `None` if we should give up now. `retry_after`
+    /// is what the server asked for, if anything; asking for longer than
+    /// `max_delay` means we give up rather than wait.
+    pub fn next_delay(&self, attempts: u32, elapsed: Duration, retry_after: Option<Duration>) -> Option<Duration> {
+        if attempts >= self.max_attempts {
+            return None;
+        }
+        let delay = match retry_after {
+            Some(delay) if delay > self.max_delay => return None,
+            Some(delay) => delay,
+            None => self.backoff(attempts),
+        };
+        match elapsed + delay < self.max_total {
+            true => Some(delay),
+            false => None,
+        }
+    }
+}
+
+/// Whether a response status is worth retrying.
+pub fn is_retryable_status(status: u16) -> bool {
+    matches!(status, 429 | 502 | 503 | 504)
+}
+
+/// Parse a `Retry-After` value, either delta-seconds or an HTTP-date (which
+/// counts from `now`; a date in the past means retry straight away).
+pub fn parse_retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let at = httpdate::parse_http_date(value).ok()?;
+    Some(at.duration_since(now).unwrap_or(Duration::ZERO))
+}
+
+/// The delay a response's `Retry-After` header asks for, if it has a usable one.
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?;
+    parse_retry_after(value, SystemTime::now())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_both_retry_after_forms() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_800_000_000);
+        assert_eq!(parse_retry_after("120", now), Some(Duration::from_secs(120)));
+        let later = httpdate::fmt_http_date(now + Duration::from_secs(30));
+        assert_eq!(parse_retry_after(&later, now), Some(Duration::from_secs(30)));
+        let earlier = httpdate::fmt_http_date(now - Duration::from_secs(30));
+        assert_eq!(parse_retry_after(&earlier, now), Some(Duration::ZERO));
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+
+    #[test]
+    fn backoff_doubles_with_jitter_and_caps() {
+        let policy = RetryPolicy {
+            base_delay: Duration::from_secs(2),
+            max_delay: Duration::from_secs(10),
+            ..RetryPolicy::default()
+        };
+        for _ in 0..50 {
+            let first = policy.backoff(1);
+            assert!(first >= Duration::from_secs(1) && first <= Duration::from_secs(2));
+            let third = policy.backoff(3);
+            assert!(third >= Duration::from_secs(4) && third <= Duration::from_secs(8));
+            let tenth = policy.backoff(10);
+            assert!(tenth >= Duration::from_secs(5) && tenth <= Duration::from_secs(10));
+        }
+    }
+
+    #[test]
+    fn gives_up_on_attempts_budget_or_long_retry_after() {
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.next_delay(1, Duration::ZERO, Some(Duration::from_secs(5))), Some(Duration::from_secs(5)));
+        assert_eq!(policy.next_delay(3, Duration::ZERO, Some(Duration::from_secs(5))), None);
+        assert_eq!(policy.next_delay(1, Duration::ZERO, Some(Duration::from_secs(3600))), None);
+        assert_eq!(policy.next_delay(1, Duration::from_secs(118), Some(Duration::from_secs(5))), None);
+        assert!(policy.next_delay(1, Duration::ZERO, None).is_some());
+    }
+}
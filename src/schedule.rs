@@ -0,0 +1,391 @@
+//! Adaptive poll scheduling. Each feed gets its own poll interval, learned
+//! from whether recent checks found new content and bounded by what the feed
+//! and its server say about freshness. The resulting next-due time is kept in
+//! a `schedule` table alongside `podcasts` in the queue database.
+
+use std::time::SystemTime;
+
+use reqwest::header::{HeaderMap, CACHE_CONTROL, EXPIRES};
+use rusqlite::{params, Connection, OptionalExtension};
+
+const MINUTE_SECS: u64 = 60;
+const HOUR_SECS: u64 = 3600;
+const DAY_SECS: u64 = 86400;
+
+/// Created on demand by the queue writer; the selection only uses it once it exists.
+pub const SCHEDULE_SCHEMA: &str = "CREATE TABLE IF NOT EXISTS schedule (
+    podcast_id INTEGER PRIMARY KEY,
+    poll_interval INTEGER NOT NULL,
+    next_due INTEGER NOT NULL,
+    content_hash TEXT,
+    checks INTEGER NOT NULL DEFAULT 0,
+    changes INTEGER NOT NULL DEFAULT 0);";
+
+/// Bounds for the learned intervals.
+#[derive(Debug, Clone)]
+pub struct ScheduleConfig {
+    /// Shortest interval any feed is polled at.
+    pub min_interval_secs: u64,
+    /// Longest interval, used for finished and long-dormant feeds.
+    pub max_interval_secs: u64,
+    /// Where a feed with no history starts.
+    pub initial_interval_secs: u64,
+    /// `Cache-Control`/`Expires` lifetimes longer than this are ignored; some
+    /// CDNs hand out a year for everything.
+    pub max_cache_hint_secs: u64,
+}
+
+impl Default for ScheduleConfig {
+    fn default() -> Self {
+        Self {
+            min_interval_secs: 15 * MINUTE_SECS,
+            max_interval_secs: 30 * DAY_SECS,
+            initial_interval_secs: 6 * HOUR_SECS,
+            max_cache_hint_secs: DAY_SECS,
+        }
+    }
+}
+
+/// What the server and the feed said about how often it changes.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ScheduleHints {
+    /// Freshness lifetime from `Cache-Control: max-age` or `Expires`.
+    pub cache_secs: Option<u64>,
+    /// RSS `<ttl>`, converted from minutes.
+    pub ttl_secs: Option<u64>,
+    /// Publishing cadence declared by `sy:updatePeriod`/`sy:updateFrequency`
+    /// or `podcast:updateFrequency`.
+    pub declared_secs: Option<u64>,
+    /// `podcast:updateFrequency complete="true"`: no more episodes are coming.
+    pub complete: bool,
+}
+
+impl ScheduleHints {
+    /// Fill in whatever `other` knows that this doesn't.
+    pub fn merge(&mut self, other: ScheduleHints) {
+        self.cache_secs = self.cache_secs.or(other.cache_secs);
+        self.ttl_secs = self.ttl_secs.or(other.ttl_secs);
+        self.declared_secs = self.declared_secs.or(other.declared_secs);
+        self.complete |= other.complete;
+    }
+}
+
+/// What a check found, as far as scheduling is concerned.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CheckOutcome {
+    /// A body came back; `Some` hash of it when it was downloaded in full.
+    /// An unchanged hash counts as not modified.
+    Fetched(Option<String>),
+    /// 304, or a bodyless response that says nothing changed.
+    NotModified,
+    /// Errors and anything else that says nothing about the feed's cadence.
+    Unknown,
+}
+
+/// A feed's place in the schedule.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FeedSchedule {
+    pub poll_interval: u64,
+    pub next_due: u64,
+}
+
+/// Freshness lifetime from the response headers, if any. `no-cache` and
+/// `no-store` say nothing about how often the feed changes, so are ignored.
+pub fn hints_from_headers(headers: &HeaderMap, now: SystemTime) -> ScheduleHints {
+    let max_age = headers
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|directive| directive.trim().split_once('='))
+        .find(|(name, _)| name.eq_ignore_ascii_case("max-age"))
+        .and_then(|(_, value)| value.trim().trim_matches('"').parse::<u64>().ok());
+    let expires = || {
+        let value = headers.get(EXPIRES)?.to_str().ok()?;
+        let at = httpdate::parse_http_date(value).ok()?;
+        at.duration_since(now).ok().map(|left| left.as_secs())
+    };
+    ScheduleHints {
+        cache_secs: max_age.or_else(expires).filter(|secs| *secs > 0),
+        ..ScheduleHints::default()
+    }
+}
+
+/// Hints declared in the channel of a feed (the part before the first item).
+pub fn hints_from_feed(head: &str) -> ScheduleHints {
+    let mut hints = ScheduleHints {
+        ttl_secs: element(head, "ttl")
+            .and_then(|(_, text)| text.trim().parse::<u64>().ok())
+            .filter(|minutes| *minutes > 0)
+            .map(|minutes| minutes * MINUTE_SECS),
+        ..ScheduleHints::default()
+    };
+
+    if let Some((attributes, _)) = element(head, "podcast:updateFrequency") {
+        hints.complete = attribute(attributes, "complete").is_some_and(|value| value.eq_ignore_ascii_case("true"));
+        hints.declared_secs = attribute(attributes, "rrule").and_then(rrule_secs);
+    }
+    if hints.declared_secs.is_none() {
+        let period = element(head, "sy:updatePeriod").and_then(|(_, text)| period_secs(text.trim()));
+        let per_period = element(head, "sy:updateFrequency")
+            .and_then(|(_, text)| text.trim().parse::<u64>().ok())
+            .filter(|times| *times > 0)
+            .unwrap_or(1);
+        hints.declared_secs = period.map(|period| period / per_period);
+    }
+    hints
+}
+
+fn period_secs(period: &str) -> Option<u64> {
+    match period.to_ascii_lowercase().as_str() {
+        "hourly" => Some(HOUR_SECS),
+        "daily" => Some(DAY_SECS),
+        "weekly" => Some(7 * DAY_SECS),
+        "monthly" => Some(30 * DAY_SECS),
+        "yearly" => Some(365 * DAY_SECS),
+        _ => None,
+    }
+}
+
+/// The cadence of an iCalendar RRULE like `FREQ=WEEKLY;INTERVAL=2`.
+fn rrule_secs(rrule: &str) -> Option<u64> {
+    let mut freq = None;
+    let mut interval = 1;
+    for part in rrule.split(';') {
+        match part.split_once('=') {
+            Some((name, value)) if name.eq_ignore_ascii_case("FREQ") => {
+                freq = match value.to_ascii_uppercase().as_str() {
+                    "MINUTELY" => Some(MINUTE_SECS),
+                    "HOURLY" => Some(HOUR_SECS),
+                    "DAILY" => Some(DAY_SECS),
+                    "WEEKLY" => Some(7 * DAY_SECS),
+                    "MONTHLY" => Some(30 * DAY_SECS),
+                    "YEARLY" => Some(365 * DAY_SECS),
+                    _ => None,
+                }
+            }
+            Some((name, value)) if name.eq_ignore_ascii_case("INTERVAL") => {
+                interval = value.parse::<u64>().ok().filter(|n| *n > 0)?;
+            }
+            _ => {}
+        }
+    }
+    freq.map(|freq| freq * interval)
+}
+
+/// The attributes and text content of the first `<name ...>text</name>`.
+fn element<'a>(text: &'a str, name: &str) -> Option<(&'a str, &'a str)> {
+    let mut rest = text;
+    loop {
+        let start = rest.find('<')? + 1;
+        rest = &rest[start..];
+        let Some(after) = rest.strip_prefix(name) else { continue };
+        if !after.starts_with(|c: char| c.is_whitespace() || c == '>' || c == '/') {
+            continue;
+        }
+        let close = after.find('>')?;
+        let attributes = &after[..close];
+        if attributes.ends_with('/') {
+            return Some((attributes.trim_end_matches('/'), ""));
+        }
+        let content = &after[close + 1..];
+        let content = &content[..content.find('<').unwrap_or(content.len())];
+        return Some((attributes, content));
+    }
+}
+
+fn attribute<'a>(attributes: &'a str, name: &str) -> Option<&'a str> {
+    let mut rest = attributes;
+    loop {
+        let at = rest.find(name)?;
+        let before = rest[..at].chars().last();
+        rest = &rest[at + name.len()..];
+        if !before.is_some_and(char::is_whitespace) {
+            continue;
+        }
+        let Some(value) = rest.trim_start().strip_prefix('=') else { continue };
+        let value = value.trim_start();
+        let quote = value.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+        let value = &value[1..];
+        return value.find(quote).map(|end| &value[..end]);
+    }
+}
+
+/// Work out a feed's next interval from its previous one and what this check
+/// found. New content halves the interval, none grows it by half again, so it
+/// settles around the feed's real cadence. Then the hints apply: never poll
+/// more often than the server or `<ttl>` says the content stays fresh, never
+/// less often than the publisher's declared cadence, and finished feeds go to
+/// the longest interval.
+pub fn next_schedule(
+    previous_interval: Option<u64>,
+    changed: Option<bool>,
+    hints: &ScheduleHints,
+    now: u64,
+    config: &ScheduleConfig,
+) -> FeedSchedule {
+    let previous = previous_interval.unwrap_or(config.initial_interval_secs);
+    let mut interval = match changed {
+        Some(true) => previous / 2,
+        Some(false) => previous + previous / 2,
+        None => previous,
+    };
+    if let Some(declared) = hints.declared_secs {
+        interval = interval.min(declared);
+    }
+    let fresh_for = [hints.cache_secs.map(|secs| secs.min(config.max_cache_hint_secs)), hints.ttl_secs]
+        .iter()
+        .flatten()
+        .max()
+        .copied();
+    if let Some(fresh_for) = fresh_for {
+        interval = interval.max(fresh_for);
+    }
+    if hints.complete {
+        interval = config.max_interval_secs;
+    }
+    let interval = interval.clamp(config.min_interval_secs, config.max_interval_secs.max(config.min_interval_secs));
+    FeedSchedule {
+        poll_interval: interval,
+        next_due: now + interval,
+    }
+}
+
+/// Whether the queue database has a schedule yet.
+pub fn has_schedule_table(conn: &Connection) -> rusqlite::Result<bool> {
+    conn.query_row(
+        "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = 'schedule'",
+        [],
+        |row| row.get::<_, i64>(0),
+    )
+    .map(|count| count > 0)
+}
+
+/// Fold one check into a feed's schedule row. Returns the new schedule.
+pub fn record_check(
+    conn: &Connection,
+    podcast_id: u64,
+    outcome: &CheckOutcome,
+    hints: &ScheduleHints,
+    now: u64,
+    config: &ScheduleConfig,
+) -> rusqlite::Result<FeedSchedule> {
+    let previous: Option<(u64, Option<String>)> = conn
+        .prepare_cached("SELECT poll_interval, content_hash FROM schedule WHERE podcast_id = ?1")?
+        .query_row(params![podcast_id as i64], |row| Ok((row.get(0)?, row.get(1)?)))
+        .optional()?;
+    let (previous_interval, previous_hash) = match previous {
+        Some((interval, hash)) => (Some(interval), hash),
+        None => (None, None),
+    };
+
+    let changed = match outcome {
+        CheckOutcome::Fetched(Some(hash)) => Some(previous_hash.as_ref() != Some(hash)),
+        CheckOutcome::Fetched(None) => Some(true),
+        CheckOutcome::NotModified => Some(false),
+        CheckOutcome::Unknown => None,
+    };
+    let hash = match outcome {
+        CheckOutcome::Fetched(hash) => hash.clone(),
+        _ => previous_hash,
+    };
+    let schedule = next_schedule(previous_interval, changed, hints, now, config);
+
+    conn.prepare_cached(
+        "INSERT INTO schedule (podcast_id, poll_interval, next_due, content_hash, checks, changes) \
+         VALUES (?1, ?2, ?3, ?4, 1, ?5) \
+         ON CONFLICT(podcast_id) DO UPDATE SET \
+             poll_interval = excluded.poll_interval, next_due = excluded.next_due, \
+             content_hash = excluded.content_hash, checks = checks + 1, changes = changes + excluded.changes",
+    )?
+    .execute(params![
+        podcast_id as i64,
+        schedule.poll_interval as i64,
+        schedule.next_due as i64,
+        hash,
+        changed == Some(true)
+    ])?;
+    Ok(schedule)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+    use std::time::Duration;
+
+    const NOW: u64 = 1_800_000_000;
+
+    #[test]
+    fn reads_cache_headers() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(NOW);
+        let mut headers = HeaderMap::new();
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static("public, max-age=600"));
+        headers.insert(EXPIRES, HeaderValue::from_str(&httpdate::fmt_http_date(now + Duration::from_secs(60))).unwrap());
+        assert_eq!(hints_from_headers(&headers, now).cache_secs, Some(600));
+
+        headers.remove(CACHE_CONTROL);
+        assert_eq!(hints_from_headers(&headers, now).cache_secs, Some(60));
+        assert_eq!(hints_from_headers(&HeaderMap::new(), now).cache_secs, None);
+    }
+
+    #[test]
+    fn reads_feed_hints() {
+        let hints = hints_from_feed(
+            "<channel><ttl>60</ttl><sy:updatePeriod>daily</sy:updatePeriod><sy:updateFrequency>2</sy:updateFrequency>",
+        );
+        assert_eq!(hints.ttl_secs, Some(3600));
+        assert_eq!(hints.declared_secs, Some(DAY_SECS / 2));
+
+        let hints = hints_from_feed(
+            "<channel><podcast:updateFrequency complete=\"true\" rrule=\"FREQ=WEEKLY;INTERVAL=2\">Biweekly</podcast:updateFrequency>",
+        );
+        assert_eq!(hints.declared_secs, Some(14 * DAY_SECS));
+        assert!(hints.complete);
+        assert_eq!(hints_from_feed("<channel><title>x</title>"), ScheduleHints::default());
+    }
+
+    #[test]
+    fn interval_shrinks_on_change_and_grows_without() {
+        let config = ScheduleConfig::default();
+        let none = ScheduleHints::default();
+        assert_eq!(next_schedule(None, Some(true), &none, NOW, &config).poll_interval, 3 * HOUR_SECS);
+        assert_eq!(next_schedule(Some(4 * HOUR_SECS), Some(false), &none, NOW, &config).poll_interval, 6 * HOUR_SECS);
+        assert_eq!(next_schedule(Some(4 * HOUR_SECS), None, &none, NOW, &config).next_due, NOW + 4 * HOUR_SECS);
+        assert_eq!(next_schedule(Some(20 * MINUTE_SECS), Some(true), &none, NOW, &config).poll_interval, 15 * MINUTE_SECS);
+        assert_eq!(next_schedule(Some(29 * DAY_SECS), Some(false), &none, NOW, &config).poll_interval, 30 * DAY_SECS);
+    }
+
+    #[test]
+    fn hints_bound_the_learned_interval() {
+        let config = ScheduleConfig::default();
+        let daily = ScheduleHints { declared_secs: Some(DAY_SECS), ..ScheduleHints::default() };
+        assert_eq!(next_schedule(Some(10 * DAY_SECS), Some(false), &daily, NOW, &config).poll_interval, DAY_SECS);
+
+        let cached = ScheduleHints { cache_secs: Some(365 * DAY_SECS), ttl_secs: Some(2 * HOUR_SECS), ..ScheduleHints::default() };
+        assert_eq!(next_schedule(Some(HOUR_SECS), Some(true), &cached, NOW, &config).poll_interval, DAY_SECS);
+
+        let finished = ScheduleHints { complete: true, ..daily };
+        assert_eq!(next_schedule(None, Some(true), &finished, NOW, &config).poll_interval, 30 * DAY_SECS);
+    }
+
+    #[test]
+    fn records_checks_and_compares_content() {
+        let conn = Connection::open_in_memory().unwrap();
+        assert!(!has_schedule_table(&conn).unwrap());
+        conn.execute_batch(SCHEDULE_SCHEMA).unwrap();
+        assert!(has_schedule_table(&conn).unwrap());
+
+        let config = ScheduleConfig::default();
+        let none = ScheduleHints::default();
+        let body = CheckOutcome::Fetched(Some("abc".to_string()));
+        assert_eq!(record_check(&conn, 1, &body, &none, NOW, &config).unwrap().poll_interval, 3 * HOUR_SECS);
+        //Same body again is not a change
+        assert_eq!(record_check(&conn, 1, &body, &none, NOW, &config).unwrap().poll_interval, 4 * HOUR_SECS + 30 * MINUTE_SECS);
+
+        let (checks, changes): (i64, i64) = conn
+            .query_row("SELECT checks, changes FROM schedule WHERE podcast_id = 1", [], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap();
+        assert_eq!((checks, changes), (2, 1));
+    }
+}
@@ -5,7 +5,7 @@ use std::error::Error;
 
 use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
 use base64::Engine;
-use ed25519_dalek::pkcs8::DecodePrivateKey;
+use ed25519_dalek::pkcs8::{DecodePrivateKey, EncodePrivateKey};
 use ed25519_dalek::{Signer, SigningKey};
 use reqwest::header::{HeaderName, HeaderValue};
 use reqwest::Url;
@@ -20,6 +20,13 @@ pub struct WebBotAuthSigner {
     ttl_secs: u64,
 }
 
+/// A new Ed25519 private key as PKCS#8 PEM, for an operator to install with
+/// `AGGRIVATOR_SIGNING_KEY`.
+pub fn generate_key_pem() -> Result<String, Box<dyn Error>> {
+    let signing_key = SigningKey::generate(&mut rand::rngs::OsRng);
+    Ok(signing_key.to_pkcs8_pem(Default::default())?.to_string())
+}
+
 /// RFC 7638 JWK thumbprint of an Ed25519 public key, base64url-no-pad.
 fn compute_keyid(public_key: &[u8; 32]) -> String {
     let x = URL_SAFE_NO_PAD.encode(public_key);
@@ -278,4 +285,14 @@ mod tests {
         assert_eq!(key["kid"], keyid);
         assert!(!key["x"].as_str().unwrap().is_empty());
     }
+
+    #[test]
+    fn generated_key_loads() {
+        let path = std::env::temp_dir().join(format!("aggrivator-key-{}.pem", std::process::id()));
+        std::fs::write(&path, generate_key_pem().unwrap()).unwrap();
+        let signer =
+            WebBotAuthSigner::from_pem_file(path.to_str().unwrap(), "https://podcastindex.org".to_string(), 300).unwrap();
+        assert_eq!(signer.jwks()["keys"][0]["kid"], signer.keyid());
+        let _ = std::fs::remove_file(&path);
+    }
 }
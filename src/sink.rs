@@ -0,0 +1,908 @@
+//! Where check results go. Every result (a fetched feed, a bodyless response,
+//! a failed fetch or a redirect stub) is handed to a `ResultSink`: begun with
+//! what's known about it, given its body, if there is one, as it streams in,
+//! and committed once it is complete. Nothing is visible before that. The
+//! default `FileSink` writes the text files in `feeds/` and `redirects/` that
+//! the parser reads. The others write the same results as JSON Lines, rows in
+//! a SQLite table or a directory of JSON documents, for consumers that would
+//! rather not parse text files.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
+
+use rusqlite::{params, Connection};
+use serde::Serialize;
+
+use crate::feedfile::{unlinked_temp_file, FeedFileNamer, PendingFeedFile};
+use crate::queue::BUSY_TIMEOUT;
+use crate::relocation::CONTENT_MOVE_STATUS;
+
+/// The header line that isn't complete until the body is in: the body's own
+/// times are added to it at commit. Feed files with a body have it after the
+/// body, as their last line.
+pub const TIMING_LINE: &str = "Timing";
+
+/// The `results` table the SQLite sink writes to. `headers` holds the extra
+/// header lines as a JSON array of `{"name", "value"}` objects.
+pub const RESULTS_SCHEMA: &str = "CREATE TABLE IF NOT EXISTS results (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    feed_id INTEGER NOT NULL,
+    status INTEGER NOT NULL,
+    last_modified INTEGER NOT NULL,
+    etag TEXT NOT NULL,
+    url TEXT NOT NULL,
+    checked_at INTEGER NOT NULL,
+    headers TEXT NOT NULL,
+    body TEXT
+);
+CREATE INDEX IF NOT EXISTS results_feed_id ON results (feed_id);";
+
+/// One result of checking a feed, less its body.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FeedResult {
+    pub feed_id: u64,
+    /// The http status, or the pseudo-status of a failed fetch. Permanent
+    /// redirect stubs are the only results with a 301 or 308, and stubs for
+    /// moves announced in a feed's content have `CONTENT_MOVE_STATUS`.
+    pub status: u16,
+    /// Last-Modified as a unix timestamp, 0 if there wasn't one.
+    pub last_modified: u64,
+    /// `[[NO_ETAG]]` if there wasn't one.
+    pub etag: String,
+    pub url: String,
+    /// Unix time the result was recorded.
+    pub checked_at: u64,
+    /// The extra header lines (`Attempts`, `Redirect`...), in order.
+    pub lines: Vec<(String, String)>,
+}
+
+impl FeedResult {
+    pub fn is_redirect_stub(&self) -> bool {
+        matches!(self.status, 301 | 308 | CONTENT_MOVE_STATUS)
+    }
+
+    /// The value of the first line called `name`.
+    pub fn line(&self, name: &str) -> Option<&str> {
+        self.lines.iter().find(|(line, _)| line == name).map(|(_, value)| value.as_str())
+    }
+
+    /// Replace the value of the first line called `name`, if there is one.
+    pub fn set_line(&mut self, name: &str, value: String) {
+        if let Some((_, old)) = self.lines.iter_mut().find(|(line, _)| line == name) {
+            *old = value;
+        }
+    }
+}
+
+/// Somewhere results go. One sink takes every result of a run, from many
+/// checks at once.
+pub trait ResultSink: Send + Sync {
+    /// Start on `result`. Its body, if it has one, is written to what this
+    /// returns.
+    fn begin(&self, result: &FeedResult) -> io::Result<Box<dyn PendingResult>>;
+
+    /// Where `result` would go, for reporting on a check that doesn't write.
+    fn location(&self, result: &FeedResult) -> String;
+}
+
+/// A result on its way into a sink. Dropping it uncommitted leaves nothing
+/// behind.
+pub trait PendingResult: Write + Send {
+    /// Finish the result and make it visible. `result` is the one it was begun
+    /// with, except that the `Timing` line may have been finished since.
+    /// Returns where it went.
+    fn commit(self: Box<Self>, result: &FeedResult) -> io::Result<String>;
+}
+
+/// The sinks there are to choose from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SinkKind {
+    /// `FileSink`
+    Files,
+    /// `JsonLinesSink` on stdout
+    JsonLines,
+    /// `SqliteSink`
+    Sqlite,
+    /// `JsonDirSink`
+    JsonDir,
+}
+
+impl FromStr for SinkKind {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "files" => Ok(SinkKind::Files),
+            "jsonl" => Ok(SinkKind::JsonLines),
+            "sqlite" => Ok(SinkKind::Sqlite),
+            "json-dir" => Ok(SinkKind::JsonDir),
+            other => Err(format!("unknown sink: {}", other)),
+        }
+    }
+}
+
+/// The head of a feed file: the last-modified, etag, url and time written
+/// lines, then the extra lines as `X-Aggrivator-<name>: <value>` unless
+/// `legacy` is set.
+pub fn file_header(result: &FeedResult, legacy: bool) -> String {
+    let mut header = format!("{}\n{}\n{}\n{}\n", result.last_modified, result.etag, result.url, result.checked_at);
+    if !legacy {
+        for (name, value) in &result.lines {
+            header.push_str(&format!("X-Aggrivator-{}: {}\n", name, value));
+        }
+    }
+    header
+}
+
+/// Feed files, as the parser reads them: results in `feeds_dir` and permanent
+/// redirect stubs in `redirects_dir`, each renamed into place once complete.
+pub struct FileSink {
+    feeds_dir: PathBuf,
+    redirects_dir: PathBuf,
+    namer: Arc<FeedFileNamer>,
+    fsync: bool,
+    legacy_header: bool,
+}
+
+impl FileSink {
+    /// With `fsync` set every file and its directory are synced before the
+    /// file counts as written. `legacy_header` leaves the extra header lines
+    /// out, for parsers that expect the body to start on line five.
+    pub fn new(feeds_dir: PathBuf, redirects_dir: PathBuf, namer: FeedFileNamer, fsync: bool, legacy_header: bool) -> Self {
+        Self { feeds_dir, redirects_dir, namer: Arc::new(namer), fsync, legacy_header }
+    }
+
+    fn dir_for(&self, result: &FeedResult) -> &Path {
+        match result.is_redirect_stub() {
+            true => &self.redirects_dir,
+            false => &self.feeds_dir,
+        }
+    }
+}
+
+impl ResultSink for FileSink {
+    fn begin(&self, result: &FeedResult) -> io::Result<Box<dyn PendingResult>> {
+        let file = self.namer.create(self.dir_for(result), result.feed_id, result.status, self.fsync)?;
+        let mut head = result.clone();
+        head.lines.retain(|(name, _)| name != TIMING_LINE);
+        Ok(Box::new(PendingFile {
+            file,
+            head: Some(file_header(&head, self.legacy_header)),
+            ends_in_newline: false,
+            namer: self.namer.clone(),
+            feed_id: result.feed_id,
+            legacy_header: self.legacy_header,
+        }))
+    }
+
+    fn location(&self, result: &FeedResult) -> String {
+        self.dir_for(result).join(self.namer.preview_name(result.feed_id, result.status)).display().to_string()
+    }
+}
+
+/// A feed file being written. The body goes straight into the temp file, after
+/// the header less its Timing line, which isn't finished until the body is in
+/// and so goes after it at commit. A file without a body gets the whole
+/// header at commit.
+struct PendingFile {
+    file: PendingFeedFile,
+    /// Until the body starts.
+    head: Option<String>,
+    ends_in_newline: bool,
+    namer: Arc<FeedFileNamer>,
+    feed_id: u64,
+    legacy_header: bool,
+}
+
+impl Write for PendingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if let Some(head) = self.head.take() {
+            self.file.write_all(head.as_bytes())?;
+        }
+        let written = self.file.write(buf)?;
+        if written > 0 {
+            self.ends_in_newline = buf[written - 1] == b'\n';
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl PendingResult for PendingFile {
+    fn commit(self: Box<Self>, result: &FeedResult) -> io::Result<String> {
+        let PendingFile { mut file, head, ends_in_newline, namer, feed_id, legacy_header } = *self;
+        match (head, result.line(TIMING_LINE)) {
+            (None, Some(timing)) if !legacy_header => {
+                let newline = if ends_in_newline { "" } else { "\n" };
+                writeln!(file, "{}X-Aggrivator-{}: {}", newline, TIMING_LINE, timing)?;
+            }
+            (None, _) => {}
+            (Some(_), _) => file.write_all(file_header(result, legacy_header).as_bytes())?,
+        }
+        Ok(namer.commit(feed_id, file)?.display().to_string())
+    }
+}
+
+/// Most of a body the JSON Lines and SQLite sinks hold in memory before the
+/// rest goes to a temp file.
+pub const SPOOLED_IN_MEMORY: usize = 256 * 1024;
+
+/// A result as a JSON document, less its body. The body goes first in the
+/// documents, as a string or `null` when there wasn't one, so it can be
+/// written as it streams in; these fields follow it.
+#[derive(Serialize)]
+struct Fields<'a> {
+    feed_id: u64,
+    status: u16,
+    last_modified: u64,
+    etag: &'a str,
+    url: &'a str,
+    checked_at: u64,
+    headers: Vec<Header<'a>>,
+}
+
+#[derive(Serialize)]
+struct Header<'a> {
+    name: &'a str,
+    value: &'a str,
+}
+
+fn headers(result: &FeedResult) -> Vec<Header<'_>> {
+    result.lines.iter().map(|(name, value)| Header { name, value }).collect()
+}
+
+/// Bytes that can't be written where they're going until commit: in memory up
+/// to `SPOOLED_IN_MEMORY`, then in an unlinked temp file, so no body is ever
+/// held whole.
+struct Spool {
+    memory: Vec<u8>,
+    spilled: Option<BufWriter<File>>,
+}
+
+impl Spool {
+    fn new() -> Self {
+        Self { memory: Vec::new(), spilled: None }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.spilled.is_none() && self.memory.is_empty()
+    }
+
+    fn spill(&mut self) -> io::Result<()> {
+        let mut file = BufWriter::new(unlinked_temp_file(&std::env::temp_dir())?);
+        file.write_all(&std::mem::take(&mut self.memory))?;
+        self.spilled = Some(file);
+        Ok(())
+    }
+
+    /// Write everything spooled to `out`.
+    fn copy_to(self, out: &mut impl Write) -> io::Result<()> {
+        match self.spilled {
+            None => out.write_all(&self.memory),
+            Some(writer) => {
+                let mut file = writer.into_inner().map_err(|e| e.into_error())?;
+                file.seek(SeekFrom::Start(0))?;
+                io::copy(&mut file, out).map(|_| ())
+            }
+        }
+    }
+
+    /// Everything spooled, as utf-8.
+    fn into_text(self) -> io::Result<String> {
+        let mut bytes = Vec::new();
+        match self.spilled {
+            None => bytes = self.memory,
+            Some(_) => self.copy_to(&mut bytes)?,
+        }
+        Ok(String::from_utf8(bytes).unwrap_or_else(|e| String::from_utf8_lossy(e.as_bytes()).into_owned()))
+    }
+}
+
+impl Write for Spool {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.spilled.is_none() && self.memory.len() + buf.len() > SPOOLED_IN_MEMORY {
+            self.spill()?;
+        }
+        match &mut self.spilled {
+            Some(file) => file.write(buf),
+            None => self.memory.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.spilled {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A JSON document written to `out` as its body streams in: the body as an
+/// escaped string first, then the rest of the fields at `finish`, once the
+/// result is final. Bodies are written as utf-8, whatever they came in as.
+struct StreamedDocument<W> {
+    out: W,
+    started: bool,
+    /// The start of a character the last write ended partway through.
+    partial: Vec<u8>,
+}
+
+impl<W: Write> StreamedDocument<W> {
+    fn new(out: W) -> Self {
+        Self { out, started: false, partial: Vec::new() }
+    }
+
+    /// Close the body, add the other fields and hand back the writer.
+    fn finish(mut self, result: &FeedResult) -> io::Result<W> {
+        let fields = Fields {
+            feed_id: result.feed_id,
+            status: result.status,
+            last_modified: result.last_modified,
+            etag: &result.etag,
+            url: &result.url,
+            checked_at: result.checked_at,
+            headers: headers(result),
+        };
+        let fields = serde_json::to_string(&fields).map_err(io::Error::other)?;
+        match self.started {
+            true if self.partial.is_empty() => self.out.write_all(b"\",")?,
+            true => self.out.write_all("\u{fffd}\",".as_bytes())?,
+            false => self.out.write_all(b"{\"body\":null,")?,
+        }
+        self.out.write_all(&fields.as_bytes()[1..])?;
+        Ok(self.out)
+    }
+}
+
+impl<W: Write> Write for StreamedDocument<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if !self.started {
+            self.out.write_all(b"{\"body\":\"")?;
+            self.started = true;
+        }
+        let mut bytes = std::mem::take(&mut self.partial);
+        bytes.extend_from_slice(buf);
+        let mut rest = &bytes[..];
+        loop {
+            match std::str::from_utf8(rest) {
+                Ok(text) => break write_escaped(&mut self.out, text)?,
+                Err(e) => {
+                    let (valid, after) = rest.split_at(e.valid_up_to());
+                    write_escaped(&mut self.out, std::str::from_utf8(valid).expect("checked as utf-8"))?;
+                    match e.error_len() {
+                        Some(invalid) => {
+                            self.out.write_all("\u{fffd}".as_bytes())?;
+                            rest = &after[invalid..];
+                        }
+                        None => {
+                            self.partial = after.to_vec();
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Write `text` as the inside of a JSON string.
+fn write_escaped(out: &mut impl Write, text: &str) -> io::Result<()> {
+    let bytes = text.as_bytes();
+    let mut start = 0;
+    for (i, &byte) in bytes.iter().enumerate() {
+        if byte != b'"' && byte != b'\\' && byte >= 0x20 {
+            continue;
+        }
+        out.write_all(&bytes[start..i])?;
+        match byte {
+            b'"' => out.write_all(b"\\\"")?,
+            b'\\' => out.write_all(b"\\\\")?,
+            b'\n' => out.write_all(b"\\n")?,
+            b'\r' => out.write_all(b"\\r")?,
+            b'\t' => out.write_all(b"\\t")?,
+            _ => write!(out, "\\u{:04x}", byte)?,
+        }
+        start = i + 1;
+    }
+    out.write_all(&bytes[start..])
+}
+
+/// One JSON document per line, each written in one go, to stdout or any other
+/// writer.
+pub struct JsonLinesSink {
+    out: Arc<Mutex<Box<dyn Write + Send>>>,
+    name: String,
+}
+
+impl JsonLinesSink {
+    /// Write to `out`, called `name` in reports.
+    pub fn new(out: impl Write + Send + 'static, name: &str) -> Self {
+        Self { out: Arc::new(Mutex::new(Box::new(out))), name: name.to_string() }
+    }
+
+    pub fn stdout() -> Self {
+        Self::new(io::stdout(), "stdout")
+    }
+}
+
+impl ResultSink for JsonLinesSink {
+    fn begin(&self, _result: &FeedResult) -> io::Result<Box<dyn PendingResult>> {
+        Ok(Box::new(PendingLine {
+            document: StreamedDocument::new(Spool::new()),
+            out: self.out.clone(),
+            name: self.name.clone(),
+        }))
+    }
+
+    fn location(&self, _result: &FeedResult) -> String {
+        self.name.clone()
+    }
+}
+
+/// A line being spooled. It goes out in one go at commit, so lines from checks
+/// running at the same time never mix.
+struct PendingLine {
+    document: StreamedDocument<Spool>,
+    out: Arc<Mutex<Box<dyn Write + Send>>>,
+    name: String,
+}
+
+impl Write for PendingLine {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.document.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.document.flush()
+    }
+}
+
+impl PendingResult for PendingLine {
+    fn commit(self: Box<Self>, result: &FeedResult) -> io::Result<String> {
+        let PendingLine { document, out, name } = *self;
+        let mut line = document.finish(result)?;
+        line.write_all(b"\n")?;
+        let mut out = out.lock().unwrap();
+        line.copy_to(&mut *out)?;
+        out.flush()?;
+        Ok(name)
+    }
+}
+
+/// Rows in the `results` table (see `RESULTS_SCHEMA`) of a SQLite database,
+/// which may be the queue db. Rows are only ever added; consumers delete the
+/// ones they have taken. They are inserted on a dedicated writer thread, with
+/// whatever rows are waiting going in together in one transaction. Bodies are
+/// spooled until then, and read in one at a time as their row goes in.
+pub struct SqliteSink {
+    rows: Sender<Row>,
+    path: PathBuf,
+}
+
+/// How many waiting rows the writer puts in one transaction, at most.
+const ROW_BATCH: usize = 256;
+
+/// A committed result on its way to the writer, and where its row id goes.
+struct Row {
+    result: FeedResult,
+    headers: String,
+    body: Option<Spool>,
+    inserted: Sender<io::Result<i64>>,
+}
+
+impl SqliteSink {
+    /// Open the database at `path`, creating it and the table if need be, and
+    /// start the writer thread. Like the queue, it waits out other writers'
+    /// locks for up to `BUSY_TIMEOUT`.
+    pub fn open(path: &Path) -> rusqlite::Result<Self> {
+        let mut conn = Connection::open(path)?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        conn.execute_batch(RESULTS_SCHEMA)?;
+        let (rows, received) = channel::<Row>();
+
+        //Runs until every pending result and the sink itself are gone
+        std::thread::spawn(move || {
+            while let Ok(row) = received.recv() {
+                let mut batch = vec![row];
+                while batch.len() < ROW_BATCH {
+                    match received.try_recv() {
+                        Ok(row) => batch.push(row),
+                        Err(_) => break,
+                    }
+                }
+                match insert_rows(&mut conn, &mut batch) {
+                    Ok(ids) => {
+                        for (row, id) in batch.iter().zip(ids) {
+                            let _ = row.inserted.send(Ok(id));
+                        }
+                    }
+                    Err(e) => {
+                        for row in &batch {
+                            let _ = row.inserted.send(Err(io::Error::other(e.to_string())));
+                        }
+                    }
+                }
+            }
+        });
+
+        Ok(Self { rows, path: path.to_path_buf() })
+    }
+}
+
+fn insert_rows(conn: &mut Connection, rows: &mut [Row]) -> rusqlite::Result<Vec<i64>> {
+    let tx = conn.transaction()?;
+    let mut ids = Vec::with_capacity(rows.len());
+    {
+        let mut insert = tx.prepare_cached(
+            "INSERT INTO results (feed_id, status, last_modified, etag, url, checked_at, headers, body)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        )?;
+        for row in rows {
+            let body = match row.body.take() {
+                Some(body) => Some(body.into_text().map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?),
+                None => None,
+            };
+            let result = &row.result;
+            insert.execute(params![
+                result.feed_id as i64,
+                result.status,
+                result.last_modified as i64,
+                result.etag,
+                result.url,
+                result.checked_at as i64,
+                row.headers,
+                body,
+            ])?;
+            ids.push(tx.last_insert_rowid());
+        }
+    }
+    tx.commit()?;
+    Ok(ids)
+}
+
+impl ResultSink for SqliteSink {
+    fn begin(&self, _result: &FeedResult) -> io::Result<Box<dyn PendingResult>> {
+        Ok(Box::new(PendingRow { body: Spool::new(), rows: self.rows.clone(), path: self.path.clone() }))
+    }
+
+    fn location(&self, _result: &FeedResult) -> String {
+        format!("{} results table", self.path.display())
+    }
+}
+
+/// A row whose body is being spooled.
+struct PendingRow {
+    body: Spool,
+    rows: Sender<Row>,
+    path: PathBuf,
+}
+
+impl Write for PendingRow {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.body.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.body.flush()
+    }
+}
+
+impl PendingResult for PendingRow {
+    fn commit(self: Box<Self>, result: &FeedResult) -> io::Result<String> {
+        let PendingRow { body, rows, path } = *self;
+        let headers = serde_json::to_string(&headers(result)).map_err(io::Error::other)?;
+        let body = Some(body).filter(|body| !body.is_empty());
+        let (inserted, id) = channel();
+        let row = Row { result: result.clone(), headers, body, inserted };
+        rows.send(row).map_err(|_| io::Error::other("the results writer has stopped"))?;
+        //Not committed until the row is in
+        let id = id.recv().map_err(|_| io::Error::other("the results writer has stopped"))??;
+        Ok(format!("{} results row {}", path.display(), id))
+    }
+}
+
+/// One JSON document per result in a directory, named like feed files but
+/// ending `.json`, with permanent redirect stubs in a `redirects` directory
+/// inside it. Each document is renamed into place once complete.
+pub struct JsonDirSink {
+    dir: PathBuf,
+    redirects_dir: PathBuf,
+    namer: Arc<FeedFileNamer>,
+    fsync: bool,
+}
+
+impl JsonDirSink {
+    /// `namer` should name `.json` files (see `FeedFileNamer::with_extension`).
+    pub fn new(dir: PathBuf, namer: FeedFileNamer, fsync: bool) -> Self {
+        let redirects_dir = Self::redirects_dir_in(&dir);
+        Self { dir, redirects_dir, namer: Arc::new(namer), fsync }
+    }
+
+    /// Where the stubs go for a sink writing to `dir`.
+    pub fn redirects_dir_in(dir: &Path) -> PathBuf {
+        dir.join("redirects")
+    }
+
+    fn dir_for(&self, result: &FeedResult) -> &Path {
+        match result.is_redirect_stub() {
+            true => &self.redirects_dir,
+            false => &self.dir,
+        }
+    }
+}
+
+impl ResultSink for JsonDirSink {
+    fn begin(&self, result: &FeedResult) -> io::Result<Box<dyn PendingResult>> {
+        let file = self.namer.create(self.dir_for(result), result.feed_id, result.status, self.fsync)?;
+        Ok(Box::new(PendingDocument {
+            document: StreamedDocument::new(file),
+            namer: self.namer.clone(),
+            feed_id: result.feed_id,
+        }))
+    }
+
+    fn location(&self, result: &FeedResult) -> String {
+        self.dir_for(result).join(self.namer.preview_name(result.feed_id, result.status)).display().to_string()
+    }
+}
+
+/// A document streamed straight into its temp file.
+struct PendingDocument {
+    document: StreamedDocument<PendingFeedFile>,
+    namer: Arc<FeedFileNamer>,
+    feed_id: u64,
+}
+
+impl Write for PendingDocument {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.document.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.document.flush()
+    }
+}
+
+impl PendingResult for PendingDocument {
+    fn commit(self: Box<Self>, result: &FeedResult) -> io::Result<String> {
+        let file = self.document.finish(result)?;
+        Ok(self.namer.commit(self.feed_id, file)?.display().to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    use crate::feedfile::NamingPolicy;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("aggrivator-sink-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("redirects")).unwrap();
+        dir
+    }
+
+    fn result(status: u16) -> FeedResult {
+        FeedResult {
+            feed_id: 7,
+            status,
+            last_modified: 1_700_000_000,
+            etag: "\"abc\"".to_string(),
+            url: "https://example.com/feed.xml".to_string(),
+            checked_at: 1_800_000_000,
+            lines: vec![
+                ("Attempts".to_string(), "1".to_string()),
+                (TIMING_LINE.to_string(), "connect=20ms ttfb=90ms".to_string()),
+            ],
+        }
+    }
+
+    /// Stream a body into `sink` the way a check does, finishing the timing
+    /// line before the commit.
+    fn write(sink: &dyn ResultSink, mut result: FeedResult, body: &str) -> String {
+        let mut pending = sink.begin(&result).unwrap();
+        //In pieces that split characters, as they come off the wire
+        for chunk in body.as_bytes().chunks(1001) {
+            pending.write_all(chunk).unwrap();
+        }
+        let timing = format!("{} body=5ms total=100ms", result.line(TIMING_LINE).unwrap());
+        result.set_line(TIMING_LINE, timing);
+        pending.commit(&result).unwrap()
+    }
+
+    #[test]
+    fn files_get_the_finished_timing_line() {
+        let dir = temp_dir("files");
+        let namer = FeedFileNamer::new(NamingPolicy::Replace, 1);
+        let sink = FileSink::new(dir.clone(), dir.join("redirects"), namer, false, false);
+
+        let path = write(&sink, result(200), "<rss/>");
+        assert_eq!(path, dir.join("7_200.txt").display().to_string());
+        let written = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = written.lines().collect();
+        assert_eq!(lines[..5], ["1700000000", "\"abc\"", "https://example.com/feed.xml", "1800000000", "X-Aggrivator-Attempts: 1"]);
+        assert_eq!(lines[5..], ["<rss/>", "X-Aggrivator-Timing: connect=20ms ttfb=90ms body=5ms total=100ms"]);
+
+        let stub = sink.begin(&result(301)).unwrap().commit(&result(301)).unwrap();
+        assert_eq!(stub, dir.join("redirects").join("7_301.txt").display().to_string());
+        let moved = sink.begin(&result(CONTENT_MOVE_STATUS)).unwrap().commit(&result(CONTENT_MOVE_STATUS)).unwrap();
+        assert_eq!(moved, dir.join("redirects").join("7_690.txt").display().to_string());
+        assert!(fs::read_to_string(&stub).unwrap().ends_with("X-Aggrivator-Timing: connect=20ms ttfb=90ms\n"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn legacy_files_have_four_line_headers() {
+        let dir = temp_dir("legacy");
+        let namer = FeedFileNamer::new(NamingPolicy::Replace, 1);
+        let sink = FileSink::new(dir.clone(), dir.join("redirects"), namer, false, true);
+
+        let path = write(&sink, result(200), "<rss/>");
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "1700000000\n\"abc\"\nhttps://example.com/feed.xml\n1800000000\n<rss/>"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn json_lines_carry_the_finished_result() {
+        let out = Shared::default();
+        let sink = JsonLinesSink::new(out.clone(), "test");
+        assert_eq!(write(&sink, result(200), "<rss/>"), "test");
+        sink.begin(&result(304)).unwrap().commit(&result(304)).unwrap();
+        //Never committed, never written
+        drop(sink.begin(&result(500)).unwrap());
+
+        let text = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<serde_json::Value> = text.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["feed_id"], 7);
+        assert_eq!(lines[0]["status"], 200);
+        assert_eq!(lines[0]["body"], "<rss/>");
+        assert_eq!(lines[0]["headers"][1]["name"], "Timing");
+        assert_eq!(lines[0]["headers"][1]["value"], "connect=20ms ttfb=90ms body=5ms total=100ms");
+        assert_eq!(lines[1]["status"], 304);
+        assert_eq!(lines[1]["body"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn sqlite_adds_a_row_per_result() {
+        let dir = temp_dir("sqlite");
+        let sink = SqliteSink::open(&dir.join("results.db")).unwrap();
+        write(&sink, result(200), "<rss/>");
+        write(&sink, result(404), "");
+
+        let conn = Connection::open(dir.join("results.db")).unwrap();
+        let rows: Vec<(i64, i64, String, Option<String>)> = conn
+            .prepare("SELECT feed_id, status, headers, body FROM results ORDER BY id")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!((rows[0].0, rows[0].1, rows[0].3.as_deref()), (7, 200, Some("<rss/>")));
+        assert_eq!((rows[1].1, rows[1].3.as_deref()), (404, None));
+        let headers: serde_json::Value = serde_json::from_str(&rows[0].2).unwrap();
+        assert_eq!(headers[0], serde_json::json!({"name": "Attempts", "value": "1"}));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sqlite_waits_out_another_writer() {
+        let dir = temp_dir("sqlite-busy");
+        let sink = Arc::new(SqliteSink::open(&dir.join("results.db")).unwrap());
+        let other = Connection::open(dir.join("results.db")).unwrap();
+        other.execute_batch("BEGIN IMMEDIATE").unwrap();
+
+        //Every check commits while the other writer holds the lock
+        let checks: Vec<_> = (0..8)
+            .map(|_| {
+                let sink = sink.clone();
+                std::thread::spawn(move || write(sink.as_ref(), result(200), "<rss/>"))
+            })
+            .collect();
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        other.execute_batch("COMMIT").unwrap();
+
+        let mut locations: Vec<String> = checks.into_iter().map(|check| check.join().unwrap()).collect();
+        locations.sort();
+        locations.dedup();
+        assert_eq!(locations.len(), 8);
+        let rows: i64 = other.query_row("SELECT COUNT(*) FROM results", [], |row| row.get(0)).unwrap();
+        assert_eq!(rows, 8);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn json_dir_writes_a_document_per_result() {
+        let dir = temp_dir("json-dir");
+        let namer = FeedFileNamer::new(NamingPolicy::Replace, 1).with_extension("json");
+        let sink = JsonDirSink::new(dir.clone(), namer, false);
+
+        let path = write(&sink, result(200), "<rss/>");
+        assert_eq!(path, dir.join("7_200.json").display().to_string());
+        let document: serde_json::Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(document["url"], "https://example.com/feed.xml");
+        assert_eq!(document["body"], "<rss/>");
+
+        assert_eq!(sink.location(&result(308)), dir.join("redirects").join("7_308.json").display().to_string());
+        sink.begin(&result(308)).unwrap().commit(&result(308)).unwrap();
+        assert!(dir.join("redirects").join("7_308.json").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn large_bodies_stream_through_every_sink() {
+        let dir = temp_dir("large");
+        let body = "<item title=\"caf\u{e9} \u{1f3a7}\">\t\u{1}\\</item>\n".repeat(SPOOLED_IN_MEMORY / 8);
+        assert!(body.len() > 4 * SPOOLED_IN_MEMORY);
+
+        let mut spool = Spool::new();
+        spool.write_all(body.as_bytes()).unwrap();
+        assert!(spool.spilled.is_some());
+        assert_eq!(spool.into_text().unwrap(), body);
+
+        let out = Shared::default();
+        write(&JsonLinesSink::new(out.clone(), "test"), result(200), &body);
+        let line: serde_json::Value = serde_json::from_slice(&out.0.lock().unwrap()).unwrap();
+        assert_eq!(line["body"], body.as_str());
+        assert_eq!(line["headers"][1]["value"], "connect=20ms ttfb=90ms body=5ms total=100ms");
+
+        write(&SqliteSink::open(&dir.join("results.db")).unwrap(), result(200), &body);
+        let conn = Connection::open(dir.join("results.db")).unwrap();
+        let stored: String = conn.query_row("SELECT body FROM results", [], |row| row.get(0)).unwrap();
+        assert_eq!(stored, body);
+
+        let namer = FeedFileNamer::new(NamingPolicy::Replace, 1).with_extension("json");
+        let path = write(&JsonDirSink::new(dir.clone(), namer, false), result(200), &body);
+        let document: serde_json::Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(document["body"], body.as_str());
+        assert_eq!(document["feed_id"], 7);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn streamed_bodies_replace_what_isnt_utf8() {
+        let mut document = StreamedDocument::new(Vec::new());
+        document.write_all(b"a\xffb\xc3").unwrap();
+        document.write_all(b"\xa9c\xe2\x82").unwrap();
+        let written = document.finish(&result(200)).unwrap();
+        let document: serde_json::Value = serde_json::from_slice(&written).unwrap();
+        assert_eq!(document["body"], "a\u{fffd}b\u{e9}c\u{fffd}");
+    }
+}
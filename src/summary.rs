@@ -0,0 +1,642 @@
+//! End-of-run summary. Every checked feed is folded into running totals as it
+//! finishes, and at the end of the run those are rolled up into counts by
+//! status and error class, latency percentiles (overall and per connection
+//! phase, from bucketed histograms), the
+//! slowest and largest feeds, the hosts that failed most or answered slowest,
+//! and how feeds fared over IPv4 and IPv6. The roll-up prints for people and
+//! serializes to JSON for dashboards.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::timing::Timings;
+
+/// How many feeds the slowest, largest, failing and slow host lists keep.
+pub const TOP_COUNT: usize = 10;
+
+/// One checked feed.
+#[derive(Debug, Clone)]
+pub struct FeedRecord {
+    pub feed_id: u64,
+    pub url: String,
+    pub host: String,
+    /// The http status, or the pseudo-status of a failed fetch.
+    pub status: u16,
+    /// The error reason (`dns_nxdomain` and friends) when the fetch failed.
+    pub error: Option<String>,
+    /// Whether the feed came back with new content.
+    pub updated: bool,
+    /// Body bytes downloaded.
+    pub bytes: u64,
+    /// Time spent on the feed, retries included.
+    pub elapsed: Duration,
+    /// Where the time went in the final request, and the address it went to.
+    pub timings: Timings,
+}
+
+impl FeedRecord {
+    /// Anything that didn't get a usable response: an error status or a
+    /// failed fetch.
+    pub fn failed(&self) -> bool {
+        self.error.is_some() || self.status >= 400
+    }
+}
+
+/// The feeds checked so far in a run, folded into running totals as each one
+/// is recorded: nothing is kept per feed beyond the top lists, and per host
+/// only counts and bucketed latencies. Safe to record into from every fetch
+/// at once.
+#[derive(Default)]
+pub struct RunSummary {
+    totals: Mutex<Totals>,
+}
+
+#[derive(Default)]
+struct Totals {
+    feeds: usize,
+    changed: usize,
+    not_modified: usize,
+    bytes: u64,
+    by_status: BTreeMap<u16, usize>,
+    by_error: BTreeMap<String, usize>,
+    latency: Histogram,
+    dns: Histogram,
+    connect: Histogram,
+    tls: Histogram,
+    ttfb: Histogram,
+    body: Histogram,
+    families: BTreeMap<&'static str, FamilyTotals>,
+    hosts: HashMap<String, HostTotals>,
+    slowest: Top,
+    largest: Top,
+}
+
+#[derive(Default)]
+struct FamilyTotals {
+    feeds: usize,
+    failures: usize,
+    connect: Histogram,
+}
+
+#[derive(Default)]
+struct HostTotals {
+    feeds: usize,
+    failures: usize,
+    /// Feeds that got as far as response headers, which the latencies are over.
+    answered: usize,
+    ttfb: Histogram,
+    connect: Histogram,
+    remote_addr: Option<SocketAddr>,
+}
+
+/// Values under this many milliseconds get a bucket each. Above it every
+/// doubling is split into `SUB_BUCKETS`, so a bucket is within 1/64th of
+/// the values in it.
+const EXACT_BELOW: u64 = 128;
+const SUB_BUCKETS: u64 = 64;
+
+/// Counts of millisecond values in fixed log-linear buckets. Only buckets
+/// that have been hit are stored.
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: BTreeMap<u16, u64>,
+    count: u64,
+    max: u64,
+}
+
+impl Histogram {
+    fn add(&mut self, duration: Option<Duration>) {
+        if let Some(duration) = duration {
+            let millis = duration.as_millis() as u64;
+            *self.buckets.entry(bucket(millis)).or_insert(0) += 1;
+            self.count += 1;
+            self.max = self.max.max(millis);
+        }
+    }
+
+    /// Nearest-rank percentiles, each the top of the bucket it falls in
+    /// (or the largest value, when that's lower).
+    fn latency(&self) -> Latency {
+        Latency {
+            p50: self.percentile(50),
+            p95: self.percentile(95),
+            p99: self.percentile(99),
+            max: self.max,
+        }
+    }
+
+    fn percentile(&self, percent: u64) -> u64 {
+        let rank = (percent * self.count).div_ceil(100).max(1);
+        let mut seen = 0;
+        for (&index, &count) in &self.buckets {
+            seen += count;
+            if seen >= rank {
+                return bucket_top(index).min(self.max);
+            }
+        }
+        0
+    }
+}
+
+fn bucket(millis: u64) -> u16 {
+    if millis < EXACT_BELOW {
+        return millis as u16;
+    }
+    let doubling = 63 - millis.leading_zeros() as u64;
+    let shift = doubling - SUB_BUCKETS.trailing_zeros() as u64;
+    let sub = (millis >> shift) - SUB_BUCKETS;
+    (EXACT_BELOW + (doubling - EXACT_BELOW.trailing_zeros() as u64) * SUB_BUCKETS + sub) as u16
+}
+
+/// The biggest value that lands in bucket `index`.
+fn bucket_top(index: u16) -> u64 {
+    let index = index as u64;
+    if index < EXACT_BELOW {
+        return index;
+    }
+    let above = index - EXACT_BELOW;
+    let shift = above / SUB_BUCKETS + EXACT_BELOW.trailing_zeros() as u64 - SUB_BUCKETS.trailing_zeros() as u64;
+    let bottom = (SUB_BUCKETS + above % SUB_BUCKETS) << shift;
+    bottom + ((1u64 << shift) - 1)
+}
+
+/// The `TOP_COUNT` feeds with the biggest non-zero key so far, biggest
+/// first. On a tie the feed recorded first stays ahead.
+#[derive(Default)]
+struct Top {
+    ranked: Vec<(u64, FeedStat)>,
+}
+
+impl Top {
+    fn offer(&mut self, key: u64, record: &FeedRecord) {
+        if key == 0 || (self.ranked.len() == TOP_COUNT && self.ranked[TOP_COUNT - 1].0 >= key) {
+            return;
+        }
+        let at = self.ranked.partition_point(|(ranked, _)| *ranked >= key);
+        self.ranked.insert(at, (key, FeedStat::of(record)));
+        self.ranked.truncate(TOP_COUNT);
+    }
+
+    fn feeds(&self) -> Vec<FeedStat> {
+        self.ranked.iter().map(|(_, feed)| feed.clone()).collect()
+    }
+}
+
+/// Latency percentiles, in milliseconds. Exact under 128ms, and within 1/64th
+/// above that.
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct Latency {
+    pub p50: u64,
+    pub p95: u64,
+    pub p99: u64,
+    pub max: u64,
+}
+
+/// A feed in one of the top lists.
+#[derive(Debug, Clone, Serialize)]
+pub struct FeedStat {
+    pub feed_id: u64,
+    pub url: String,
+    pub status: u16,
+    pub bytes: u64,
+    pub elapsed_ms: u64,
+    pub remote_addr: Option<String>,
+}
+
+/// Latency of each phase, over the feeds whose final request went through it.
+/// A reused connection has no dns, connect or tls phase.
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct PhaseLatency {
+    pub dns: Latency,
+    pub connect: Latency,
+    pub tls: Latency,
+    pub ttfb: Latency,
+    pub body: Latency,
+}
+
+/// How the feeds that went to addresses of one family (`ipv4` or `ipv6`)
+/// fared. A feed that never got as far as connecting has no family.
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct FamilyStats {
+    pub feeds: usize,
+    pub failures: usize,
+    pub connect_ms: Latency,
+}
+
+/// A host in the slow host list.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HostLatency {
+    pub host: String,
+    pub feeds: usize,
+    /// Median time to the response headers, connecting included.
+    pub ttfb_p50_ms: u64,
+    pub connect_p50_ms: u64,
+    /// The address the last of its feeds went to.
+    pub remote_addr: Option<String>,
+}
+
+/// A host with failed checks, and how many of its feeds were checked.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HostFailures {
+    pub host: String,
+    pub failures: usize,
+    pub feeds: usize,
+}
+
+/// The rolled up summary of a run.
+#[derive(Debug, Clone, Serialize)]
+pub struct SummaryReport {
+    /// Unix time the run finished.
+    pub finished_at: u64,
+    pub duration_secs: f64,
+    pub feeds: usize,
+    pub by_status: BTreeMap<u16, usize>,
+    pub by_error: BTreeMap<String, usize>,
+    /// Feeds that came back with new content.
+    pub changed: usize,
+    /// Feeds that answered `304 Not Modified`.
+    pub not_modified: usize,
+    pub bytes: u64,
+    pub latency_ms: Latency,
+    pub phases_ms: PhaseLatency,
+    pub by_family: BTreeMap<String, FamilyStats>,
+    pub slowest: Vec<FeedStat>,
+    pub largest: Vec<FeedStat>,
+    pub failing_hosts: Vec<HostFailures>,
+    pub slow_hosts: Vec<HostLatency>,
+}
+
+impl RunSummary {
+    pub fn record(&self, record: FeedRecord) {
+        let mut totals = self.totals.lock().unwrap();
+        let totals = &mut *totals;
+        let failed = record.failed();
+        let timings = &record.timings;
+
+        totals.feeds += 1;
+        totals.changed += record.updated as usize;
+        totals.not_modified += (record.status == 304) as usize;
+        totals.bytes += record.bytes;
+        *totals.by_status.entry(record.status).or_insert(0) += 1;
+        if let Some(error) = &record.error {
+            *totals.by_error.entry(error.clone()).or_insert(0) += 1;
+        }
+
+        totals.latency.add(Some(record.elapsed));
+        totals.dns.add(timings.dns);
+        totals.connect.add(timings.connect);
+        totals.tls.add(timings.tls);
+        totals.ttfb.add(timings.ttfb);
+        totals.body.add(timings.body);
+
+        if let Some(addr) = timings.remote_addr {
+            let family = totals.families.entry(if addr.is_ipv6() { "ipv6" } else { "ipv4" }).or_default();
+            family.feeds += 1;
+            family.failures += failed as usize;
+            family.connect.add(timings.connect);
+        }
+
+        let host = match totals.hosts.get_mut(record.host.as_str()) {
+            Some(host) => host,
+            None => totals.hosts.entry(record.host.clone()).or_default(),
+        };
+        host.feeds += 1;
+        host.failures += failed as usize;
+        if timings.ttfb.is_some() {
+            host.answered += 1;
+            host.ttfb.add(timings.ttfb);
+            host.connect.add(timings.connect);
+            if timings.remote_addr.is_some() {
+                host.remote_addr = timings.remote_addr;
+            }
+        }
+
+        totals.slowest.offer(record.elapsed.as_millis() as u64, &record);
+        totals.largest.offer(record.bytes, &record);
+    }
+
+    /// Roll up everything recorded so far, for a run that took `duration`
+    /// and finished at unix time `finished_at`.
+    pub fn report(&self, duration: Duration, finished_at: u64) -> SummaryReport {
+        let totals = self.totals.lock().unwrap();
+
+        let by_family = totals
+            .families
+            .iter()
+            .map(|(family, totals)| {
+                let stats = FamilyStats {
+                    feeds: totals.feeds,
+                    failures: totals.failures,
+                    connect_ms: totals.connect.latency(),
+                };
+                (family.to_string(), stats)
+            })
+            .collect();
+
+        let mut slow_hosts: Vec<HostLatency> = totals
+            .hosts
+            .iter()
+            .filter(|(_, host)| host.answered > 0)
+            .map(|(name, host)| HostLatency {
+                host: name.clone(),
+                feeds: host.answered,
+                ttfb_p50_ms: host.ttfb.percentile(50),
+                connect_p50_ms: host.connect.percentile(50),
+                remote_addr: host.remote_addr.map(|addr| addr.to_string()),
+            })
+            .collect();
+        slow_hosts.sort_by(|a, b| b.ttfb_p50_ms.cmp(&a.ttfb_p50_ms).then_with(|| a.host.cmp(&b.host)));
+        slow_hosts.truncate(TOP_COUNT);
+
+        let mut failing_hosts: Vec<HostFailures> = totals
+            .hosts
+            .iter()
+            .filter(|(_, host)| host.failures > 0)
+            .map(|(name, host)| HostFailures { host: name.clone(), failures: host.failures, feeds: host.feeds })
+            .collect();
+        failing_hosts.sort_by(|a, b| b.failures.cmp(&a.failures).then_with(|| a.host.cmp(&b.host)));
+        failing_hosts.truncate(TOP_COUNT);
+
+        SummaryReport {
+            finished_at,
+            duration_secs: duration.as_secs_f64(),
+            feeds: totals.feeds,
+            by_status: totals.by_status.clone(),
+            by_error: totals.by_error.clone(),
+            changed: totals.changed,
+            not_modified: totals.not_modified,
+            bytes: totals.bytes,
+            latency_ms: totals.latency.latency(),
+            phases_ms: PhaseLatency {
+                dns: totals.dns.latency(),
+                connect: totals.connect.latency(),
+                tls: totals.tls.latency(),
+                ttfb: totals.ttfb.latency(),
+                body: totals.body.latency(),
+            },
+            by_family,
+            slowest: totals.slowest.feeds(),
+            largest: totals.largest.feeds(),
+            failing_hosts,
+            slow_hosts,
+        }
+    }
+}
+
+impl FeedStat {
+    fn of(record: &FeedRecord) -> Self {
+        FeedStat {
+            feed_id: record.feed_id,
+            url: record.url.clone(),
+            status: record.status,
+            bytes: record.bytes,
+            elapsed_ms: record.elapsed.as_millis() as u64,
+            remote_addr: record.timings.remote_addr.map(|addr| addr.to_string()),
+        }
+    }
+}
+
+impl SummaryReport {
+    /// Write the report to `path` as JSON. It's written next to it first and
+    /// renamed into place, so a dashboard never reads half a file.
+    pub fn write_json(&self, path: &Path) -> io::Result<()> {
+        let json = serde_json::to_string_pretty(self).map_err(io::Error::other)?;
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(format!(".{}.tmp", std::process::id()));
+        fs::write(&temp, json)?;
+        fs::rename(&temp, path)
+    }
+}
+
+impl fmt::Display for SummaryReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "  Duration:     {:.1}s", self.duration_secs)?;
+        writeln!(f, "  Checked:      {}", self.feeds)?;
+        writeln!(f, "  Changed:      {}", self.changed)?;
+        writeln!(f, "  Not modified: {}", self.not_modified)?;
+        writeln!(f, "  Downloaded:   {} bytes", self.bytes)?;
+        writeln!(
+            f,
+            "  Latency:      p50 {}ms, p95 {}ms, p99 {}ms, max {}ms",
+            self.latency_ms.p50, self.latency_ms.p95, self.latency_ms.p99, self.latency_ms.max
+        )?;
+        let phases = [
+            ("dns", &self.phases_ms.dns),
+            ("connect", &self.phases_ms.connect),
+            ("tls", &self.phases_ms.tls),
+            ("ttfb", &self.phases_ms.ttfb),
+            ("body", &self.phases_ms.body),
+        ];
+        writeln!(f, "  Phases:")?;
+        for (name, latency) in phases {
+            writeln!(f, "    {:<8} p50 {}ms, p95 {}ms, max {}ms", name, latency.p50, latency.p95, latency.max)?;
+        }
+        if !self.by_family.is_empty() {
+            writeln!(f, "  By address family:")?;
+            for (family, stats) in &self.by_family {
+                writeln!(
+                    f,
+                    "    {}: {} feeds, {} failed, connect p50 {}ms, max {}ms",
+                    family, stats.feeds, stats.failures, stats.connect_ms.p50, stats.connect_ms.max
+                )?;
+            }
+        }
+        writeln!(f, "  By status:")?;
+        for (status, count) in &self.by_status {
+            writeln!(f, "    {}: {}", status, count)?;
+        }
+        if !self.by_error.is_empty() {
+            writeln!(f, "  By error:")?;
+            for (error, count) in &self.by_error {
+                writeln!(f, "    {}: {}", error, count)?;
+            }
+        }
+        if !self.slowest.is_empty() {
+            writeln!(f, "  Slowest:")?;
+            for feed in &self.slowest {
+                writeln!(f, "    {}ms [{}] {} ({})", feed.elapsed_ms, feed.feed_id, feed.url, feed.status)?;
+            }
+        }
+        if !self.largest.is_empty() {
+            writeln!(f, "  Largest:")?;
+            for feed in &self.largest {
+                writeln!(f, "    {} bytes [{}] {}", feed.bytes, feed.feed_id, feed.url)?;
+            }
+        }
+        if !self.failing_hosts.is_empty() {
+            writeln!(f, "  Failing hosts:")?;
+            for host in &self.failing_hosts {
+                writeln!(f, "    {}: {} of {} feeds failed", host.host, host.failures, host.feeds)?;
+            }
+        }
+        if !self.slow_hosts.is_empty() {
+            writeln!(f, "  Slow hosts:")?;
+            for host in &self.slow_hosts {
+                let addr = host.remote_addr.as_deref().unwrap_or("-");
+                writeln!(
+                    f,
+                    "    {}ms ttfb, {}ms connect: {} via {} ({} feeds)",
+                    host.ttfb_p50_ms, host.connect_p50_ms, host.host, addr, host.feeds
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(feed_id: u64, host: &str, status: u16, bytes: u64, elapsed_ms: u64) -> FeedRecord {
+        FeedRecord {
+            feed_id,
+            url: format!("https://{}/{}.xml", host, feed_id),
+            host: host.to_string(),
+            status,
+            error: None,
+            updated: status == 200,
+            bytes,
+            elapsed: Duration::from_millis(elapsed_ms),
+            timings: Timings::default(),
+        }
+    }
+
+    fn timed(mut record: FeedRecord, addr: &str, connect_ms: Option<u64>, ttfb_ms: Option<u64>) -> FeedRecord {
+        record.timings = Timings {
+            remote_addr: Some(addr.parse().unwrap()),
+            connect: connect_ms.map(Duration::from_millis),
+            ttfb: ttfb_ms.map(Duration::from_millis),
+            ..Timings::default()
+        };
+        record
+    }
+
+    fn histogram(millis: impl IntoIterator<Item = u64>) -> Histogram {
+        let mut histogram = Histogram::default();
+        for value in millis {
+            histogram.add(Some(Duration::from_millis(value)));
+        }
+        histogram
+    }
+
+    #[test]
+    fn nearest_rank_percentiles() {
+        let values = histogram(1..=100);
+        assert_eq!(values.latency(), Latency { p50: 50, p95: 95, p99: 99, max: 100 });
+        assert_eq!(histogram([7]).percentile(99), 7);
+        assert_eq!(histogram([1, 2, 3]).percentile(50), 2);
+        assert_eq!(histogram([]).latency(), Latency::default());
+    }
+
+    #[test]
+    fn buckets_stay_within_a_sixty_fourth() {
+        let mut last = None;
+        for millis in (0..1_000_000).step_by(7).chain([u64::MAX / 2, u64::MAX]) {
+            let index = bucket(millis);
+            let top = bucket_top(index);
+            assert!(top >= millis && top - millis <= millis / 64, "{} went in a bucket topped at {}", millis, top);
+            assert!(last.is_none_or(|last| index >= last));
+            last = Some(index);
+        }
+        //Big values are reported as the largest seen, not the top of their bucket
+        assert_eq!(histogram([1_000, 30_000]).percentile(99), 30_000);
+        assert_eq!(histogram([1_000, 1_001, 30_000]).percentile(50), 1_007);
+    }
+
+    #[test]
+    fn keeps_only_the_top_feeds() {
+        let summary = RunSummary::default();
+        for feed_id in 1..=1_000 {
+            summary.record(record(feed_id, "a.example", 200, feed_id % 100, feed_id));
+        }
+        let report = summary.report(Duration::from_secs(1), 1);
+        assert_eq!(report.slowest.len(), TOP_COUNT);
+        assert_eq!(report.slowest[0].feed_id, 1_000);
+        assert_eq!(report.slowest[TOP_COUNT - 1].feed_id, 991);
+        //Ties go to the feed recorded first
+        assert_eq!(report.largest.iter().map(|feed| feed.feed_id).take(3).collect::<Vec<_>>(), vec![99, 199, 299]);
+    }
+
+    #[test]
+    fn rolls_up_statuses_errors_and_hosts() {
+        let summary = RunSummary::default();
+        summary.record(record(1, "a.example", 200, 5_000, 120));
+        summary.record(record(2, "a.example", 304, 0, 40));
+        summary.record(record(3, "b.example", 503, 0, 900));
+        summary.record(record(4, "b.example", 200, 90_000, 300));
+        let mut failed = record(5, "c.example", 670, 0, 10);
+        failed.error = Some("dns_nxdomain".to_string());
+        summary.record(failed);
+
+        let report = summary.report(Duration::from_secs(3), 1_700_000_000);
+        assert_eq!(report.feeds, 5);
+        assert_eq!(report.changed, 2);
+        assert_eq!(report.not_modified, 1);
+        assert_eq!(report.bytes, 95_000);
+        assert_eq!(report.by_status.get(&200), Some(&2));
+        assert_eq!(report.by_status.get(&670), Some(&1));
+        assert_eq!(report.by_error.get("dns_nxdomain"), Some(&1));
+        assert_eq!(report.latency_ms.max, 900);
+        assert_eq!(report.slowest[0].feed_id, 3);
+        assert_eq!(report.largest.iter().map(|feed| feed.feed_id).collect::<Vec<_>>(), vec![4, 1]);
+        assert_eq!(
+            report.failing_hosts,
+            vec![
+                HostFailures { host: "b.example".to_string(), failures: 1, feeds: 2 },
+                HostFailures { host: "c.example".to_string(), failures: 1, feeds: 1 },
+            ]
+        );
+    }
+
+    #[test]
+    fn rolls_up_phases_families_and_slow_hosts() {
+        let summary = RunSummary::default();
+        summary.record(timed(record(1, "a.example", 200, 10, 100), "192.0.2.1:443", Some(20), Some(80)));
+        summary.record(timed(record(2, "a.example", 304, 0, 50), "192.0.2.1:443", None, Some(30)));
+        let mut black_hole = timed(record(3, "b.example", 670, 0, 30_000), "[2001:db8::1]:443", Some(30_000), None);
+        black_hole.error = Some("timeout".to_string());
+        summary.record(black_hole);
+        summary.record(timed(record(4, "c.example", 200, 10, 2_000), "[2001:db8::2]:443", Some(900), Some(1_500)));
+        summary.record(record(5, "d.example", 670, 0, 5));
+
+        let report = summary.report(Duration::from_secs(30), 1);
+        assert_eq!(report.phases_ms.connect.max, 30_000);
+        assert_eq!(report.phases_ms.ttfb, Latency { p50: 80, p95: 1_500, p99: 1_500, max: 1_500 });
+        assert_eq!(report.phases_ms.dns, Latency::default());
+        assert_eq!(report.by_family["ipv4"], FamilyStats {
+            feeds: 2,
+            failures: 0,
+            connect_ms: Latency { p50: 20, p95: 20, p99: 20, max: 20 },
+        });
+        assert_eq!(report.by_family["ipv6"].feeds, 2);
+        assert_eq!(report.by_family["ipv6"].failures, 1);
+        assert_eq!(report.by_family["ipv6"].connect_ms.max, 30_000);
+        assert_eq!(report.slow_hosts.iter().map(|host| host.host.as_str()).collect::<Vec<_>>(), vec!["c.example", "a.example"]);
+        assert_eq!(report.slow_hosts[0].remote_addr.as_deref(), Some("[2001:db8::2]:443"));
+        assert_eq!(report.slowest[0].remote_addr.as_deref(), Some("[2001:db8::1]:443"));
+        assert!(report.to_string().contains("ipv6: 2 feeds, 1 failed"));
+    }
+
+    #[test]
+    fn writes_json() {
+        let summary = RunSummary::default();
+        summary.record(record(1, "a.example", 200, 10, 5));
+        let path = std::env::temp_dir().join(format!("aggrivator-summary-{}.json", std::process::id()));
+        summary.report(Duration::from_millis(1500), 1).write_json(&path).unwrap();
+
+        let json: serde_json::Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(json["feeds"], 1);
+        assert_eq!(json["by_status"]["200"], 1);
+        assert_eq!(json["latency_ms"]["p50"], 5);
+        fs::remove_file(&path).unwrap();
+    }
+}
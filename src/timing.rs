@@ -0,0 +1,373 @@
+//! Where the time of a single request goes: DNS, TCP connect, TLS, and the
+//! wait for the response headers. reqwest doesn't expose its connection
+//! phases, so they are marked from the hooks it does have: the DNS resolver it
+//! is built with (`TimingResolver`), a layer around its connector
+//! (`ConnectTiming`) and the TLS session store it hands rustls
+//! (`TimingSessions`), which rustls asks as it starts each handshake. All of
+//! them mark the request being `timed` on the current task, so a connection
+//! opened for someone else's request is never counted. The address the lookup
+//! put first is kept until the response says which one it came from, which
+//! is how an IPv6 address that swallows connections shows up.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use rustls::client::{ClientSessionMemoryCache, ClientSessionStore, Tls12ClientSessionValue, Tls13ClientSessionValue};
+use rustls::pki_types::ServerName;
+use rustls::NamedGroup;
+use tower_layer::Layer;
+use tower_service::Service;
+
+/// The start and end of one phase.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Phase {
+    pub start: Instant,
+    pub end: Instant,
+}
+
+impl Phase {
+    pub fn duration(&self) -> Duration {
+        self.end.saturating_duration_since(self.start)
+    }
+}
+
+/// The phases of one request. The connection phases are `None` when a pooled
+/// connection was reused, `dns` also when the host is an IP address, and `tls`
+/// for plain http. A phase cut short by an error or a timeout ends when the
+/// request gave up, so that's where the time shows.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RequestPhases {
+    pub dns: Option<Phase>,
+    pub connect: Option<Phase>,
+    pub tls: Option<Phase>,
+    /// From the connection being ready to the response headers arriving.
+    pub request: Option<Phase>,
+    /// From sending the request to the response headers arriving. `timed`
+    /// can't tell a response from a failure, so it's up to the caller to clear
+    /// this when the request failed.
+    pub ttfb: Option<Phase>,
+    /// The address the lookup put first, which is the first one tried. `None`
+    /// for a pooled connection or an IP address, where the response has it
+    /// instead.
+    pub remote_addr: Option<SocketAddr>,
+}
+
+/// How long a fetch spent in each phase of its final request, and where that
+/// request went. Phases it didn't go through are `None`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timings {
+    pub remote_addr: Option<SocketAddr>,
+    pub dns: Option<Duration>,
+    pub connect: Option<Duration>,
+    pub tls: Option<Duration>,
+    /// From sending the request to the response headers.
+    pub ttfb: Option<Duration>,
+    /// Reading the body.
+    pub body: Option<Duration>,
+    /// From the first request of the redirect chain to the end of the body.
+    pub total: Option<Duration>,
+}
+
+impl RequestPhases {
+    /// The durations of the phases, with `body` and `total` left for the
+    /// caller to fill in.
+    pub fn timings(&self) -> Timings {
+        Timings {
+            remote_addr: self.remote_addr,
+            dns: self.dns.map(|phase| phase.duration()),
+            connect: self.connect.map(|phase| phase.duration()),
+            tls: self.tls.map(|phase| phase.duration()),
+            ttfb: self.ttfb.map(|phase| phase.duration()),
+            body: None,
+            total: None,
+        }
+    }
+}
+
+/// `dns=12ms connect=30ms tls=55ms ttfb=240ms body=1200ms total=1500ms`,
+/// leaving out the phases that didn't happen.
+impl fmt::Display for Timings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let phases = [
+            ("dns", self.dns),
+            ("connect", self.connect),
+            ("tls", self.tls),
+            ("ttfb", self.ttfb),
+            ("body", self.body),
+            ("total", self.total),
+        ];
+        let mut first = true;
+        for (name, duration) in phases {
+            if let Some(duration) = duration {
+                write!(f, "{}{}={}ms", if first { "" } else { " " }, name, duration.as_millis())?;
+                first = false;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What was seen while a request was being timed.
+#[derive(Debug, Default)]
+struct Marks {
+    dns_start: Option<Instant>,
+    dns_end: Option<Instant>,
+    connect_start: Option<Instant>,
+    connect_end: Option<Instant>,
+    handshake_done: Option<Instant>,
+    remote_addr: Option<SocketAddr>,
+}
+
+tokio::task_local! {
+    static MARKS: RefCell<Marks>;
+}
+
+/// Note something about the request being timed on this task, if there is one.
+fn mark(note: impl FnOnce(&mut Marks)) {
+    let _ = MARKS.try_with(|marks| note(&mut marks.borrow_mut()));
+}
+
+impl Marks {
+    fn into_phases(self, started: Instant, ended: Instant, tls: bool) -> RequestPhases {
+        let phase = |start: Option<Instant>, end: Option<Instant>| {
+            start.map(|start| Phase { start, end: end.unwrap_or(ended).max(start) })
+        };
+        let ready = match (self.dns_start, self.connect_start) {
+            (None, None) => Some(started),
+            (Some(_), None) => None,
+            (_, Some(_)) if tls => self.handshake_done,
+            (_, Some(_)) => self.connect_end,
+        };
+        RequestPhases {
+            dns: phase(self.dns_start, self.dns_end),
+            connect: phase(self.connect_start, self.connect_end),
+            tls: phase(self.connect_end.filter(|_| tls), self.handshake_done),
+            request: phase(ready, Some(ended)),
+            ttfb: phase(Some(started), Some(ended)),
+            remote_addr: self.remote_addr,
+        }
+    }
+}
+
+/// Run `request` (a `send()` on a client built with the hooks below) and
+/// time its phases. `tls` says whether the url is https.
+pub async fn timed<F: Future>(tls: bool, request: F) -> (F::Output, RequestPhases) {
+    let started = Instant::now();
+    let (output, marks) = MARKS
+        .scope(RefCell::new(Marks::default()), async {
+            let output = request.await;
+            (output, MARKS.with(|marks| marks.take()))
+        })
+        .await;
+    (output, marks.into_phases(started, Instant::now(), tls))
+}
+
+/// The usual getaddrinfo lookup, timed for `timed`. Connecting starts as soon
+/// as it's done.
+#[derive(Debug, Default)]
+pub struct TimingResolver;
+
+impl Resolve for TimingResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        mark(|marks| {
+            marks.dns_start.get_or_insert_with(Instant::now);
+        });
+        Box::pin(async move {
+            //The port is replaced with the url's by the connector
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0)).await?.collect();
+            mark(|marks| {
+                let now = Instant::now();
+                marks.dns_end = Some(now);
+                marks.connect_start = Some(now);
+                marks.remote_addr = addrs.first().copied();
+            });
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// A layer around the client's connector, which marks when it's asked for a
+/// new connection and when that connection is ready for the request: connected
+/// for http, through the handshake for https.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConnectTiming;
+
+impl<S> Layer<S> for ConnectTiming {
+    type Service = TimedConnect<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        TimedConnect { inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TimedConnect<S> {
+    inner: S,
+}
+
+impl<S, R> Service<R> for TimedConnect<S>
+where
+    S: Service<R>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<S::Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: R) -> Self::Future {
+        //An IP address isn't looked up, so connecting starts here; a lookup moves it
+        mark(|marks| {
+            marks.connect_start.get_or_insert_with(Instant::now);
+        });
+        let connecting = self.inner.call(request);
+        Box::pin(async move {
+            let connection = connecting.await?;
+            mark(|marks| {
+                let now = Some(Instant::now());
+                match marks.connect_end {
+                    Some(_) => marks.handshake_done = now,
+                    None => marks.connect_end = now,
+                }
+            });
+            Ok(connection)
+        })
+    }
+}
+
+/// The TLS session cache the client's rustls config resumes sessions from.
+/// rustls asks it for a key exchange hint as it builds the ClientHello, which
+/// is the moment the TCP connection is up and the handshake starts.
+#[derive(Debug)]
+pub struct TimingSessions {
+    cache: ClientSessionMemoryCache,
+}
+
+impl TimingSessions {
+    /// Keep sessions for up to `size` servers.
+    pub fn new(size: usize) -> Arc<Self> {
+        Arc::new(Self { cache: ClientSessionMemoryCache::new(size) })
+    }
+}
+
+impl ClientSessionStore for TimingSessions {
+    fn set_kx_hint(&self, server_name: ServerName<'static>, group: NamedGroup) {
+        self.cache.set_kx_hint(server_name, group)
+    }
+
+    fn kx_hint(&self, server_name: &ServerName<'_>) -> Option<NamedGroup> {
+        mark(|marks| {
+            marks.connect_end.get_or_insert_with(Instant::now);
+        });
+        self.cache.kx_hint(server_name)
+    }
+
+    fn set_tls12_session(&self, server_name: ServerName<'static>, value: Tls12ClientSessionValue) {
+        self.cache.set_tls12_session(server_name, value)
+    }
+
+    fn tls12_session(&self, server_name: &ServerName<'_>) -> Option<Tls12ClientSessionValue> {
+        self.cache.tls12_session(server_name)
+    }
+
+    fn remove_tls12_session(&self, server_name: &ServerName<'static>) {
+        self.cache.remove_tls12_session(server_name)
+    }
+
+    fn insert_tls13_ticket(&self, server_name: ServerName<'static>, value: Tls13ClientSessionValue) {
+        self.cache.insert_tls13_ticket(server_name, value)
+    }
+
+    fn take_tls13_ticket(&self, server_name: &ServerName<'static>) -> Option<Tls13ClientSessionValue> {
+        self.cache.take_tls13_ticket(server_name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(base: Instant, ms: u64) -> Option<Instant> {
+        Some(base + Duration::from_millis(ms))
+    }
+
+    #[test]
+    fn splits_a_new_https_connection() {
+        let base = Instant::now();
+        let marks = Marks {
+            dns_start: at(base, 0),
+            dns_end: at(base, 10),
+            connect_start: at(base, 11),
+            connect_end: at(base, 40),
+            handshake_done: at(base, 90),
+            remote_addr: None,
+        };
+        let phases = marks.into_phases(base, base + Duration::from_millis(150), true);
+        assert_eq!(phases.dns.unwrap().duration(), Duration::from_millis(10));
+        assert_eq!(phases.connect.unwrap().duration(), Duration::from_millis(29));
+        assert_eq!(phases.tls.unwrap().duration(), Duration::from_millis(50));
+        assert_eq!(phases.request.unwrap().duration(), Duration::from_millis(60));
+        assert_eq!(phases.ttfb.unwrap().duration(), Duration::from_millis(150));
+
+        let mut timings = phases.timings();
+        timings.body = Some(Duration::from_millis(200));
+        timings.total = Some(Duration::from_millis(350));
+        assert_eq!(timings.to_string(), "dns=10ms connect=29ms tls=50ms ttfb=150ms body=200ms total=350ms");
+    }
+
+    #[test]
+    fn pooled_connection_is_all_request() {
+        let base = Instant::now();
+        let phases = Marks::default().into_phases(base, base + Duration::from_millis(20), true);
+        assert_eq!(phases.dns, None);
+        assert_eq!(phases.connect, None);
+        assert_eq!(phases.tls, None);
+        assert_eq!(phases.request.unwrap().duration(), Duration::from_millis(20));
+    }
+
+    #[test]
+    fn timeout_shows_in_the_phase_it_hit() {
+        let base = Instant::now();
+        let marks = Marks { connect_start: at(base, 5), ..Marks::default() };
+        let phases = marks.into_phases(base, base + Duration::from_secs(30), false);
+        assert_eq!(phases.connect.unwrap().duration(), Duration::from_millis(29_995));
+        assert_eq!(phases.request, None);
+
+        let marks = Marks { dns_start: at(base, 0), ..Marks::default() };
+        let phases = marks.into_phases(base, base + Duration::from_secs(5), false);
+        assert_eq!(phases.dns.unwrap().duration(), Duration::from_secs(5));
+        assert_eq!(phases.request, None);
+    }
+
+    #[tokio::test]
+    async fn times_a_new_connection() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+            let (mut sock, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0u8; 4096];
+            let _ = sock.read(&mut buf).await;
+            let _ = sock.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n").await;
+        });
+
+        let client = crate::fetch::build_client("aggrivator-test").unwrap();
+        let (res, phases) = timed(false, client.get(format!("http://localhost:{}/", addr.port())).send()).await;
+        assert_eq!(res.unwrap().status().as_u16(), 200);
+        assert!(phases.dns.is_some());
+        assert!(phases.connect.is_some());
+        assert_eq!(phases.tls, None);
+        assert!(phases.request.is_some());
+        assert!(phases.remote_addr.is_some());
+    }
+}